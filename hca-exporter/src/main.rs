@@ -0,0 +1,221 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+mod metrics;
+#[cfg(feature = "otlp")]
+mod otlp;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+/// The max size of a request; only the request line is used.
+const MAX_REQUEST_SIZE: usize = 8192;
+/// The time the metrics of a scrape are served again, e.g. to the other replica of an HA
+/// Prometheus, instead of running another discovery.
+const SCRAPE_TTL: Duration = Duration::from_secs(1);
+
+/// The metrics of the last scrape, rendered, and when they were gathered; the lock is held
+/// during a scrape, so that the concurrent requests wait for it instead of running theirs.
+type ScrapeCache = Mutex<Option<(Instant, String)>>;
+
+/// Serve the state and counters of the HCAs on the host as Prometheus metrics.
+#[derive(Parser)]
+#[command(name = "hca-exporter", version, about)]
+struct Options {
+    /// The address to serve `/metrics` on.
+    #[arg(short, long, default_value = "0.0.0.0:9879")]
+    listen: String,
+
+    /// Read the HCAs from the sysfs mounted here, e.g. `/host/sys` in a container, instead of
+    /// libudev and libibverbs.
+    #[arg(long)]
+    sysfs_root: Option<PathBuf>,
+
+    /// Push the metrics to this OTLP/HTTP endpoint, e.g. `http://localhost:4318`, instead of
+    /// serving them.
+    #[cfg(feature = "otlp")]
+    #[arg(long)]
+    otlp_endpoint: Option<otlp::OtlpEndpoint>,
+
+    /// The interval in seconds to push the metrics at.
+    #[cfg(feature = "otlp")]
+    #[arg(long, default_value_t = 60)]
+    otlp_interval: u64,
+
+    /// A header to send to the OTLP endpoint, e.g. `Authorization=Bearer xxx`; repeatable.
+    #[cfg(feature = "otlp")]
+    #[arg(long = "otlp-header", value_parser = otlp::parse_header)]
+    otlp_headers: Vec<(String, String)>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), color_eyre::Report> {
+    color_eyre::install()?;
+
+    let options = Arc::new(Options::parse());
+
+    #[cfg(feature = "otlp")]
+    if let Some(endpoint) = &options.otlp_endpoint {
+        let exporter = otlp::OtlpExporter::new(endpoint.clone(), options.otlp_headers.clone());
+        return push_metrics(exporter, &options).await;
+    }
+
+    let listener = TcpListener::bind(&options.listen).await?;
+    eprintln!("Serving http://{}/metrics", listener.local_addr()?);
+
+    let cache = Arc::new(ScrapeCache::default());
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let options = options.clone();
+        let cache = cache.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve(stream, &options, &cache).await {
+                eprintln!("{}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Serve one HTTP request, and close the connection.
+async fn serve(
+    mut stream: TcpStream,
+    options: &Options,
+    cache: &ScrapeCache,
+) -> Result<(), color_eyre::Report> {
+    let request =
+        tokio::time::timeout(Duration::from_secs(10), read_request(&mut stream)).await??;
+
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let path = path.split_once('?').map(|(p, _)| p).unwrap_or(path);
+
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/metrics") => match scrape(options, cache).await {
+            Ok(text) => ("200 OK", "text/plain; version=0.0.4; charset=utf-8", text),
+            Err(e) => (
+                "500 Internal Server Error",
+                "text/plain; charset=utf-8",
+                format!("failed to discover the HCAs: {}\n", e),
+            ),
+        },
+        ("GET", "/") => (
+            "200 OK",
+            "text/html; charset=utf-8",
+            "<html><body><h1>HCA Exporter</h1><a href=\"/metrics\">Metrics</a></body></html>\n"
+                .to_string(),
+        ),
+        ("GET", _) => (
+            "404 Not Found",
+            "text/plain; charset=utf-8",
+            "not found\n".to_string(),
+        ),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain; charset=utf-8",
+            "method not allowed\n".to_string(),
+        ),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// Gather the metrics and render them, or serve those of the last scrape if gathered within
+/// [`SCRAPE_TTL`]; a failed scrape is not cached.
+async fn scrape(options: &Options, cache: &ScrapeCache) -> Result<String, color_eyre::Report> {
+    let mut cache = cache.lock().await;
+    if let Some((_, text)) = cache
+        .as_ref()
+        .filter(|(gathered, _)| gathered.elapsed() < SCRAPE_TTL)
+    {
+        return Ok(text.clone());
+    }
+
+    let sysfs_root = options.sysfs_root.clone();
+    let metrics =
+        tokio::task::spawn_blocking(move || metrics::gather(sysfs_root.as_deref())).await??;
+    let text = metrics::render_text(&metrics);
+    *cache = Some((Instant::now(), text.clone()));
+
+    Ok(text)
+}
+
+/// Push the metrics to the OTLP endpoint at the interval, until killed; a failed push is
+/// reported and retried at the next interval.
+#[cfg(feature = "otlp")]
+async fn push_metrics(
+    exporter: otlp::OtlpExporter,
+    options: &Options,
+) -> Result<(), color_eyre::Report> {
+    if options.otlp_interval == 0 {
+        return Err(color_eyre::eyre::eyre!("invalid interval <0>"));
+    }
+    eprintln!(
+        "Pushing to {} every {}s",
+        exporter.endpoint(),
+        options.otlp_interval
+    );
+
+    let exporter = Arc::new(exporter);
+    let mut interval = tokio::time::interval(Duration::from_secs(options.otlp_interval));
+    loop {
+        interval.tick().await;
+
+        let exporter = exporter.clone();
+        let sysfs_root = options.sysfs_root.clone();
+        let pushed = tokio::task::spawn_blocking(move || {
+            let metrics = metrics::gather(sysfs_root.as_deref())?;
+            exporter.push(&metrics)?;
+            Ok::<_, color_eyre::Report>(())
+        })
+        .await?;
+        if let Err(e) = pushed {
+            eprintln!("failed to push the metrics: {}", e);
+        }
+    }
+}
+
+/// Read the request line and headers, up to the empty line.
+async fn read_request(stream: &mut TcpStream) -> Result<String, color_eyre::Report> {
+    let mut buf = vec![];
+    let mut chunk = [0u8; 1024];
+
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < MAX_REQUEST_SIZE {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    Ok(String::from_utf8_lossy(&buf).to_string())
+}
@@ -0,0 +1,363 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Gather the metrics of the HCAs, and render them in the Prometheus text exposition format.
+
+use std::fmt::{self, Display, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use libhca::counters::PortCounters;
+use libhca::sysfs::Sysfs;
+use libhca::types::{Discovery, IbDevice, IbPort, PciDevice};
+
+/// The labels of a sample, e.g. `device="mlx5_0"`.
+pub type Labels = Vec<(&'static str, String)>;
+
+/// A gauge of the ports: its name, help and value.
+type PortGauge = (&'static str, &'static str, fn(&IbPort) -> f64);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricKind {
+    Gauge,
+    /// A monotonic counter, cumulative since the driver was loaded.
+    Counter,
+}
+
+/// A metric with its samples, e.g. `hca_port_state` of all ports.
+#[derive(Clone, Debug)]
+pub struct Metric {
+    pub name: String,
+    pub help: String,
+    pub kind: MetricKind,
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub labels: Labels,
+    pub value: SampleValue,
+}
+
+/// The value of a sample; the counters are integers, which an `f64` cannot hold above 2^53.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleValue {
+    Double(f64),
+    Int(u64),
+}
+
+impl Display for SampleValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Double(v) => write!(f, "{}", v),
+            Self::Int(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+impl Metric {
+    fn new(name: &str, help: &str, kind: MetricKind) -> Self {
+        Self {
+            name: name.to_string(),
+            help: help.to_string(),
+            kind,
+            samples: vec![],
+        }
+    }
+
+    fn push(&mut self, labels: Labels, value: f64) {
+        self.samples.push(Sample {
+            labels,
+            value: SampleValue::Double(value),
+        });
+    }
+
+    fn push_int(&mut self, labels: Labels, value: u64) {
+        self.samples.push(Sample {
+            labels,
+            value: SampleValue::Int(value),
+        });
+    }
+}
+
+/// A port with its labels and counters, i.e. the samples of all the port metrics.
+struct PortSample<'a> {
+    labels: Labels,
+    port: &'a IbPort,
+    counters: Option<PortCounters>,
+}
+
+/// Discover the HCAs and read their counters, with libudev and libibverbs, or from the sysfs
+/// at `sysfs_root` if given, e.g. `/host/sys`.
+pub fn gather(sysfs_root: Option<&Path>) -> Result<Vec<Metric>, libhca::HcaError> {
+    let started = Instant::now();
+
+    let sysfs = sysfs_root.map(Sysfs::new).unwrap_or_default();
+    let discovery = match sysfs_root {
+        Some(_) => sysfs.discover_pci_devices()?,
+        None => libhca::discover_pci_devices()?,
+    };
+
+    let mut metrics = collect(&discovery, &sysfs);
+
+    let mut duration = Metric::new(
+        "hca_exporter_scrape_duration_seconds",
+        "The time to discover the HCAs and read their counters.",
+        MetricKind::Gauge,
+    );
+    duration.push(vec![], started.elapsed().as_secs_f64());
+    metrics.push(duration);
+
+    Ok(metrics)
+}
+
+fn collect(discovery: &Discovery, sysfs: &Sysfs) -> Vec<Metric> {
+    let mut metrics = vec![];
+
+    let devices: Vec<(&PciDevice, &IbDevice)> = discovery
+        .pci_devices
+        .iter()
+        .flat_map(|hca| hca.ib_devices.iter().map(move |dev| (hca, dev)))
+        .collect();
+
+    let mut info = Metric::new(
+        "hca_device_info",
+        "The RDMA devices, with their adapter, model and firmware as labels.",
+        MetricKind::Gauge,
+    );
+    for (hca, dev) in &devices {
+        let mut labels = device_labels(dev);
+        labels.extend([
+            ("adapter", hca.adapter_id.clone()),
+            ("model", hca.model_name.clone()),
+            ("vendor", hca.vendor_name.clone()),
+            ("driver", dev.driver.clone().unwrap_or_default()),
+            ("fw_ver", dev.fw_ver.clone().unwrap_or_default()),
+            ("board_id", dev.board_id.clone().unwrap_or_default()),
+            ("node_type", dev.node_type.to_string()),
+            ("transport", dev.transport.to_string()),
+        ]);
+        info.push(labels, 1.0);
+    }
+    metrics.push(info);
+
+    let ports: Vec<PortSample> = devices
+        .iter()
+        .flat_map(|(_, dev)| {
+            dev.ib_ports.iter().map(move |port| {
+                let mut labels = device_labels(dev);
+                labels.insert(1, ("port", port.port_num.to_string()));
+                PortSample {
+                    labels,
+                    port,
+                    counters: sysfs.read_port_counters(&dev.name, port.port_num).ok(),
+                }
+            })
+        })
+        .collect();
+
+    let gauges: [PortGauge; 5] = [
+        (
+            "hca_port_state",
+            "The logical state of the port: 1 Down, 2 Initializing, 3 Armed, 4 Active, 5 ActiveDefer.",
+            |p| p.state.code() as f64,
+        ),
+        (
+            "hca_port_phys_state",
+            "The physical state of the port: 1 Sleep, 2 Polling, 3 Disabled, 4 Training, 5 LinkUp, 6 LinkErrorRecovery, 7 PhyTest.",
+            |p| p.phys_state.code() as f64,
+        ),
+        (
+            "hca_port_rate_gbps",
+            "The data rate of the port in Gb/s; 0 if the link is down.",
+            |p| p.rate,
+        ),
+        ("hca_port_lid", "The LID of the port; 0 for Ethernet.", |p| {
+            p.lid as f64
+        }),
+        (
+            "hca_port_sm_lid",
+            "The LID of the subnet manager of the port; 0 if none is reachable.",
+            |p| p.sm_lid as f64,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        let mut metric = Metric::new(name, help, MetricKind::Gauge);
+        for port in &ports {
+            metric.push(port.labels.clone(), value(port.port));
+        }
+        metrics.push(metric);
+    }
+
+    // The typed counters, e.g. `hca_port_xmit_bytes_total`; those the port does not report are left out.
+    for (counter, _) in PortCounters::default().values() {
+        let mut metric = Metric::new(
+            &format!("hca_port_{}_total", counter),
+            &format!("The {} counter of the port.", counter),
+            MetricKind::Counter,
+        );
+        for port in &ports {
+            let value = port.counters.as_ref().and_then(|c| {
+                c.values()
+                    .into_iter()
+                    .find(|(n, _)| *n == counter)
+                    .and_then(|(_, v)| v)
+            });
+            if let Some(value) = value {
+                metric.push_int(port.labels.clone(), value);
+            }
+        }
+        metrics.push(metric);
+    }
+
+    let mut hw_counters = Metric::new(
+        "hca_port_hw_counter_total",
+        "The provider specific counters of hw_counters/ of the port.",
+        MetricKind::Counter,
+    );
+    for port in &ports {
+        for (counter, value) in port.counters.iter().flat_map(|c| &c.hw_counters) {
+            let mut labels = port.labels.clone();
+            labels.push(("counter", counter.clone()));
+            hw_counters.push_int(labels, *value);
+        }
+    }
+    metrics.push(hw_counters);
+
+    let mut warnings = Metric::new(
+        "hca_exporter_warnings",
+        "The devices and ports which could not be read.",
+        MetricKind::Gauge,
+    );
+    warnings.push(vec![], discovery.warnings.len() as f64);
+    metrics.push(warnings);
+
+    metrics
+}
+
+/// The labels which identify a device: device, slot and node_guid.
+fn device_labels(dev: &IbDevice) -> Labels {
+    vec![
+        ("device", dev.name.clone()),
+        ("slot", dev.slot_name.clone()),
+        ("node_guid", dev.node_guid.clone().unwrap_or_default()),
+    ]
+}
+
+/// Render the metrics in the Prometheus text exposition format.
+pub fn render_text(metrics: &[Metric]) -> String {
+    let mut out = String::new();
+
+    for metric in metrics {
+        let kind = match metric.kind {
+            MetricKind::Gauge => "gauge",
+            MetricKind::Counter => "counter",
+        };
+        let _ = writeln!(out, "# HELP {} {}", metric.name, metric.help);
+        let _ = writeln!(out, "# TYPE {} {}", metric.name, kind);
+
+        for sample in &metric.samples {
+            out.push_str(&metric.name);
+            if !sample.labels.is_empty() {
+                let labels: Vec<String> = sample
+                    .labels
+                    .iter()
+                    .map(|(k, v)| format!("{}=\"{}\"", k, escape_label(v)))
+                    .collect();
+                let _ = write!(out, "{{{}}}", labels.join(","));
+            }
+            let _ = writeln!(out, " {}", sample.value);
+        }
+    }
+
+    out
+}
+
+/// Escape a label value: backslash, double quote and line feed.
+fn escape_label(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_labels() {
+        assert_eq!(escape_label("mlx5_0"), "mlx5_0");
+        assert_eq!(escape_label(r"a\b"), r"a\\b");
+        assert_eq!(escape_label("node \"01\""), "node \\\"01\\\"");
+        assert_eq!(escape_label("a\nb"), "a\\nb");
+        assert_eq!(escape_label("\\\"\n"), "\\\\\\\"\\n");
+    }
+
+    #[test]
+    fn render_metrics() {
+        let mut state = Metric::new(
+            "hca_port_state",
+            "The state of the port.",
+            MetricKind::Gauge,
+        );
+        state.push(
+            vec![("device", "mlx5_0".to_string()), ("port", "1".to_string())],
+            4.0,
+        );
+        state.push(
+            vec![("device", "mlx5_1".to_string()), ("port", "1".to_string())],
+            1.0,
+        );
+        let mut bytes = Metric::new(
+            "hca_port_rcv_bytes_total",
+            "The bytes received.",
+            MetricKind::Counter,
+        );
+        bytes.push_int(vec![("node_desc", "host \"a\"\n".to_string())], u64::MAX);
+        let mut duration = Metric::new(
+            "hca_exporter_scrape_duration_seconds",
+            "The time of the scrape.",
+            MetricKind::Gauge,
+        );
+        duration.push(vec![], 0.25);
+
+        assert_eq!(
+            render_text(&[state, bytes, duration]),
+            "# HELP hca_port_state The state of the port.\n\
+             # TYPE hca_port_state gauge\n\
+             hca_port_state{device=\"mlx5_0\",port=\"1\"} 4\n\
+             hca_port_state{device=\"mlx5_1\",port=\"1\"} 1\n\
+             # HELP hca_port_rcv_bytes_total The bytes received.\n\
+             # TYPE hca_port_rcv_bytes_total counter\n\
+             hca_port_rcv_bytes_total{node_desc=\"host \\\"a\\\"\\n\"} 18446744073709551615\n\
+             # HELP hca_exporter_scrape_duration_seconds The time of the scrape.\n\
+             # TYPE hca_exporter_scrape_duration_seconds gauge\n\
+             hca_exporter_scrape_duration_seconds 0.25\n"
+        );
+    }
+
+    #[test]
+    fn render_empty() {
+        let metric = Metric::new("hca_exporter_warnings", "The warnings.", MetricKind::Gauge);
+
+        assert_eq!(render_text(&[]), "");
+        assert_eq!(
+            render_text(&[metric]),
+            "# HELP hca_exporter_warnings The warnings.\n\
+             # TYPE hca_exporter_warnings gauge\n"
+        );
+    }
+}
@@ -0,0 +1,580 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Push the metrics to an OpenTelemetry collector with OTLP/HTTP, in the JSON encoding.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::metrics::{Labels, Metric, MetricKind, SampleValue};
+
+/// The default port of OTLP/HTTP.
+const DEFAULT_PORT: u16 = 4318;
+/// The default path of the metrics of OTLP/HTTP.
+const DEFAULT_PATH: &str = "/v1/metrics";
+/// The timeout to connect to, send to and receive from the collector.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// `AGGREGATION_TEMPORALITY_CUMULATIVE` of OTLP.
+const CUMULATIVE: u32 = 2;
+
+/// An OTLP/HTTP endpoint, e.g. `http://collector:4318` or `http://[::1]:4318`; TLS is not
+/// supported, so a collector on another host should be reached through a local agent.
+#[derive(Clone, Debug)]
+pub struct OtlpEndpoint {
+    /// The host name or address, without the brackets of an IPv6 address.
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl OtlpEndpoint {
+    /// The host and port, e.g. `[::1]:4318`, as in the URL and the `Host` header.
+    fn authority(&self) -> String {
+        match self.host.contains(':') {
+            true => format!("[{}]:{}", self.host, self.port),
+            false => format!("{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl Display for OtlpEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}{}", self.authority(), self.path)
+    }
+}
+
+impl FromStr for OtlpEndpoint {
+    type Err = String;
+
+    /// Parse an endpoint; the port defaults to 4318 and the path to `/v1/metrics`.
+    fn from_str(v: &str) -> Result<Self, Self::Err> {
+        let rest = v
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("unsupported endpoint <{}>, only http:// is supported", v))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        let (host, port) = split_authority(authority)
+            .ok_or_else(|| format!("invalid host <{}> of <{}>", authority, v))?;
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| format!("invalid port <{}> of <{}>", port, v))?,
+            None => DEFAULT_PORT,
+        };
+        if host.is_empty() {
+            return Err(format!("missing host of <{}>", v));
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: match path {
+                "" | "/" => DEFAULT_PATH.to_string(),
+                path => path.to_string(),
+            },
+        })
+    }
+}
+
+/// Split an authority into its host and port if any, e.g. `[::1]:4318`; `None` if an IPv6
+/// address is not in brackets, as its colons are not the port separator.
+fn split_authority(authority: &str) -> Option<(&str, Option<&str>)> {
+    match authority.strip_prefix('[') {
+        Some(rest) => {
+            let (host, rest) = rest.split_once(']')?;
+            match rest {
+                "" => Some((host, None)),
+                rest => Some((host, Some(rest.strip_prefix(':')?))),
+            }
+        }
+        None => match authority.split_once(':') {
+            Some((_, port)) if port.contains(':') => None,
+            Some((host, port)) => Some((host, Some(port))),
+            None => Some((authority, None)),
+        },
+    }
+}
+
+/// Export the metrics to a collector.
+///
+/// The counters are cumulative sums whose start time is when the exporter started; a counter
+/// which goes backwards, e.g. as the driver was reloaded, or which appears later, e.g. of a new
+/// VF, starts at the previous export instead, as it was reset or created since.
+pub struct OtlpExporter {
+    endpoint: OtlpEndpoint,
+    headers: Vec<(String, String)>,
+    start_time: SystemTime,
+    host_name: String,
+    /// The counters of the last export; `None` before the first one.
+    last_export: Mutex<Option<Export>>,
+}
+
+/// The time of an export and its counters by metric name and labels, with their start time
+/// and value.
+struct Export {
+    time: String,
+    counters: HashMap<String, (String, SampleValue)>,
+}
+
+impl OtlpExporter {
+    /// Create an exporter; the headers are sent with every request, e.g. for authentication.
+    pub fn new(endpoint: OtlpEndpoint, headers: Vec<(String, String)>) -> Self {
+        Self {
+            endpoint,
+            headers,
+            start_time: SystemTime::now(),
+            host_name: fs::read_to_string("/proc/sys/kernel/hostname")
+                .map(|v| v.trim().to_string())
+                .unwrap_or_default(),
+            last_export: Mutex::new(None),
+        }
+    }
+
+    pub fn endpoint(&self) -> &OtlpEndpoint {
+        &self.endpoint
+    }
+
+    /// Encode the metrics as an `ExportMetricsServiceRequest`, and keep their counters to
+    /// detect the resets at the next one.
+    pub fn encode(&self, metrics: &[Metric]) -> Value {
+        let time = unix_nanos(SystemTime::now());
+        let mut last_export = self.last_export.lock().unwrap_or_else(|e| e.into_inner());
+        // The time the counters which are new or went backwards since the last export start at.
+        let restart_time = match last_export.as_ref() {
+            Some(export) => export.time.clone(),
+            None => unix_nanos(self.start_time),
+        };
+        let mut counters = HashMap::new();
+
+        let metrics: Vec<Value> = metrics
+            .iter()
+            .map(|metric| match metric.kind {
+                MetricKind::Gauge => json!({
+                    "name": metric.name,
+                    "description": metric.help,
+                    "gauge": {
+                        "dataPoints": metric.samples.iter()
+                            .map(|s| data_point(&s.labels, None, &time, s.value))
+                            .collect::<Vec<_>>(),
+                    },
+                }),
+                MetricKind::Counter => {
+                    let points: Vec<Value> = metric
+                        .samples
+                        .iter()
+                        .map(|s| {
+                            let key = format!("{}{:?}", metric.name, s.labels);
+                            let last = last_export.as_ref().and_then(|e| e.counters.get(&key));
+                            let start_time = match last {
+                                Some((start_time, last)) if !is_reset(*last, s.value) => {
+                                    start_time.clone()
+                                }
+                                _ => restart_time.clone(),
+                            };
+                            let point = data_point(&s.labels, Some(&start_time), &time, s.value);
+                            counters.insert(key, (start_time, s.value));
+                            point
+                        })
+                        .collect();
+                    // The `_total` suffix is added by Prometheus, it is not part of an OTLP name.
+                    json!({
+                        "name": metric.name.trim_end_matches("_total"),
+                        "description": metric.help,
+                        "sum": {
+                            "dataPoints": points,
+                            "aggregationTemporality": CUMULATIVE,
+                            "isMonotonic": true,
+                        },
+                    })
+                }
+            })
+            .collect();
+        // The counters which are gone are dropped, e.g. of a removed VF.
+        *last_export = Some(Export {
+            time: time.clone(),
+            counters,
+        });
+
+        json!({
+            "resourceMetrics": [{
+                "resource": {
+                    "attributes": [
+                        attribute("service.name", env!("CARGO_PKG_NAME")),
+                        attribute("service.version", env!("CARGO_PKG_VERSION")),
+                        attribute("host.name", &self.host_name),
+                    ],
+                },
+                "scopeMetrics": [{
+                    "scope": {
+                        "name": env!("CARGO_PKG_NAME"),
+                        "version": env!("CARGO_PKG_VERSION"),
+                    },
+                    "metrics": metrics,
+                }],
+            }],
+        })
+    }
+
+    /// Push the metrics to the collector; an error if it does not answer with a 2xx status.
+    pub fn push(&self, metrics: &[Metric]) -> io::Result<()> {
+        let body = self.encode(metrics).to_string();
+
+        let addr = (self.endpoint.host.as_str(), self.endpoint.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no address of <{}>", self.endpoint.host),
+                )
+            })?;
+        let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.endpoint.path,
+            self.endpoint.authority(),
+            body.len()
+        );
+        for (name, value) in &self.headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        request.push_str(&body);
+        stream.write_all(request.as_bytes())?;
+
+        let mut response = vec![];
+        stream.read_to_end(&mut response)?;
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        let status: u16 = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default();
+        if !(200..300).contains(&status) {
+            return Err(io::Error::other(format!(
+                "{} answered <{}>",
+                self.endpoint, status_line
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a header of `--otlp-header`, e.g. `Authorization=Bearer xxx`.
+pub fn parse_header(v: &str) -> Result<(String, String), String> {
+    v.split_once('=')
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .filter(|(name, _)| !name.is_empty())
+        .ok_or_else(|| format!("invalid header <{}>, expected <name>=<value>", v))
+}
+
+fn attributes(labels: &Labels) -> Vec<Value> {
+    labels.iter().map(|(k, v)| attribute(k, v)).collect()
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+/// A data point of a gauge, or of a sum with its start time; an integer is `asInt`, a string
+/// like int64 in the JSON of OTLP.
+fn data_point(labels: &Labels, start_time: Option<&str>, time: &str, value: SampleValue) -> Value {
+    let mut point = json!({
+        "attributes": attributes(labels),
+        "timeUnixNano": time,
+    });
+    if let Some(start_time) = start_time {
+        point["startTimeUnixNano"] = json!(start_time);
+    }
+    match value {
+        // asInt is an int64, so a counter above it is sent as a double, which keeps it
+        // monotonic rather than stuck at i64::MAX.
+        SampleValue::Int(v) => match i64::try_from(v) {
+            Ok(v) => point["asInt"] = json!(v.to_string()),
+            Err(_) => point["asDouble"] = json!(v as f64),
+        },
+        SampleValue::Double(v) => point["asDouble"] = json!(v),
+    }
+
+    point
+}
+
+/// Whether a counter went backwards, i.e. was reset since its last value.
+fn is_reset(last: SampleValue, value: SampleValue) -> bool {
+    match (last, value) {
+        (SampleValue::Int(last), SampleValue::Int(v)) => v < last,
+        (SampleValue::Double(last), SampleValue::Double(v)) => v < last,
+        _ => true,
+    }
+}
+
+/// The time in nanoseconds since the UNIX epoch, as a string like int64 in the JSON of OTLP.
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::metrics::Sample;
+
+    fn endpoint(host: &str, port: u16) -> OtlpEndpoint {
+        OtlpEndpoint {
+            host: host.to_string(),
+            port,
+            path: DEFAULT_PATH.to_string(),
+        }
+    }
+
+    fn counter(value: u64) -> Metric {
+        Metric {
+            name: "hca_port_xmit_bytes_total".to_string(),
+            help: "The xmit_bytes counter of the port.".to_string(),
+            kind: MetricKind::Counter,
+            samples: vec![Sample {
+                labels: vec![("device", "mlx5_0".to_string())],
+                value: SampleValue::Int(value),
+            }],
+        }
+    }
+
+    fn data_points(request: &Value) -> &Vec<Value> {
+        let metric = &request["resourceMetrics"][0]["scopeMetrics"][0]["metrics"][0];
+        metric["sum"]["dataPoints"]
+            .as_array()
+            .or_else(|| metric["gauge"]["dataPoints"].as_array())
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_endpoints() {
+        let parse = |v: &str| v.parse::<OtlpEndpoint>().map(|e| (e.host, e.port, e.path));
+        let owned = |host: &str, port, path: &str| Ok((host.to_string(), port, path.to_string()));
+
+        assert_eq!(
+            parse("http://collector"),
+            owned("collector", 4318, "/v1/metrics")
+        );
+        assert_eq!(
+            parse("http://collector:4000/"),
+            owned("collector", 4000, "/v1/metrics")
+        );
+        assert_eq!(
+            parse("http://10.0.0.1:4000/otlp/v1/metrics"),
+            owned("10.0.0.1", 4000, "/otlp/v1/metrics")
+        );
+        assert_eq!(
+            parse("http://[::1]:4000"),
+            owned("::1", 4000, "/v1/metrics")
+        );
+        assert_eq!(
+            parse("http://[2001:db8::1]/v1/metrics"),
+            owned("2001:db8::1", 4318, "/v1/metrics")
+        );
+
+        assert!(parse("https://collector").is_err());
+        assert!(parse("http://").is_err());
+        assert!(parse("http://:4318").is_err());
+        assert!(parse("http://collector:http").is_err());
+        assert!(parse("http://[::1").is_err());
+        assert!(parse("http://[::1]4318").is_err());
+        assert!(parse("http://::1:4318").is_err());
+    }
+
+    #[test]
+    fn display_endpoints() {
+        assert_eq!(
+            endpoint("collector", 4318).to_string(),
+            "http://collector:4318/v1/metrics"
+        );
+        assert_eq!(
+            endpoint("::1", 4000).to_string(),
+            "http://[::1]:4000/v1/metrics"
+        );
+    }
+
+    #[test]
+    fn parse_headers() {
+        assert_eq!(
+            parse_header("Authorization=Bearer a=b"),
+            Ok(("Authorization".to_string(), "Bearer a=b".to_string()))
+        );
+        assert!(parse_header("Authorization").is_err());
+        assert!(parse_header("=value").is_err());
+    }
+
+    #[test]
+    fn encode_values() {
+        let exporter = OtlpExporter::new(endpoint("collector", 4318), vec![]);
+        let gauge = Metric {
+            name: "hca_port_rate_gbps".to_string(),
+            help: "The data rate of the port in Gb/s.".to_string(),
+            kind: MetricKind::Gauge,
+            samples: vec![Sample {
+                labels: vec![],
+                value: SampleValue::Double(212.5),
+            }],
+        };
+
+        let request = exporter.encode(&[counter(i64::MAX as u64)]);
+        let metric = &request["resourceMetrics"][0]["scopeMetrics"][0]["metrics"][0];
+        assert_eq!(metric["name"], "hca_port_xmit_bytes");
+        assert_eq!(metric["sum"]["isMonotonic"], true);
+        assert_eq!(data_points(&request)[0]["asInt"], "9223372036854775807");
+        assert_eq!(
+            data_points(&request)[0]["attributes"],
+            json!([{ "key": "device", "value": { "stringValue": "mlx5_0" } }])
+        );
+
+        let request = exporter.encode(&[gauge]);
+        assert_eq!(data_points(&request)[0]["asDouble"], 212.5);
+        assert!(data_points(&request)[0].get("startTimeUnixNano").is_none());
+    }
+
+    #[test]
+    fn encode_large_counters() {
+        // Above the int64 of asInt.
+        let request = OtlpExporter::new(endpoint("collector", 4318), vec![])
+            .encode(&[counter(i64::MAX as u64 + 1)]);
+        let point = &data_points(&request)[0];
+        assert!(point.get("asInt").is_none());
+        assert_eq!(point["asDouble"], 9.223372036854776e18);
+
+        let request =
+            OtlpExporter::new(endpoint("collector", 4318), vec![]).encode(&[counter(u64::MAX)]);
+        assert_eq!(data_points(&request)[0]["asDouble"], 1.8446744073709552e19);
+    }
+
+    #[test]
+    fn reset_start_time() {
+        let exporter = OtlpExporter::new(endpoint("collector", 4318), vec![]);
+        let start_time = |request: &Value| data_points(request)[0]["startTimeUnixNano"].clone();
+        let time = |request: &Value| data_points(request)[0]["timeUnixNano"].clone();
+
+        let first = exporter.encode(&[counter(100)]);
+        assert_eq!(start_time(&first), json!(unix_nanos(exporter.start_time)));
+
+        thread::sleep(Duration::from_millis(1));
+        let second = exporter.encode(&[counter(200)]);
+        assert_eq!(start_time(&second), start_time(&first));
+
+        // Reset since the second export.
+        thread::sleep(Duration::from_millis(1));
+        let third = exporter.encode(&[counter(50)]);
+        assert_eq!(start_time(&third), time(&second));
+
+        thread::sleep(Duration::from_millis(1));
+        let fourth = exporter.encode(&[counter(60)]);
+        assert_eq!(start_time(&fourth), time(&second));
+
+        // Gone, then back: created since the last export.
+        exporter.encode(&[]);
+        let fifth = exporter.encode(&[counter(70)]);
+        assert_ne!(start_time(&fifth), time(&second));
+    }
+
+    #[test]
+    fn push() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let collector = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            let mut chunk = [0u8; 4096];
+            // Read up to the end of the body, by its length.
+            loop {
+                let n = stream.read(&mut chunk).unwrap();
+                request.extend_from_slice(&chunk[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length: usize = head
+                        .lines()
+                        .find_map(|l| l.strip_prefix("Content-Length: "))
+                        .and_then(|v| v.parse().ok())
+                        .unwrap();
+                    if body.len() >= length || n == 0 {
+                        break;
+                    }
+                }
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .unwrap();
+
+            String::from_utf8(request).unwrap()
+        });
+
+        let exporter = OtlpExporter::new(
+            endpoint("127.0.0.1", port),
+            vec![("Authorization".to_string(), "Bearer xxx".to_string())],
+        );
+        exporter.push(&[counter(42)]).unwrap();
+
+        let request = collector.join().unwrap();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        let mut lines = head.lines();
+        assert_eq!(lines.next(), Some("POST /v1/metrics HTTP/1.1"));
+        let headers: Vec<&str> = lines.collect();
+        assert!(headers.contains(&format!("Host: 127.0.0.1:{}", port).as_str()));
+        assert!(headers.contains(&"Content-Type: application/json"));
+        assert!(headers.contains(&"Authorization: Bearer xxx"));
+
+        let body: Value = serde_json::from_str(body).unwrap();
+        let resource = &body["resourceMetrics"][0]["resource"]["attributes"][0];
+        assert_eq!(resource["value"]["stringValue"], "hca-exporter");
+        assert_eq!(data_points(&body)[0]["asInt"], "42");
+    }
+
+    #[test]
+    fn push_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let collector = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0u8; 4096]);
+            stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+        });
+
+        let exporter = OtlpExporter::new(endpoint("127.0.0.1", port), vec![]);
+        let error = exporter.push(&[counter(42)]).unwrap_err();
+        collector.join().unwrap();
+
+        assert!(error.to_string().contains("400 Bad Request"));
+    }
+}
@@ -7,6 +7,22 @@ fn main() {
     println!("cargo:rustc-link-lib=ibverbs");
     println!("cargo:rerun-if-changed=wrappers/*");
 
+    // Export the static inline extended verbs, see wrappers/ibverbs_ex.c.
+    cc::Build::new()
+        .file("wrappers/ibverbs_ex.c")
+        .compile("ibverbs_ex");
+
+    // The mlx5 direct verbs are optional, they give the DC capabilities of the mlx5 devices.
+    println!("cargo:rustc-check-cfg=cfg(hca_mlx5dv)");
+    if cc::Build::new()
+        .file("wrappers/mlx5dv_ex.c")
+        .try_compile("mlx5dv_ex")
+        .is_ok()
+    {
+        println!("cargo:rustc-link-lib=mlx5");
+        println!("cargo:rustc-cfg=hca_mlx5dv");
+    }
+
     // Build binding builder
     let bindings = bindgen::Builder::default()
         .header("wrappers/ibverbs.h")
@@ -38,6 +54,12 @@ fn main() {
         .generate()
         .expect("Unable to generate bindings");
 
+    // The extended active speed, e.g. XDR, is in the verbs headers of rdma-core 47 and later.
+    println!("cargo:rustc-check-cfg=cfg(hca_active_speed_ex)");
+    if bindings.to_string().contains("active_speed_ex") {
+        println!("cargo:rustc-cfg=hca_active_speed_ex");
+    }
+
     // Write the bindings to the src/pci.rs file.
     let out_path = PathBuf::from("src/wrappers".to_string());
     bindings
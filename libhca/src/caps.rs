@@ -0,0 +1,201 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use std::fmt::{self, Display};
+
+use bitflags::{bitflags, Flags};
+use serde::{Serialize, Serializer};
+
+use super::wrappers::ibverbs::{
+    self, ibv_port_cap_flags, ibv_qp_type, IBV_PORT_INFO_EXT_SUP, IBV_PORT_LINK_SPEED_HDR_SUP,
+    IBV_PORT_LINK_SPEED_NDR_SUP, IBV_PORT_LINK_WIDTH_2X_SUP, IBV_PORT_SET_NODE_DESC_SUP,
+    IBV_PORT_SWITCH_PORT_STATE_TABLE_SUP, IBV_PORT_VIRT_SUP,
+};
+use super::wrappers::ibverbs_ex::{IBV_PORT_EXTENDED_SPEEDS2_SUP, IBV_PORT_LINK_SPEED_XDR_SUP};
+
+/// The names of the flags in a set, e.g. `RC` and `UD` of a [`QpTypes`].
+pub fn flag_names<F: Flags>(flags: &F) -> Vec<&'static str> {
+    flags.iter_names().map(|(name, _)| name).collect()
+}
+
+/// Print a set of flags as their names, e.g. `RC, UD`, and serialize it as the sequence of
+/// their names; the unknown bits are left out.
+macro_rules! impl_flag_names {
+    ($($flags:ty),+) => {
+        $(
+            impl Display for $flags {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    f.write_str(&flag_names(self).join(", "))
+                }
+            }
+
+            impl Serialize for $flags {
+                fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    serializer.collect_seq(self.iter_names().map(|(name, _)| name))
+                }
+            }
+        )+
+    };
+}
+
+bitflags! {
+    /// The capabilities of a port: `ibv_port_attr::port_cap_flags` in the low 32 bits, and
+    /// `ibv_port_attr::port_cap_flags2` in the high 32 bits.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+    pub struct PortCapabilities: u64 {
+        const IS_SM = ibv_port_cap_flags::IBV_PORT_SM.0 as u64;
+        const NOTICE_SUP = ibv_port_cap_flags::IBV_PORT_NOTICE_SUP.0 as u64;
+        const TRAP_SUP = ibv_port_cap_flags::IBV_PORT_TRAP_SUP.0 as u64;
+        const OPT_IPD_SUP = ibv_port_cap_flags::IBV_PORT_OPT_IPD_SUP.0 as u64;
+        const AUTO_MIGR_SUP = ibv_port_cap_flags::IBV_PORT_AUTO_MIGR_SUP.0 as u64;
+        const SL_MAP_SUP = ibv_port_cap_flags::IBV_PORT_SL_MAP_SUP.0 as u64;
+        const MKEY_NVRAM = ibv_port_cap_flags::IBV_PORT_MKEY_NVRAM.0 as u64;
+        const PKEY_NVRAM = ibv_port_cap_flags::IBV_PORT_PKEY_NVRAM.0 as u64;
+        const LED_INFO_SUP = ibv_port_cap_flags::IBV_PORT_LED_INFO_SUP.0 as u64;
+        // IsSMDisabled of the IBTA PortInfo, only named by the kernel uapi.
+        const SM_DISABLED = ibverbs::IB_UVERBS_PCF_SM_DISABLED as u64;
+        const SYS_IMAGE_GUID_SUP = ibv_port_cap_flags::IBV_PORT_SYS_IMAGE_GUID_SUP.0 as u64;
+        const PKEY_SW_EXT_PORT_TRAP_SUP =
+            ibv_port_cap_flags::IBV_PORT_PKEY_SW_EXT_PORT_TRAP_SUP.0 as u64;
+        const EXTENDED_SPEEDS_SUP = ibv_port_cap_flags::IBV_PORT_EXTENDED_SPEEDS_SUP.0 as u64;
+        const CAP_MASK2_SUP = ibv_port_cap_flags::IBV_PORT_CAP_MASK2_SUP.0 as u64;
+        const CM_SUP = ibv_port_cap_flags::IBV_PORT_CM_SUP.0 as u64;
+        const SNMP_TUNNEL_SUP = ibv_port_cap_flags::IBV_PORT_SNMP_TUNNEL_SUP.0 as u64;
+        const REINIT_SUP = ibv_port_cap_flags::IBV_PORT_REINIT_SUP.0 as u64;
+        const DEVICE_MGMT_SUP = ibv_port_cap_flags::IBV_PORT_DEVICE_MGMT_SUP.0 as u64;
+        const VENDOR_CLASS_SUP = ibv_port_cap_flags::IBV_PORT_VENDOR_CLASS_SUP.0 as u64;
+        const DR_NOTICE_SUP = ibv_port_cap_flags::IBV_PORT_DR_NOTICE_SUP.0 as u64;
+        const CAP_MASK_NOTICE_SUP = ibv_port_cap_flags::IBV_PORT_CAP_MASK_NOTICE_SUP.0 as u64;
+        const BOOT_MGMT_SUP = ibv_port_cap_flags::IBV_PORT_BOOT_MGMT_SUP.0 as u64;
+        const LINK_LATENCY_SUP = ibv_port_cap_flags::IBV_PORT_LINK_LATENCY_SUP.0 as u64;
+        const CLIENT_REG_SUP = ibv_port_cap_flags::IBV_PORT_CLIENT_REG_SUP.0 as u64;
+        const IP_BASED_GIDS = ibv_port_cap_flags::IBV_PORT_IP_BASED_GIDS.0 as u64;
+
+        const SET_NODE_DESC_SUP = (IBV_PORT_SET_NODE_DESC_SUP as u64) << 32;
+        const INFO_EXT_SUP = (IBV_PORT_INFO_EXT_SUP as u64) << 32;
+        const VIRT_SUP = (IBV_PORT_VIRT_SUP as u64) << 32;
+        const SWITCH_PORT_STATE_TABLE_SUP = (IBV_PORT_SWITCH_PORT_STATE_TABLE_SUP as u64) << 32;
+        const LINK_WIDTH_2X_SUP = (IBV_PORT_LINK_WIDTH_2X_SUP as u64) << 32;
+        const LINK_SPEED_HDR_SUP = (IBV_PORT_LINK_SPEED_HDR_SUP as u64) << 32;
+        const LINK_SPEED_NDR_SUP = (IBV_PORT_LINK_SPEED_NDR_SUP as u64) << 32;
+        const EXTENDED_SPEEDS2_SUP = (IBV_PORT_EXTENDED_SPEEDS2_SUP as u64) << 32;
+        const LINK_SPEED_XDR_SUP = (IBV_PORT_LINK_SPEED_XDR_SUP as u64) << 32;
+    }
+}
+
+impl PortCapabilities {
+    pub fn from_raw(port_cap_flags: u32, port_cap_flags2: u16) -> Self {
+        Self::from_bits_retain(port_cap_flags as u64 | (port_cap_flags2 as u64) << 32)
+    }
+}
+
+bitflags! {
+    /// The capabilities of a device, i.e. `ibv_device_attr::device_cap_flags`.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+    pub struct DeviceCapabilities: u32 {
+        const RESIZE_MAX_WR = ibverbs::IBV_DEVICE_RESIZE_MAX_WR;
+        const BAD_PKEY_CNTR = ibverbs::IBV_DEVICE_BAD_PKEY_CNTR;
+        const BAD_QKEY_CNTR = ibverbs::IBV_DEVICE_BAD_QKEY_CNTR;
+        const RAW_MULTI = ibverbs::IBV_DEVICE_RAW_MULTI;
+        const AUTO_PATH_MIG = ibverbs::IBV_DEVICE_AUTO_PATH_MIG;
+        const CHANGE_PHY_PORT = ibverbs::IBV_DEVICE_CHANGE_PHY_PORT;
+        const UD_AV_PORT_ENFORCE = ibverbs::IBV_DEVICE_UD_AV_PORT_ENFORCE;
+        const CURR_QP_STATE_MOD = ibverbs::IBV_DEVICE_CURR_QP_STATE_MOD;
+        const SHUTDOWN_PORT = ibverbs::IBV_DEVICE_SHUTDOWN_PORT;
+        const INIT_TYPE = ibverbs::IBV_DEVICE_INIT_TYPE;
+        const PORT_ACTIVE_EVENT = ibverbs::IBV_DEVICE_PORT_ACTIVE_EVENT;
+        const SYS_IMAGE_GUID = ibverbs::IBV_DEVICE_SYS_IMAGE_GUID;
+        const RC_RNR_NAK_GEN = ibverbs::IBV_DEVICE_RC_RNR_NAK_GEN;
+        const SRQ_RESIZE = ibverbs::IBV_DEVICE_SRQ_RESIZE;
+        const N_NOTIFY_CQ = ibverbs::IBV_DEVICE_N_NOTIFY_CQ;
+        const MEM_WINDOW = ibverbs::IBV_DEVICE_MEM_WINDOW;
+        const UD_IP_CSUM = ibverbs::IBV_DEVICE_UD_IP_CSUM;
+        const XRC = ibverbs::IBV_DEVICE_XRC;
+        const MEM_MGT_EXTENSIONS = ibverbs::IBV_DEVICE_MEM_MGT_EXTENSIONS;
+        const MEM_WINDOW_TYPE_2A = ibverbs::IBV_DEVICE_MEM_WINDOW_TYPE_2A;
+        const MEM_WINDOW_TYPE_2B = ibverbs::IBV_DEVICE_MEM_WINDOW_TYPE_2B;
+        const RC_IP_CSUM = ibverbs::IBV_DEVICE_RC_IP_CSUM;
+        const RAW_IP_CSUM = ibverbs::IBV_DEVICE_RAW_IP_CSUM;
+        const MANAGED_FLOW_STEERING = ibverbs::IBV_DEVICE_MANAGED_FLOW_STEERING;
+    }
+}
+
+bitflags! {
+    /// The operations which support on-demand paging on a transport, see `enum ibv_odp_transport_cap_bits`.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+    pub struct OdpCapabilities: u32 {
+        const SEND = ibverbs::IBV_ODP_SUPPORT_SEND;
+        const RECV = ibverbs::IBV_ODP_SUPPORT_RECV;
+        const WRITE = ibverbs::IBV_ODP_SUPPORT_WRITE;
+        const READ = ibverbs::IBV_ODP_SUPPORT_READ;
+        const ATOMIC = ibverbs::IBV_ODP_SUPPORT_ATOMIC;
+        const SRQ_RECV = ibverbs::IBV_ODP_SUPPORT_SRQ_RECV;
+    }
+}
+
+bitflags! {
+    /// A set of QP types, i.e. the bits `1 << IBV_QPT_*` of the `supported_qpts` of the extended caps.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+    pub struct QpTypes: u32 {
+        const RC = 1 << ibv_qp_type::IBV_QPT_RC;
+        const UC = 1 << ibv_qp_type::IBV_QPT_UC;
+        const UD = 1 << ibv_qp_type::IBV_QPT_UD;
+        const RAW_PACKET = 1 << ibv_qp_type::IBV_QPT_RAW_PACKET;
+        const XRC_SEND = 1 << ibv_qp_type::IBV_QPT_XRC_SEND;
+        const XRC_RECV = 1 << ibv_qp_type::IBV_QPT_XRC_RECV;
+    }
+}
+
+impl_flag_names!(
+    PortCapabilities,
+    DeviceCapabilities,
+    OdpCapabilities,
+    QpTypes
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_capability_names() {
+        let caps = PortCapabilities::from_raw(
+            (ibv_port_cap_flags::IBV_PORT_TRAP_SUP | ibv_port_cap_flags::IBV_PORT_CM_SUP).0
+                | 1 << 10,
+            (IBV_PORT_LINK_SPEED_NDR_SUP | IBV_PORT_LINK_SPEED_XDR_SUP) as u16,
+        );
+        assert!(caps.contains(PortCapabilities::SM_DISABLED));
+        assert_eq!(
+            flag_names(&caps),
+            [
+                "TRAP_SUP",
+                "SM_DISABLED",
+                "CM_SUP",
+                "LINK_SPEED_NDR_SUP",
+                "LINK_SPEED_XDR_SUP"
+            ]
+        );
+    }
+
+    #[test]
+    fn display_names() {
+        assert_eq!((QpTypes::RC | QpTypes::UD).to_string(), "RC, UD");
+        assert_eq!(OdpCapabilities::empty().to_string(), "");
+        // The bits unknown to the flags are left out.
+        let caps = DeviceCapabilities::from_bits_retain(ibverbs::IBV_DEVICE_XRC | 1 << 31);
+        assert_eq!(caps.to_string(), "XRC");
+    }
+}
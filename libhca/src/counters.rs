@@ -0,0 +1,592 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! The traffic and error counters of the ports, from `counters/` and `hw_counters/` in sysfs.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use super::error::Result;
+use super::sysfs::Sysfs;
+use super::utils::{read_sysfs_attr, read_sysfs_dir};
+
+/// The counters of a port; a counter is `None` if the provider does not report it, e.g. the
+/// InfiniBand link counters of some RoCE ports.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PortCounters {
+    pub device: String,
+    pub port_num: u8,
+
+    /// The data sent and received in bytes; sysfs reports them in 4-byte words.
+    pub xmit_bytes: Option<u64>,
+    pub rcv_bytes: Option<u64>,
+    pub xmit_packets: Option<u64>,
+    pub rcv_packets: Option<u64>,
+    pub unicast_xmit_packets: Option<u64>,
+    pub unicast_rcv_packets: Option<u64>,
+    pub multicast_xmit_packets: Option<u64>,
+    pub multicast_rcv_packets: Option<u64>,
+
+    pub symbol_errors: Option<u64>,
+    pub link_downed: Option<u64>,
+    pub link_error_recovery: Option<u64>,
+    pub rcv_errors: Option<u64>,
+    pub rcv_remote_physical_errors: Option<u64>,
+    pub rcv_switch_relay_errors: Option<u64>,
+    pub xmit_discards: Option<u64>,
+    pub xmit_constraint_errors: Option<u64>,
+    pub rcv_constraint_errors: Option<u64>,
+    pub local_link_integrity_errors: Option<u64>,
+    pub excessive_buffer_overrun_errors: Option<u64>,
+    pub vl15_dropped: Option<u64>,
+    /// The ticks the port had data to send but no credits, i.e. the congestion of the link.
+    pub xmit_wait: Option<u64>,
+
+    /// The packets received out of sequence, e.g. after a drop on a lossy RoCE fabric.
+    pub out_of_sequence: Option<u64>,
+    pub packet_seq_err: Option<u64>,
+    pub duplicate_request: Option<u64>,
+    pub rnr_nak_retry_err: Option<u64>,
+    pub local_ack_timeout_err: Option<u64>,
+    pub implied_nak_seq_err: Option<u64>,
+    /// The congestion notification packets of RoCE, sent by the receiver and handled by the sender.
+    pub np_cnp_sent: Option<u64>,
+    pub rp_cnp_handled: Option<u64>,
+    pub np_ecn_marked_roce_packets: Option<u64>,
+
+    /// All the counters of `hw_counters/`, which are specific to the provider.
+    pub hw_counters: BTreeMap<String, u64>,
+}
+
+impl PortCounters {
+    /// Read the counters of the port at `path`, e.g. `/sys/class/infiniband/mlx5_0/ports/1`.
+    pub fn read(path: &Path, device: &str, port_num: u8) -> Result<Self> {
+        // A port without counters has neither directory, but the port itself must exist.
+        read_sysfs_dir(path)?;
+
+        let counters = path.join("counters");
+        let counter = |name: &str| read_counter(&counters.join(name));
+
+        let mut hw_counters = BTreeMap::new();
+        let hw_path = path.join("hw_counters");
+        for name in read_sysfs_dir(&hw_path).unwrap_or_default() {
+            // `lifespan` is the caching period of the counters in ms, not a counter.
+            if name == "lifespan" {
+                continue;
+            }
+            if let Some(v) = read_counter(&hw_path.join(&name)) {
+                hw_counters.insert(name, v);
+            }
+        }
+        let hw_counter = |name: &str| hw_counters.get(name).copied();
+
+        Ok(Self {
+            device: device.to_string(),
+            port_num,
+
+            xmit_bytes: counter("port_xmit_data").map(|v| v.saturating_mul(4)),
+            rcv_bytes: counter("port_rcv_data").map(|v| v.saturating_mul(4)),
+            xmit_packets: counter("port_xmit_packets"),
+            rcv_packets: counter("port_rcv_packets"),
+            unicast_xmit_packets: counter("unicast_xmit_packets"),
+            unicast_rcv_packets: counter("unicast_rcv_packets"),
+            multicast_xmit_packets: counter("multicast_xmit_packets"),
+            multicast_rcv_packets: counter("multicast_rcv_packets"),
+
+            symbol_errors: counter("symbol_error"),
+            link_downed: counter("link_downed"),
+            link_error_recovery: counter("link_error_recovery"),
+            rcv_errors: counter("port_rcv_errors"),
+            rcv_remote_physical_errors: counter("port_rcv_remote_physical_errors"),
+            rcv_switch_relay_errors: counter("port_rcv_switch_relay_errors"),
+            xmit_discards: counter("port_xmit_discards"),
+            xmit_constraint_errors: counter("port_xmit_constraint_errors"),
+            rcv_constraint_errors: counter("port_rcv_constraint_errors"),
+            local_link_integrity_errors: counter("local_link_integrity_errors"),
+            excessive_buffer_overrun_errors: counter("excessive_buffer_overrun_errors"),
+            vl15_dropped: counter("VL15_dropped"),
+            xmit_wait: counter("port_xmit_wait"),
+
+            out_of_sequence: hw_counter("out_of_sequence"),
+            packet_seq_err: hw_counter("packet_seq_err"),
+            duplicate_request: hw_counter("duplicate_request"),
+            rnr_nak_retry_err: hw_counter("rnr_nak_retry_err"),
+            local_ack_timeout_err: hw_counter("local_ack_timeout_err"),
+            implied_nak_seq_err: hw_counter("implied_nak_seq_err"),
+            np_cnp_sent: hw_counter("np_cnp_sent"),
+            rp_cnp_handled: hw_counter("rp_cnp_handled"),
+            np_ecn_marked_roce_packets: hw_counter("np_ecn_marked_roce_packets"),
+
+            hw_counters,
+        })
+    }
+
+    /// The typed counters by name, in the order of the fields, e.g. for a table or CSV columns.
+    pub fn values(&self) -> Vec<(&'static str, Option<u64>)> {
+        vec![
+            ("xmit_bytes", self.xmit_bytes),
+            ("rcv_bytes", self.rcv_bytes),
+            ("xmit_packets", self.xmit_packets),
+            ("rcv_packets", self.rcv_packets),
+            ("unicast_xmit_packets", self.unicast_xmit_packets),
+            ("unicast_rcv_packets", self.unicast_rcv_packets),
+            ("multicast_xmit_packets", self.multicast_xmit_packets),
+            ("multicast_rcv_packets", self.multicast_rcv_packets),
+            ("symbol_errors", self.symbol_errors),
+            ("link_downed", self.link_downed),
+            ("link_error_recovery", self.link_error_recovery),
+            ("rcv_errors", self.rcv_errors),
+            (
+                "rcv_remote_physical_errors",
+                self.rcv_remote_physical_errors,
+            ),
+            ("rcv_switch_relay_errors", self.rcv_switch_relay_errors),
+            ("xmit_discards", self.xmit_discards),
+            ("xmit_constraint_errors", self.xmit_constraint_errors),
+            ("rcv_constraint_errors", self.rcv_constraint_errors),
+            (
+                "local_link_integrity_errors",
+                self.local_link_integrity_errors,
+            ),
+            (
+                "excessive_buffer_overrun_errors",
+                self.excessive_buffer_overrun_errors,
+            ),
+            ("vl15_dropped", self.vl15_dropped),
+            ("xmit_wait", self.xmit_wait),
+            ("out_of_sequence", self.out_of_sequence),
+            ("packet_seq_err", self.packet_seq_err),
+            ("duplicate_request", self.duplicate_request),
+            ("rnr_nak_retry_err", self.rnr_nak_retry_err),
+            ("local_ack_timeout_err", self.local_ack_timeout_err),
+            ("implied_nak_seq_err", self.implied_nak_seq_err),
+            ("np_cnp_sent", self.np_cnp_sent),
+            ("rp_cnp_handled", self.rp_cnp_handled),
+            (
+                "np_ecn_marked_roce_packets",
+                self.np_ecn_marked_roce_packets,
+            ),
+        ]
+    }
+}
+
+/// The typed counters which count errors, see [`PortCounters::values`].
+pub const ERROR_COUNTERS: [&str; 18] = [
+    "symbol_errors",
+    "link_downed",
+    "link_error_recovery",
+    "rcv_errors",
+    "rcv_remote_physical_errors",
+    "rcv_switch_relay_errors",
+    "xmit_discards",
+    "xmit_constraint_errors",
+    "rcv_constraint_errors",
+    "local_link_integrity_errors",
+    "excessive_buffer_overrun_errors",
+    "vl15_dropped",
+    "out_of_sequence",
+    "packet_seq_err",
+    "duplicate_request",
+    "rnr_nak_retry_err",
+    "local_ack_timeout_err",
+    "implied_nak_seq_err",
+];
+
+/// The change of the counters of a port between two samples.
+#[derive(Clone, Debug)]
+pub struct CounterDelta {
+    pub device: String,
+    pub port_num: u8,
+    /// The time between the two samples.
+    pub interval: Duration,
+    /// The deltas of the typed counters, in the order of [`PortCounters::values`]; `None` if
+    /// a counter is missing in either sample.
+    pub values: Vec<(&'static str, Option<u64>)>,
+    /// The deltas of `hw_counters/`.
+    pub hw_counters: BTreeMap<String, u64>,
+    /// Whether a counter went backwards other than by wrapping around, i.e. was reset, e.g. by
+    /// reloading the driver or `perfquery -R`; its delta is then its new value.
+    pub reset: bool,
+    /// The typed counters at the max of their width, e.g. `symbol_errors` at 65535: they stopped
+    /// counting, so their deltas are a lower bound until they are reset.
+    pub saturated: Vec<&'static str>,
+}
+
+impl CounterDelta {
+    /// Compute the deltas between two samples of the same port, `interval` apart.
+    pub fn between(prev: &PortCounters, cur: &PortCounters, interval: Duration) -> Self {
+        let mut reset = false;
+        let mut saturated = vec![];
+
+        let values = prev
+            .values()
+            .into_iter()
+            .zip(cur.values())
+            .map(|((name, prev), (_, cur))| {
+                let delta = match (prev, cur) {
+                    (Some(prev), Some(cur)) => {
+                        let (delta, is_reset) = counter_delta(prev, cur, counter_range(name));
+                        reset |= is_reset;
+                        if counter_max(name) == Some(cur) {
+                            saturated.push(name);
+                        }
+                        Some(delta)
+                    }
+                    _ => None,
+                };
+                (name, delta)
+            })
+            .collect();
+
+        let mut hw_counters = BTreeMap::new();
+        for (name, cur) in &cur.hw_counters {
+            if let Some(prev) = prev.hw_counters.get(name) {
+                let (delta, is_reset) = counter_delta(*prev, *cur, counter_range(name));
+                reset |= is_reset;
+                hw_counters.insert(name.clone(), delta);
+            }
+        }
+
+        Self {
+            device: cur.device.clone(),
+            port_num: cur.port_num,
+            interval,
+            values,
+            hw_counters,
+            reset,
+            saturated,
+        }
+    }
+
+    /// The delta of a typed counter by name, e.g. `xmit_bytes`, or of `hw_counters/`.
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.values
+            .iter()
+            .find(|(n, _)| *n == name)
+            .and_then(|(_, v)| *v)
+            .or_else(|| self.hw_counters.get(name).copied())
+    }
+
+    /// The rate of a counter per second.
+    pub fn rate(&self, name: &str) -> Option<f64> {
+        let secs = self.interval.as_secs_f64();
+        if secs == 0.0 {
+            return None;
+        }
+
+        self.get(name).map(|v| v as f64 / secs)
+    }
+
+    /// The transmit throughput in Gb/s.
+    pub fn xmit_gbps(&self) -> Option<f64> {
+        self.rate("xmit_bytes").map(|v| v * 8.0 / 1e9)
+    }
+
+    /// The receive throughput in Gb/s.
+    pub fn rcv_gbps(&self) -> Option<f64> {
+        self.rate("rcv_bytes").map(|v| v * 8.0 / 1e9)
+    }
+
+    /// The error counters which changed, see [`ERROR_COUNTERS`].
+    pub fn errors(&self) -> Vec<(&'static str, u64)> {
+        self.values
+            .iter()
+            .filter(|(name, _)| ERROR_COUNTERS.contains(name))
+            .filter_map(|(name, v)| v.filter(|v| *v > 0).map(|v| (*name, v)))
+            .collect()
+    }
+}
+
+/// Sample the counters of ports repeatedly and compute the deltas since the previous sample
+/// of each port, e.g. for a rate view.
+#[derive(Clone, Debug, Default)]
+pub struct CounterSampler {
+    sysfs: Sysfs,
+    samples: HashMap<(String, u8), (Instant, PortCounters)>,
+}
+
+impl CounterSampler {
+    pub fn new(sysfs: Sysfs) -> Self {
+        Self {
+            sysfs,
+            samples: HashMap::new(),
+        }
+    }
+
+    /// Read the counters of a port, and return the deltas since its previous sample; `None`
+    /// for the first sample of the port.
+    pub fn sample(&mut self, device: &str, port_num: u8) -> Result<Option<CounterDelta>> {
+        let counters = self.sysfs.read_port_counters(device, port_num)?;
+        let now = Instant::now();
+
+        let key = (device.to_string(), port_num);
+        let delta = self
+            .samples
+            .get(&key)
+            .map(|(at, prev)| CounterDelta::between(prev, &counters, now - *at));
+        self.samples.insert(key, (now, counters));
+
+        Ok(delta)
+    }
+}
+
+/// The max of a typed counter, by its width in the IBTA `PortCounters` attribute; the error
+/// counters saturate there instead of wrapping around. `None` for the counters which wrap
+/// around, see [`counter_range`], and those of `PortCountersExtended`, which are 64 bits.
+fn counter_max(name: &str) -> Option<u64> {
+    let bits = match name {
+        "xmit_wait" => 32,
+        "symbol_errors"
+        | "rcv_errors"
+        | "rcv_remote_physical_errors"
+        | "rcv_switch_relay_errors"
+        | "xmit_discards"
+        | "vl15_dropped" => 16,
+        "link_downed"
+        | "link_error_recovery"
+        | "xmit_constraint_errors"
+        | "rcv_constraint_errors" => 8,
+        "local_link_integrity_errors" | "excessive_buffer_overrun_errors" => 4,
+        _ => return None,
+    };
+
+    Some((1 << bits) - 1)
+}
+
+/// The range of a counter which wraps around, i.e. its max plus one: the legacy 32-bit data and
+/// packet counters of `counters/`, on ports without extended counters, and those of
+/// `hw_counters/`. The data and packet counters are 64 bits if the port supports them, then
+/// they only pass through the max of the legacy width. `None` for the counters which saturate,
+/// see [`counter_max`], and the 64-bit ones of `PortCountersExtended`.
+fn counter_range(name: &str) -> Option<u64> {
+    match name {
+        // In 4-byte words.
+        "xmit_bytes" | "rcv_bytes" => Some(1 << 34),
+        "unicast_xmit_packets"
+        | "unicast_rcv_packets"
+        | "multicast_xmit_packets"
+        | "multicast_rcv_packets" => None,
+        _ if counter_max(name).is_some() => None,
+        _ => Some(1 << 32),
+    }
+}
+
+/// The delta of a counter and whether it was reset. A counter which went backwards from the top
+/// half of its range wrapped around, if it wraps, see [`counter_range`]; otherwise it was reset
+/// and its delta is its new value.
+fn counter_delta(prev: u64, cur: u64, range: Option<u64>) -> (u64, bool) {
+    match (cur.checked_sub(prev), range) {
+        (Some(delta), _) => (delta, false),
+        (None, Some(range)) if prev >= range / 2 && prev < range => (cur + (range - prev), false),
+        (None, _) => (cur, true),
+    }
+}
+
+/// Read a counter; `None` if it is missing or not readable, e.g. some counters of `counters/`
+/// fail with `EINVAL` on Ethernet ports.
+fn read_counter(path: &Path) -> Option<u64> {
+    read_sysfs_attr(path).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn counters(symbol_errors: u64, xmit_bytes: u64, hw_counters: &[(&str, u64)]) -> PortCounters {
+        PortCounters {
+            device: "mlx5_0".to_string(),
+            port_num: 1,
+            xmit_bytes: Some(xmit_bytes),
+            symbol_errors: Some(symbol_errors),
+            hw_counters: hw_counters
+                .iter()
+                .map(|(name, v)| (name.to_string(), *v))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn delta_between_samples() {
+        let prev = counters(1, 1000, &[("out_of_sequence", 7)]);
+        let cur = counters(4, 1_250_001_000, &[("out_of_sequence", 9)]);
+
+        let delta = CounterDelta::between(&prev, &cur, SECOND * 2);
+        assert!(!delta.reset);
+        assert!(delta.saturated.is_empty());
+        assert_eq!(delta.get("symbol_errors"), Some(3));
+        assert_eq!(delta.get("out_of_sequence"), Some(2));
+        assert_eq!(delta.xmit_gbps(), Some(5.0));
+        // Missing in both samples.
+        assert_eq!(delta.get("rcv_bytes"), None);
+        assert_eq!(delta.rcv_gbps(), None);
+        assert_eq!(delta.errors(), [("symbol_errors", 3)]);
+
+        let delta = CounterDelta::between(&prev, &cur, Duration::ZERO);
+        assert_eq!(delta.rate("symbol_errors"), None);
+    }
+
+    #[test]
+    fn delta_of_reset_counters() {
+        let prev = counters(100, 5000, &[]);
+        let cur = counters(2, 6000, &[]);
+
+        let delta = CounterDelta::between(&prev, &cur, SECOND);
+        assert!(delta.reset);
+        assert_eq!(delta.get("symbol_errors"), Some(2));
+        assert_eq!(delta.get("xmit_bytes"), Some(1000));
+
+        let prev = counters(0, 0, &[("rnr_nak_retry_err", 40)]);
+        let cur = counters(0, 0, &[("rnr_nak_retry_err", 1)]);
+        let delta = CounterDelta::between(&prev, &cur, SECOND);
+        assert!(delta.reset);
+        assert_eq!(delta.get("rnr_nak_retry_err"), Some(1));
+    }
+
+    #[test]
+    fn delta_of_wrapped_counters() {
+        let max = u64::from(u32::MAX) * 4;
+        let prev = counters(0, max - 400, &[("out_of_sequence", u64::from(u32::MAX))]);
+        let cur = counters(0, 600, &[("out_of_sequence", 5)]);
+
+        let delta = CounterDelta::between(&prev, &cur, SECOND);
+        assert!(!delta.reset);
+        assert_eq!(delta.get("xmit_bytes"), Some(1004));
+        assert_eq!(delta.get("out_of_sequence"), Some(6));
+
+        // A counter which went backwards from the bottom half of its range was reset.
+        let prev = counters(0, 1 << 20, &[("out_of_sequence", 1 << 30)]);
+        let cur = counters(0, 600, &[("out_of_sequence", 5)]);
+        let delta = CounterDelta::between(&prev, &cur, SECOND);
+        assert!(delta.reset);
+        assert_eq!(delta.get("xmit_bytes"), Some(600));
+        assert_eq!(delta.get("out_of_sequence"), Some(5));
+
+        // A 64-bit data counter passes through the max of the legacy width, and does not wrap
+        // around from above it.
+        let prev = counters(0, max - 400, &[]);
+        let cur = counters(0, max + 600, &[]);
+        let delta = CounterDelta::between(&prev, &cur, SECOND);
+        assert!(!delta.reset && delta.saturated.is_empty());
+        assert_eq!(delta.get("xmit_bytes"), Some(1000));
+
+        let delta = CounterDelta::between(&cur, &prev, SECOND);
+        assert!(delta.reset);
+        assert_eq!(delta.get("xmit_bytes"), Some(max - 400));
+    }
+
+    #[test]
+    fn counter_deltas() {
+        let range = Some(1 << 32);
+        assert_eq!(counter_delta(u64::from(u32::MAX), 5, range), (6, false));
+        assert_eq!(counter_delta(1 << 31, 0, range), (1 << 31, false));
+        assert_eq!(counter_delta((1 << 31) - 1, 0, range), (0, true));
+        // The error counters saturate.
+        assert_eq!(counter_delta(65535, 2, None), (2, true));
+        assert_eq!(counter_delta(5, 5, None), (0, false));
+    }
+
+    #[test]
+    fn delta_of_saturated_counters() {
+        let prev = counters(65530, 0, &[]);
+        let cur = counters(65535, 0, &[]);
+        let delta = CounterDelta::between(&prev, &cur, SECOND);
+        assert!(!delta.reset);
+        assert_eq!(delta.get("symbol_errors"), Some(5));
+        assert_eq!(delta.saturated, ["symbol_errors"]);
+
+        // It stays at the max, whatever the errors of the interval.
+        let delta = CounterDelta::between(&cur, &cur, SECOND);
+        assert_eq!(delta.get("symbol_errors"), Some(0));
+        assert_eq!(delta.saturated, ["symbol_errors"]);
+
+        let prev = PortCounters {
+            link_downed: Some(250),
+            excessive_buffer_overrun_errors: Some(15),
+            xmit_wait: Some(0),
+            xmit_packets: Some(0),
+            rcv_errors: Some(0),
+            ..Default::default()
+        };
+        let cur = PortCounters {
+            link_downed: Some(255),
+            excessive_buffer_overrun_errors: Some(15),
+            xmit_wait: Some(u64::from(u32::MAX)),
+            // The data and packet counters wrap around at their max.
+            xmit_packets: Some(u64::from(u32::MAX)),
+            // Below the max of its width.
+            rcv_errors: Some(255),
+            ..Default::default()
+        };
+        let delta = CounterDelta::between(&prev, &cur, SECOND);
+        assert_eq!(
+            delta.saturated,
+            [
+                "link_downed",
+                "excessive_buffer_overrun_errors",
+                "xmit_wait"
+            ]
+        );
+    }
+
+    #[test]
+    fn ecn_marks_are_not_errors() {
+        let prev = PortCounters {
+            np_cnp_sent: Some(0),
+            np_ecn_marked_roce_packets: Some(0),
+            ..Default::default()
+        };
+        let cur = PortCounters {
+            np_cnp_sent: Some(1000),
+            np_ecn_marked_roce_packets: Some(1000),
+            ..Default::default()
+        };
+
+        let delta = CounterDelta::between(&prev, &cur, SECOND);
+        assert_eq!(delta.get("np_ecn_marked_roce_packets"), Some(1000));
+        assert!(delta.errors().is_empty());
+    }
+
+    #[test]
+    fn sample_counters() {
+        let dir = TempDir::new();
+        let port = "class/infiniband/mlx5_0/ports/1";
+        dir.write(format!("{}/counters/port_xmit_data", port), "100\n");
+        dir.write(format!("{}/counters/symbol_error", port), "0\n");
+        dir.write(format!("{}/hw_counters/out_of_sequence", port), "3\n");
+        dir.write(format!("{}/hw_counters/lifespan", port), "10\n");
+
+        let mut sampler = CounterSampler::new(Sysfs::new(dir.path()));
+        assert!(sampler.sample("mlx5_0", 1).unwrap().is_none());
+
+        dir.write(format!("{}/counters/port_xmit_data", port), "150\n");
+        dir.write(format!("{}/counters/symbol_error", port), "2\n");
+        let delta = sampler.sample("mlx5_0", 1).unwrap().unwrap();
+        assert_eq!(delta.get("xmit_bytes"), Some(200));
+        assert_eq!(delta.get("symbol_errors"), Some(2));
+        assert_eq!(delta.get("out_of_sequence"), Some(0));
+        assert_eq!(delta.get("lifespan"), None);
+        assert_eq!(delta.get("rcv_bytes"), None);
+
+        assert!(sampler.sample("mlx5_0", 2).is_err());
+    }
+}
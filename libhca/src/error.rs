@@ -0,0 +1,110 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use std::io;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, HcaError>;
+
+#[derive(Debug, Error)]
+pub enum HcaError {
+    #[error("{device}: missing udev property <{name}>")]
+    MissingProperty { device: String, name: String },
+
+    #[error("{device}: missing sysfs attribute <{name}>")]
+    MissingAttribute { device: String, name: String },
+
+    #[error("{device}: invalid value <{value}> of <{name}>")]
+    InvalidValue {
+        device: String,
+        name: String,
+        value: String,
+    },
+
+    #[error("{device}: invalid value <{value}> of {}", .path.display())]
+    InvalidAttribute {
+        device: String,
+        path: PathBuf,
+        value: String,
+    },
+
+    #[error("unknown {kind} <{value}>")]
+    UnknownValue { kind: &'static str, value: u32 },
+
+    #[error("{device}: {call} failed: {}", io::Error::from_raw_os_error(*.errno))]
+    Verbs {
+        device: String,
+        call: &'static str,
+        errno: i32,
+    },
+
+    #[error("{device} port {port_num}: {error}")]
+    Port {
+        device: String,
+        port_num: u8,
+        error: Box<HcaError>,
+    },
+
+    #[error("{}: {error}", .path.display())]
+    Sysfs { path: PathBuf, error: io::Error },
+
+    #[error("{device}: the local CPUs are unknown")]
+    NoLocalCpus { device: String },
+
+    #[error("{device}: failed to set the CPU affinity: {error}")]
+    Affinity { device: String, error: io::Error },
+
+    #[error("{device}: not an SR-IOV physical function")]
+    NotSriovPf { device: String },
+
+    #[error("failed to wait for udev events: {0}")]
+    Monitor(io::Error),
+
+    #[error("udev: {0}")]
+    Udev(#[from] libudev::Error),
+}
+
+impl HcaError {
+    /// The error of a verbs call which reports the failure in `errno`, e.g. `ibv_open_device`.
+    pub(crate) fn last_verbs_error(device: &str, call: &'static str) -> Self {
+        Self::Verbs {
+            device: device.to_string(),
+            call,
+            errno: io::Error::last_os_error()
+                .raw_os_error()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The error of a verbs call which returns the errno, e.g. `ibv_query_port`.
+    pub(crate) fn verbs_error(device: &str, call: &'static str, errno: i32) -> Self {
+        Self::Verbs {
+            device: device.to_string(),
+            call,
+            errno,
+        }
+    }
+
+    pub(crate) fn port_error(device: &str, port_num: u8, e: HcaError) -> Self {
+        Self::Port {
+            device: device.to_string(),
+            port_num,
+            error: Box::new(e),
+        }
+    }
+}
@@ -0,0 +1,594 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Hotplug events of the HCAs from udev, e.g. a VF created or a device reset by the firmware.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use libudev::{Context, Device, Monitor, MonitorSocket};
+use serde::Serialize;
+
+use super::error::{HcaError, Result};
+use super::sriov::read_physfn;
+use super::sysfs::Sysfs;
+use super::utils::{read_sysfs_dir, read_sysfs_link_name};
+
+/// The subsystems of the events.
+const SUBSYSTEMS: [&str; 3] = ["infiniband", "net", "pci"];
+/// The PCI class of an InfiniBand controller; the class of a RoCE function is Ethernet.
+const PCI_CLASS_INFINIBAND: u32 = 0x0207;
+
+/// The action of an event, i.e. `ACTION` of the uevent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HcaAction {
+    Add,
+    Remove,
+    Change,
+    /// A driver was bound to a PCI function.
+    Bind,
+    /// The driver was unbound from a PCI function, e.g. by a firmware reset.
+    Unbind,
+    /// A network interface was renamed.
+    Move,
+}
+
+impl Display for HcaAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Add => f.write_str("add"),
+            Self::Remove => f.write_str("remove"),
+            Self::Change => f.write_str("change"),
+            Self::Bind => f.write_str("bind"),
+            Self::Unbind => f.write_str("unbind"),
+            Self::Move => f.write_str("move"),
+        }
+    }
+}
+
+impl HcaAction {
+    fn parse(v: &str) -> Option<Self> {
+        match v {
+            "add" => Some(Self::Add),
+            "remove" => Some(Self::Remove),
+            "change" => Some(Self::Change),
+            "bind" => Some(Self::Bind),
+            "unbind" => Some(Self::Unbind),
+            "move" => Some(Self::Move),
+            _ => None,
+        }
+    }
+}
+
+/// The kind of the device of an event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HcaEventKind {
+    /// An RDMA device, e.g. `mlx5_0`.
+    IbDevice,
+    /// A network interface of an RDMA function, e.g. `ens1f0`.
+    NetDev,
+    /// A PCI function of an HCA, e.g. `0000:b1:00.2`.
+    PciFunction,
+}
+
+impl Display for HcaEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IbDevice => f.write_str("ib_device"),
+            Self::NetDev => f.write_str("netdev"),
+            Self::PciFunction => f.write_str("pci_function"),
+        }
+    }
+}
+
+/// An event of an HCA.
+#[derive(Clone, Debug, Serialize)]
+pub struct HcaEvent {
+    /// The sequence number of the uevent, increasing by one for every event of the kernel.
+    pub seqnum: u64,
+    pub action: HcaAction,
+    pub kind: HcaEventKind,
+    /// The name of the device, e.g. `mlx5_0`, `ens1f0` or `0000:b1:00.2`.
+    pub name: String,
+    /// The PCI function of the device; `None` for a software device, e.g. `rxe0`.
+    pub slot_name: Option<String>,
+    /// The PF of the function if it is a VF.
+    pub physfn: Option<String>,
+    /// The driver of the function, e.g. `mlx5_core`; `None` if unbound.
+    pub driver: Option<String>,
+}
+
+impl Display for HcaEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.action, self.kind, self.name)?;
+        if let Some(slot_name) = self.slot_name.as_ref().filter(|s| **s != self.name) {
+            write!(f, " ({})", slot_name)?;
+        }
+        if let Some(physfn) = &self.physfn {
+            write!(f, " VF of {}", physfn)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Subscribe to the udev events of the HCAs: the RDMA devices, their network interfaces and
+/// their PCI functions, including the VFs of their PFs.
+///
+/// The socket is non-blocking: [`HcaMonitor::try_next`] returns the pending events, and
+/// [`HcaMonitor::wait`] blocks for the next one; the monitor can also be polled by its fd.
+pub struct HcaMonitor {
+    socket: MonitorSocket,
+    /// The PCI functions of the HCAs with their PF, i.e. the functions of which the events
+    /// are reported; the sysfs of a removed function is gone, so it is tracked here.
+    functions: HashMap<String, Option<String>>,
+}
+
+impl HcaMonitor {
+    pub fn new() -> Result<Self> {
+        let context = Context::new()?;
+        let mut monitor = Monitor::new(&context)?;
+        for subsystem in SUBSYSTEMS {
+            monitor.match_subsystem(subsystem)?;
+        }
+        let socket = monitor.listen()?;
+
+        // The functions are read after listening, so that none is missed in between.
+        Ok(Self {
+            socket,
+            functions: read_rdma_functions(&Sysfs::default()),
+        })
+    }
+
+    /// The next pending event of the HCAs; `None` if there is none, the events of other
+    /// devices are skipped.
+    pub fn try_next(&mut self) -> Option<HcaEvent> {
+        while let Some(event) = self.socket.receive_event() {
+            if let Some(event) = self.hca_event(event.device()) {
+                return Some(event);
+            }
+        }
+
+        None
+    }
+
+    /// Wait for the next event of the HCAs, up to the timeout if any; `None` if timed out.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<Option<HcaEvent>> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            if let Some(event) = self.try_next() {
+                return Ok(Some(event));
+            }
+
+            let mut fds = libc::pollfd {
+                fd: self.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // The events of other devices wake up the poll too, so the timeout is what is left.
+            let timeout: i32 = deadline.map_or(-1, |d| {
+                d.saturating_duration_since(Instant::now())
+                    .as_millis()
+                    .try_into()
+                    .unwrap_or(i32::MAX)
+            });
+            match unsafe { libc::poll(&mut fds, 1, timeout) } {
+                0 => return Ok(None),
+                n if n < 0 => {
+                    let error = io::Error::last_os_error();
+                    if error.kind() != io::ErrorKind::Interrupted {
+                        return Err(HcaError::Monitor(error));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn hca_event(&mut self, device: &Device) -> Option<HcaEvent> {
+        let syspath = device.syspath()?;
+        let subsystem = device.subsystem().and_then(|v| v.to_str())?;
+        // The PCI function of a PCI function or RDMA device, e.g.
+        // `.../0000:b1:00.0/infiniband/mlx5_0`; the sysfs of a removed one is gone.
+        let pci_path = match subsystem {
+            "pci" => Some(syspath),
+            "infiniband" => syspath.parent().and_then(Path::parent),
+            _ => None,
+        };
+
+        let uevent = Uevent {
+            action: device.property_value("ACTION").and_then(|v| v.to_str())?,
+            subsystem,
+            sysname: device.sysname().and_then(|v| v.to_str())?,
+            syspath,
+            properties: device
+                .properties()
+                .filter_map(|p| {
+                    let name = p.name().to_str()?.to_string();
+                    Some((name, p.value().to_str()?.to_string()))
+                })
+                .collect(),
+            // The driver of a PCI function is in the uevent, even if it is removed.
+            driver: device
+                .driver()
+                .or_else(|| device.property_value("DRIVER"))
+                .and_then(|v| v.to_str())
+                .map(|v| v.to_string())
+                .or_else(|| read_sysfs_link_name(syspath.join("device").join("driver")).ok()),
+            physfn: pci_path.and_then(|p| {
+                let slot_name = p.file_name()?.to_str()?;
+                read_physfn(p, slot_name).map(|(physfn, _)| physfn)
+            }),
+        };
+
+        classify_event(&mut self.functions, &uevent)
+    }
+}
+
+/// The fields of a uevent, with what is read from the sysfs of its device, to classify it.
+struct Uevent<'a> {
+    /// `ACTION` of the uevent, e.g. `add`.
+    action: &'a str,
+    subsystem: &'a str,
+    sysname: &'a str,
+    syspath: &'a Path,
+    /// The properties of the uevent, e.g. `PCI_CLASS` and `SEQNUM`.
+    properties: HashMap<String, String>,
+    /// The driver of the device, or the one of its `device` in sysfs.
+    driver: Option<String>,
+    /// The PF of the PCI function of the device if it is a VF.
+    physfn: Option<String>,
+}
+
+/// Classify a uevent as an event of the HCAs, and track the functions of the HCAs in
+/// `functions`, see [`HcaMonitor::functions`]; `None` for the events of other devices.
+fn classify_event(
+    functions: &mut HashMap<String, Option<String>>,
+    uevent: &Uevent,
+) -> Option<HcaEvent> {
+    let action = HcaAction::parse(uevent.action)?;
+    let name = uevent.sysname.to_string();
+    let property = |name: &str| uevent.properties.get(name).map(String::as_str);
+
+    let (kind, slot_name) = match uevent.subsystem {
+        "infiniband" => {
+            let slot_name = slot_of(uevent.syspath);
+            // The functions of new devices, e.g. of a VF once its driver is bound.
+            if let Some(slot_name) = &slot_name {
+                functions
+                    .entry(slot_name.clone())
+                    .or_insert_with(|| uevent.physfn.clone());
+            }
+            (HcaEventKind::IbDevice, slot_name)
+        }
+        "net" => {
+            let slot_name = slot_of(uevent.syspath).filter(|s| functions.contains_key(s))?;
+            (HcaEventKind::NetDev, Some(slot_name))
+        }
+        "pci" => {
+            let is_infiniband = property("PCI_CLASS").is_some_and(is_infiniband_class);
+            let is_vf_of_hca = uevent
+                .physfn
+                .as_ref()
+                .is_some_and(|pf| functions.contains_key(pf));
+            if !(is_infiniband || is_vf_of_hca || functions.contains_key(&name)) {
+                return None;
+            }
+            if action != HcaAction::Remove {
+                functions.insert(name.clone(), uevent.physfn.clone());
+            }
+            (HcaEventKind::PciFunction, Some(name.clone()))
+        }
+        _ => return None,
+    };
+
+    let physfn = slot_name
+        .as_ref()
+        .and_then(|s| functions.get(s).cloned().flatten());
+    if kind == HcaEventKind::PciFunction && action == HcaAction::Remove {
+        functions.remove(&name);
+    }
+
+    Some(HcaEvent {
+        seqnum: property("SEQNUM")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default(),
+        action,
+        kind,
+        name,
+        slot_name,
+        physfn,
+        driver: uevent.driver.clone(),
+    })
+}
+
+impl AsRawFd for HcaMonitor {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+/// The PCI functions of the RDMA devices on the host with their PF, see
+/// [`HcaMonitor::functions`].
+fn read_rdma_functions(sysfs: &Sysfs) -> HashMap<String, Option<String>> {
+    let mut functions = HashMap::new();
+
+    for name in read_sysfs_dir(sysfs.class_infiniband()).unwrap_or_default() {
+        let pci_path = sysfs.class_infiniband().join(&name).join("device");
+        if let Some(slot_name) = read_sysfs_link_name(&pci_path)
+            .ok()
+            .filter(|s| is_pci_slot(s))
+        {
+            let physfn = read_physfn(&pci_path, &slot_name).map(|(physfn, _)| physfn);
+            functions.insert(slot_name, physfn);
+        }
+    }
+
+    functions
+}
+
+/// The PCI function of a device by its sysfs path, e.g. `0000:b1:00.0` of
+/// `/sys/devices/pci0000:b0/0000:b0:02.0/0000:b1:00.0/net/ens1f0`; the path is used as the
+/// sysfs of a removed device is gone.
+fn slot_of(syspath: &Path) -> Option<String> {
+    syspath
+        .ancestors()
+        .skip(1)
+        .filter_map(|p| p.file_name()?.to_str())
+        .find(|name| is_pci_slot(name))
+        .map(|name| name.to_string())
+}
+
+/// Whether `PCI_CLASS` of a uevent, i.e. the class, subclass and programming interface in
+/// hex, e.g. `20700`, is of an InfiniBand controller.
+fn is_infiniband_class(v: &str) -> bool {
+    u32::from_str_radix(v, 16).is_ok_and(|class| class >> 8 == PCI_CLASS_INFINIBAND)
+}
+
+/// Whether the name is a PCI address, i.e. `<domain>:<bus>:<device>.<function>`.
+fn is_pci_slot(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    bytes.len() == 12
+        && bytes.iter().enumerate().all(|(i, b)| match i {
+            4 | 7 => *b == b':',
+            10 => *b == b'.',
+            _ => b.is_ascii_hexdigit(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pci_slots() {
+        assert!(is_pci_slot("0000:b1:00.0"));
+        assert!(is_pci_slot("0000:B1:1f.7"));
+
+        assert!(!is_pci_slot("10000:00:00.0"));
+        assert!(!is_pci_slot("b1:00.0"));
+        assert!(!is_pci_slot("0000:b1:00:0"));
+        assert!(!is_pci_slot("0000-b1:00.0"));
+        assert!(!is_pci_slot("0000:g1:00.0"));
+        assert!(!is_pci_slot("mlx5_0"));
+        assert!(!is_pci_slot("pci0000:b0"));
+        assert!(!is_pci_slot(""));
+    }
+
+    #[test]
+    fn slots_of_paths() {
+        let slot = |v: &str| slot_of(Path::new(v));
+
+        assert_eq!(
+            slot("/sys/devices/pci0000:b0/0000:b0:02.0/0000:b1:00.0/net/ens1f0"),
+            Some("0000:b1:00.0".to_string())
+        );
+        assert_eq!(
+            slot("/sys/devices/pci0000:b0/0000:b0:02.0/0000:b1:00.0/infiniband/mlx5_0"),
+            Some("0000:b1:00.0".to_string())
+        );
+        // The function itself is not its own slot, but the bridge above it.
+        assert_eq!(
+            slot("/sys/devices/pci0000:b0/0000:b0:02.0/0000:b1:00.0"),
+            Some("0000:b0:02.0".to_string())
+        );
+        assert_eq!(slot("/sys/devices/virtual/net/rxe0"), None);
+        assert_eq!(slot("/sys/devices/pci0000:b0"), None);
+    }
+
+    #[test]
+    fn infiniband_classes() {
+        assert!(is_infiniband_class("20700"));
+        assert!(is_infiniband_class("020700"));
+        assert!(is_infiniband_class("207ff"));
+
+        // Ethernet, e.g. a RoCE function, and other network controllers.
+        assert!(!is_infiniband_class("20000"));
+        assert!(!is_infiniband_class("28000"));
+        assert!(!is_infiniband_class("0207"));
+        assert!(!is_infiniband_class("2070000"));
+        assert!(!is_infiniband_class("0x20700"));
+        assert!(!is_infiniband_class(""));
+    }
+
+    const PF: &str = "/sys/devices/pci0000:b0/0000:b0:02.0/0000:b1:00.0";
+    const VF: &str = "/sys/devices/pci0000:b0/0000:b0:02.0/0000:b1:00.2";
+
+    /// A uevent of the device at `syspath`, with its driver and PF, and `PCI_CLASS` if any.
+    fn uevent<'a>(
+        action: &'a str,
+        subsystem: &'a str,
+        syspath: &'a str,
+        pci_class: Option<&str>,
+        physfn: Option<&str>,
+    ) -> Uevent<'a> {
+        let syspath = Path::new(syspath);
+        let mut properties = HashMap::from([("SEQNUM".to_string(), "4242".to_string())]);
+        if let Some(pci_class) = pci_class {
+            properties.insert("PCI_CLASS".to_string(), pci_class.to_string());
+        }
+
+        Uevent {
+            action,
+            subsystem,
+            sysname: syspath.file_name().and_then(|v| v.to_str()).unwrap(),
+            syspath,
+            properties,
+            driver: Some("mlx5_core".to_string()),
+            physfn: physfn.map(|v| v.to_string()),
+        }
+    }
+
+    #[test]
+    fn classify_add_events() {
+        let mut functions = HashMap::new();
+
+        // An InfiniBand PF, then a VF of it, which is Ethernet for RoCE.
+        let event = classify_event(
+            &mut functions,
+            &uevent("add", "pci", PF, Some("20700"), None),
+        )
+        .unwrap();
+        assert_eq!(event.to_string(), "add pci_function 0000:b1:00.0");
+        assert_eq!(event.seqnum, 4242);
+        assert_eq!(event.driver.as_deref(), Some("mlx5_core"));
+
+        let event = classify_event(
+            &mut functions,
+            &uevent("add", "pci", VF, Some("20000"), Some("0000:b1:00.0")),
+        )
+        .unwrap();
+        assert_eq!(
+            event.to_string(),
+            "add pci_function 0000:b1:00.2 VF of 0000:b1:00.0"
+        );
+
+        // The devices of the VF, once its driver is bound.
+        let ib_device = format!("{}/infiniband/mlx5_2", VF);
+        let event = classify_event(
+            &mut functions,
+            &uevent("add", "infiniband", &ib_device, None, Some("0000:b1:00.0")),
+        )
+        .unwrap();
+        assert_eq!(event.kind, HcaEventKind::IbDevice);
+        assert_eq!(event.slot_name.as_deref(), Some("0000:b1:00.2"));
+        assert_eq!(event.physfn.as_deref(), Some("0000:b1:00.0"));
+
+        let netdev = format!("{}/net/ens1f0v0", VF);
+        let event =
+            classify_event(&mut functions, &uevent("add", "net", &netdev, None, None)).unwrap();
+        assert_eq!(
+            event.to_string(),
+            "add netdev ens1f0v0 (0000:b1:00.2) VF of 0000:b1:00.0"
+        );
+
+        // Other devices.
+        let nvme = "/sys/devices/pci0000:00/0000:00:1d.0/0000:3b:00.0";
+        assert!(classify_event(
+            &mut functions,
+            &uevent("add", "pci", nvme, Some("10802"), None)
+        )
+        .is_none());
+        let eth = "/sys/devices/pci0000:00/0000:00:1c.0/0000:3c:00.0/net/eno1";
+        assert!(classify_event(&mut functions, &uevent("add", "net", eth, None, None)).is_none());
+        assert!(
+            classify_event(&mut functions, &uevent("add", "block", nvme, None, None)).is_none()
+        );
+        assert!(classify_event(&mut functions, &uevent("online", "pci", PF, None, None)).is_none());
+
+        assert_eq!(
+            functions,
+            HashMap::from([
+                ("0000:b1:00.0".to_string(), None),
+                ("0000:b1:00.2".to_string(), Some("0000:b1:00.0".to_string())),
+            ])
+        );
+    }
+
+    #[test]
+    fn classify_remove_events() {
+        let mut functions = HashMap::from([
+            ("0000:b1:00.0".to_string(), None),
+            ("0000:b1:00.2".to_string(), Some("0000:b1:00.0".to_string())),
+        ]);
+
+        // The VF is reported with its PF once its sysfs is gone, then untracked.
+        let netdev = format!("{}/net/ens1f0v0", VF);
+        let event = classify_event(
+            &mut functions,
+            &uevent("remove", "net", &netdev, None, None),
+        )
+        .unwrap();
+        assert_eq!(event.physfn.as_deref(), Some("0000:b1:00.0"));
+
+        let event =
+            classify_event(&mut functions, &uevent("remove", "pci", VF, None, None)).unwrap();
+        assert_eq!(
+            event.to_string(),
+            "remove pci_function 0000:b1:00.2 VF of 0000:b1:00.0"
+        );
+        assert!(!functions.contains_key("0000:b1:00.2"));
+
+        assert!(classify_event(
+            &mut functions,
+            &uevent("remove", "net", &netdev, None, None)
+        )
+        .is_none());
+        assert!(classify_event(&mut functions, &uevent("remove", "pci", VF, None, None)).is_none());
+    }
+
+    #[test]
+    fn classify_firmware_reset_events() {
+        let mut functions = HashMap::from([("0000:b1:00.0".to_string(), None)]);
+
+        // A firmware reset unbinds the driver, removes the devices of the PF and reports the
+        // change of the function; it is tracked throughout.
+        let event =
+            classify_event(&mut functions, &uevent("unbind", "pci", PF, None, None)).unwrap();
+        assert_eq!(event.action, HcaAction::Unbind);
+
+        let ib_device = format!("{}/infiniband/mlx5_0", PF);
+        let event = classify_event(
+            &mut functions,
+            &uevent("remove", "infiniband", &ib_device, None, None),
+        )
+        .unwrap();
+        assert_eq!(event.to_string(), "remove ib_device mlx5_0 (0000:b1:00.0)");
+
+        let event =
+            classify_event(&mut functions, &uevent("change", "pci", PF, None, None)).unwrap();
+        assert_eq!(event.to_string(), "change pci_function 0000:b1:00.0");
+        assert_eq!(
+            functions,
+            HashMap::from([("0000:b1:00.0".to_string(), None)])
+        );
+
+        let event = classify_event(&mut functions, &uevent("bind", "pci", PF, None, None)).unwrap();
+        assert_eq!(event.action, HcaAction::Bind);
+        let event = classify_event(
+            &mut functions,
+            &uevent("add", "infiniband", &ib_device, None, None),
+        )
+        .unwrap();
+        assert_eq!(event.to_string(), "add ib_device mlx5_0 (0000:b1:00.0)");
+    }
+}
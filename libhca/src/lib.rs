@@ -19,149 +19,508 @@ limitations under the License.
 #![allow(non_snake_case)]
 #![allow(dead_code)]
 
-mod types;
+pub mod caps;
+pub mod counters;
+pub mod error;
+pub mod hotplug;
+pub mod netdev;
+mod numa;
+pub mod pcie;
+pub mod sriov;
+pub mod sysfs;
+#[cfg(test)]
+mod testutil;
+pub mod types;
+mod umad;
 mod utils;
+pub mod vpd;
 mod wrappers;
 
 use std::alloc::{self, Layout};
-use std::collections::HashMap;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
 
 use std::os::raw::c_int;
+use std::path::Path;
 use std::ptr::NonNull;
 use std::slice;
-use std::{io, vec};
+use std::vec;
 
-use numeric_cast::NumericCast;
 use scopeguard::defer;
 
 use wrappers::ibverbs::{
-    ibv_close_device, ibv_device_attr, ibv_free_device_list, ibv_get_device_list, ibv_gid,
-    ibv_open_device, ibv_port_attr, ibv_query_device, ibv_query_gid, ibv_query_port,
+    __be16, _ibv_query_gid_table, ibv_close_device, ibv_context, ibv_device_attr,
+    ibv_device_attr_ex, ibv_free_device_list, ibv_get_device_list, ibv_gid, ibv_gid_entry,
+    ibv_open_device, ibv_port_attr, ibv_query_device, ibv_query_gid, ibv_query_pkey,
+    ibv_query_port,
 };
+#[cfg(hca_mlx5dv)]
+use wrappers::ibverbs_ex::hca_mlx5_query_dc_odp_caps;
+use wrappers::ibverbs_ex::hca_query_device_ex;
+
+pub use error::HcaError;
+
+use caps::{OdpCapabilities, PortCapabilities};
+use counters::PortCounters;
+use error::Result;
+use sysfs::Sysfs;
+use types::{
+    DevicePtr, Discovery, DiscoveryWarning, Gid, GidEntry, GidType, IbDevice, IbDeviceCaps,
+    IbDeviceExtendedCaps, IbMtu, IbPort, IbPortLinkType, IbPortPhysState, IbPortSpeed, IbPortState,
+    IbPortWidth, PKey, PKeyEntry, PciDevice,
+};
+use utils::{cstr_to_string, ifindex_to_name};
+
+/// List the HCAs on the host; the devices and ports which can not be read are skipped,
+/// see [`discover_pci_devices`] for the details of them.
+pub fn list_pci_devices() -> Result<Vec<PciDevice>> {
+    Ok(discover_pci_devices()?.pci_devices)
+}
 
-use types::{DevicePtr, IbDevice, IbPort, IbPortLinkType, IbPortPhysState, IbPortState, PciDevice};
-use utils::cstr_to_string;
+/// Discover the HCAs on the host. A device or port which can not be read does not abort the
+/// discovery, it is reported in [`Discovery::warnings`] instead.
+pub fn discover_pci_devices() -> Result<Discovery> {
+    let mut warnings = vec![];
+
+    let mut verbs_devs = match list_verbs_devices(&mut warnings) {
+        Ok(verbs_devs) => verbs_devs,
+        Err(e) => {
+            warnings.push(DiscoveryWarning::new("-", e));
+            HashMap::new()
+        }
+    };
+    let if_addrs = netdev::read_interface_addresses();
+    let class_net = Sysfs::default().class_net();
 
-/// List the HCAs on the host.
-pub fn list_pci_devices() -> io::Result<Vec<PciDevice>> {
-    let ib_ports = list_ib_ports()?;
     let context = libudev::Context::new()?;
 
     let mut enumerator = libudev::Enumerator::new(&context)?;
     enumerator.match_subsystem("infiniband")?;
     let devices = enumerator.scan_devices()?;
 
-    let mut pci_devs = HashMap::<String, PciDevice>::new();
+    let mut pci_devs = BTreeMap::<String, PciDevice>::new();
     for device in devices {
-        if let Some(parent) = device.parent() {
-            let pci_dev = PciDevice::try_from(parent)?;
-            let pci_dev = pci_devs.entry(pci_dev.subsys_id.clone()).or_insert(pci_dev);
+        let name = device
+            .sysname()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+
+        // A software device, e.g. rxe or siw, has no PCI parent; it is an adapter of its own.
+        let pci_parent = device
+            .parent()
+            .filter(|p| p.subsystem().is_some_and(|s| s == "pci"));
+
+        let ib_path = device.syspath().map(Path::to_path_buf);
+        let mut ib_dev = match IbDevice::try_from(device) {
+            Ok(ib_dev) => ib_dev,
+            Err(e) => {
+                warnings.push(DiscoveryWarning::new(&name, e));
+                continue;
+            }
+        };
+        let pci_dev = match pci_parent {
+            Some(parent) => match PciDevice::try_from(parent) {
+                Ok(pci_dev) => pci_dev,
+                Err(e) => {
+                    warnings.push(DiscoveryWarning::new(&name, e));
+                    continue;
+                }
+            },
+            None => PciDevice::software(&ib_dev),
+        };
+
+        if let Some(verbs_dev) = verbs_devs.remove(&ib_dev.name) {
+            ib_dev.caps = Some(verbs_dev.caps);
+            ib_dev.ext_caps = verbs_dev.ext_caps;
+            ib_dev.ib_ports = verbs_dev.ib_ports;
+        }
+        if let Some(ib_path) = &ib_path {
+            for port in &mut ib_dev.ib_ports {
+                port.netdevs = netdev::read_port_netdevs(ib_path, &class_net, port, &if_addrs);
+            }
+        }
 
-            let mut ib_dev = IbDevice::try_from(device)?;
-            ib_dev.ib_ports = ib_ports
-                .get(&ib_dev.name)
-                .unwrap_or(&Vec::<IbPort>::new())
-                .to_vec();
+        merge_ib_device(&mut pci_devs, pci_dev, ib_dev);
+    }
 
-            pci_dev.fw_ver = ib_dev.fw_ver.clone();
-            pci_dev.board_id = ib_dev.board_id.clone();
+    Ok(Discovery {
+        pci_devices: pci_devs.into_values().collect(),
+        warnings,
+    })
+}
+
+/// List the HCAs from the sysfs mounted at `root`, without libudev or libibverbs.
+pub fn list_pci_devices_from_sysfs<P: AsRef<Path>>(root: P) -> Result<Vec<PciDevice>> {
+    Ok(Sysfs::new(root).discover_pci_devices()?.pci_devices)
+}
+
+/// Read the counters of a port, e.g. port 1 of `mlx5_0`, see [`Sysfs::read_port_counters`];
+/// see [`counters::CounterSampler`] for their rates.
+pub fn read_port_counters(device: &str, port_num: u8) -> Result<PortCounters> {
+    Sysfs::default().read_port_counters(device, port_num)
+}
 
-            pci_dev.ib_devices.push(ib_dev);
+/// Add an RDMA device to its adapter; the adapters are sorted by their ID, and the devices
+/// of an adapter by their slot. A software device is an adapter of its own, by its name.
+fn merge_ib_device(
+    pci_devs: &mut BTreeMap<String, PciDevice>,
+    mut pci_dev: PciDevice,
+    ib_dev: IbDevice,
+) {
+    pci_dev.adapter_id = PciDevice::adapter_id_of(&ib_dev);
+
+    // The PCI attributes of an adapter, e.g. the VPD and PCIe link, are those of a PF, even
+    // if a VF was found first.
+    let pci_dev = match pci_devs.entry(pci_dev.adapter_id.clone()) {
+        Entry::Vacant(entry) => entry.insert(pci_dev),
+        Entry::Occupied(entry) => {
+            let existing = entry.into_mut();
+            if !ib_dev.is_vf() && existing.ib_devices.iter().all(IbDevice::is_vf) {
+                pci_dev.ib_devices = mem::take(&mut existing.ib_devices);
+                *existing = pci_dev;
+            }
+            existing
         }
-    }
+    };
 
-    Ok(pci_devs.into_values().collect())
+    pci_dev.fw_ver = ib_dev.fw_ver.clone();
+    pci_dev.board_id = ib_dev.board_id.clone();
+
+    pci_dev.ib_devices.push(ib_dev);
+    pci_dev
+        .ib_devices
+        .sort_by(|a, b| (&a.slot_name, &a.name).cmp(&(&b.slot_name, &b.name)));
 }
 
-fn list_ib_ports() -> io::Result<HashMap<String, Vec<IbPort>>> {
-    let mut ib_ports = HashMap::<String, Vec<IbPort>>::new();
+/// What libibverbs reports of a device, merged into the [`IbDevice`] from udev.
+struct VerbsDevice {
+    caps: IbDeviceCaps,
+    ext_caps: Option<IbDeviceExtendedCaps>,
+    ib_ports: Vec<IbPort>,
+}
+
+fn list_verbs_devices(
+    warnings: &mut Vec<DiscoveryWarning>,
+) -> Result<HashMap<String, VerbsDevice>> {
+    let mut verbs_devs = HashMap::<String, VerbsDevice>::new();
 
     unsafe {
         let mut num_devices: c_int = 0;
         let device_list = ibv_get_device_list(&mut num_devices);
         if device_list.is_null() {
-            return Err(io::Error::last_os_error());
+            return Err(HcaError::last_verbs_error("-", "ibv_get_device_list"));
         }
         defer! {
             ibv_free_device_list(device_list);
         }
 
         let device_list: NonNull<DevicePtr> = NonNull::new_unchecked(device_list.cast());
-        let len: usize = num_devices.numeric_cast();
+        let len = usize::try_from(num_devices).unwrap_or(0);
 
         let devices = slice::from_raw_parts(device_list.as_ptr(), len);
 
         for devptr in devices {
-            let ctx = ibv_open_device(devptr.ffi_ptr());
-            if ctx.is_null() {
-                return Err(io::Error::last_os_error());
+            let name = cstr_to_string((*devptr.ffi_ptr()).name.as_ptr());
+
+            match query_verbs_device(devptr, &name, warnings) {
+                Ok(verbs_dev) => {
+                    verbs_devs.insert(name, verbs_dev);
+                }
+                Err(e) => warnings.push(DiscoveryWarning::new(&name, e)),
             }
-            defer! {
-                ibv_close_device(ctx);
-            };
+        }
+    };
 
-            let dev_attr_ptr =
-                alloc::alloc(Layout::new::<ibv_device_attr>()) as *mut ibv_device_attr;
-            defer! {
-                alloc::dealloc(dev_attr_ptr as *mut u8, Layout::new::<ibv_device_attr>());
-            };
+    Ok(verbs_devs)
+}
 
-            if ibv_query_device(ctx, dev_attr_ptr) != 0 {
-                return Err(io::Error::last_os_error());
-            };
+unsafe fn query_verbs_device(
+    devptr: &DevicePtr,
+    name: &str,
+    warnings: &mut Vec<DiscoveryWarning>,
+) -> Result<VerbsDevice> {
+    let ctx = ibv_open_device(devptr.ffi_ptr());
+    if ctx.is_null() {
+        return Err(HcaError::last_verbs_error(name, "ibv_open_device"));
+    }
+    defer! {
+        ibv_close_device(ctx);
+    };
 
-            let mut ports = vec![];
-
-            for i in 1..=(*dev_attr_ptr).phys_port_cnt {
-                let port_attr_ptr =
-                    alloc::alloc(Layout::new::<ibv_port_attr>()) as *mut ibv_port_attr;
-                defer! {
-                    alloc::dealloc(port_attr_ptr as *mut u8, Layout::new::<ibv_port_attr>());
-                };
-
-                if ibv_query_port(ctx, i, port_attr_ptr as *mut _) != 0 {
-                    return Err(io::Error::last_os_error());
-                };
-
-                let guid_ptr = alloc::alloc(Layout::new::<ibv_gid>()) as *mut ibv_gid;
-                defer! {
-                    alloc::dealloc(guid_ptr as *mut u8, Layout::new::<ibv_gid>());
-                };
-
-                if ibv_query_gid(ctx, i, 0, guid_ptr) != 0 {
-                    return Err(io::Error::last_os_error());
-                };
-
-                let link_type = IbPortLinkType::try_from((*port_attr_ptr).link_layer)?;
-
-                let guid = match link_type {
-                    IbPortLinkType::Ethernet => None,
-                    IbPortLinkType::Infiniband => Some(format!(
-                        "{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}",
-                        (*guid_ptr).raw[8],
-                        (*guid_ptr).raw[9],
-                        (*guid_ptr).raw[10],
-                        (*guid_ptr).raw[11],
-                        (*guid_ptr).raw[12],
-                        (*guid_ptr).raw[13],
-                        (*guid_ptr).raw[14],
-                        (*guid_ptr).raw[15]
-                    )),
-                };
-
-                ports.push(IbPort {
-                    port_num: i,
-                    lid: (*port_attr_ptr).lid,
-                    link_type,
-                    guid,
-                    state: IbPortState::try_from((*port_attr_ptr).state)?,
-                    phys_state: IbPortPhysState::try_from((*port_attr_ptr).phys_state)?,
-                });
+    let dev_attr_ptr = alloc::alloc(Layout::new::<ibv_device_attr>()) as *mut ibv_device_attr;
+    defer! {
+        alloc::dealloc(dev_attr_ptr as *mut u8, Layout::new::<ibv_device_attr>());
+    };
+
+    let rc = ibv_query_device(ctx, dev_attr_ptr);
+    if rc != 0 {
+        return Err(HcaError::verbs_error(name, "ibv_query_device", rc));
+    };
+
+    let mut ports = vec![];
+
+    for i in 1..=(*dev_attr_ptr).phys_port_cnt {
+        match query_ib_port(ctx, name, i, warnings) {
+            Ok(port) => ports.push(port),
+            Err(e) => warnings.push(DiscoveryWarning::new(
+                name,
+                HcaError::port_error(name, i, e),
+            )),
+        }
+    }
+
+    let max_entries = ports.iter().map(|p| p.gid_tbl_len as usize).sum();
+    match query_gid_table(ctx, name, max_entries) {
+        Ok(entries) => {
+            for (port_num, entry) in entries {
+                if let Some(port) = ports.iter_mut().find(|p| p.port_num == port_num) {
+                    port.gids.push(entry);
+                }
             }
+        }
+        Err(e) => warnings.push(DiscoveryWarning::new(name, e)),
+    }
 
-            ib_ports.insert(cstr_to_string((*devptr.ffi_ptr()).name.as_ptr()), ports);
+    // The extended capabilities are optional, a provider may not implement them.
+    let ext_caps = match query_device_ex(ctx, name) {
+        Ok(ext_caps) => ext_caps,
+        Err(e) => {
+            warnings.push(DiscoveryWarning::new(name, e));
+            None
         }
     };
 
-    Ok(ib_ports)
+    let ext_caps = ext_caps.map(|ext_caps| IbDeviceExtendedCaps {
+        dc_odp_caps: query_dc_odp_caps(ctx),
+        ..ext_caps
+    });
+
+    Ok(VerbsDevice {
+        caps: IbDeviceCaps::from(&*dev_attr_ptr),
+        ext_caps,
+        ib_ports: ports,
+    })
+}
+
+/// The ODP capabilities of the DC transport of a device; `None` if the device is not driven by
+/// mlx5, or its provider does not report them.
+#[cfg(hca_mlx5dv)]
+unsafe fn query_dc_odp_caps(ctx: *mut ibv_context) -> Option<OdpCapabilities> {
+    let mut caps = 0;
+    match hca_mlx5_query_dc_odp_caps(ctx, &mut caps) {
+        0 => Some(OdpCapabilities::from_bits_retain(caps)),
+        _ => None,
+    }
+}
+
+/// The ODP capabilities of the DC transport of a device; libhca is built without the mlx5
+/// direct verbs.
+#[cfg(not(hca_mlx5dv))]
+unsafe fn query_dc_odp_caps(_ctx: *mut ibv_context) -> Option<OdpCapabilities> {
+    None
+}
+
+/// Query the extended capabilities of a device; `None` if the provider does not implement them.
+unsafe fn query_device_ex(
+    ctx: *mut ibv_context,
+    name: &str,
+) -> Result<Option<IbDeviceExtendedCaps>> {
+    let mut attr = ibv_device_attr_ex::default();
+
+    match hca_query_device_ex(ctx, &mut attr) {
+        0 => Ok(Some(IbDeviceExtendedCaps::from(&attr))),
+        libc::EOPNOTSUPP | libc::ENOSYS => Ok(None),
+        rc => Err(HcaError::verbs_error(name, "ibv_query_device_ex", rc)),
+    }
+}
+
+/// Query the valid entries of the GID tables of all ports of a device, with their port number.
+unsafe fn query_gid_table(
+    ctx: *mut ibv_context,
+    name: &str,
+    max_entries: usize,
+) -> Result<Vec<(u8, GidEntry)>> {
+    let mut entries = vec![ibv_gid_entry::default(); max_entries];
+
+    let rc = _ibv_query_gid_table(
+        ctx,
+        entries.as_mut_ptr(),
+        max_entries,
+        0,
+        mem::size_of::<ibv_gid_entry>(),
+    );
+    if rc < 0 {
+        return Err(HcaError::verbs_error(
+            name,
+            "ibv_query_gid_table",
+            -rc as i32,
+        ));
+    }
+    entries.truncate(rc as usize);
+
+    let mut gids: Vec<(u8, GidEntry)> = entries
+        .iter()
+        .map(|e| {
+            let netdev = match e.ndev_ifindex {
+                0 => None,
+                ifindex => ifindex_to_name(ifindex),
+            };
+
+            (
+                e.port_num as u8,
+                GidEntry {
+                    index: e.gid_index,
+                    gid: Gid(e.gid.raw),
+                    gid_type: GidType::from(e.gid_type),
+                    netdev,
+                },
+            )
+        })
+        .collect();
+    gids.sort_by_key(|(port_num, e)| (*port_num, e.index));
+
+    Ok(gids)
+}
+
+/// Query a port; a port whose GID 0 or P_Key table can not be queried, e.g. of some VFs, is
+/// kept without them, and the failure is reported in `warnings`.
+unsafe fn query_ib_port(
+    ctx: *mut ibv_context,
+    name: &str,
+    port_num: u8,
+    warnings: &mut Vec<DiscoveryWarning>,
+) -> Result<IbPort> {
+    let port_attr_ptr = alloc::alloc(Layout::new::<ibv_port_attr>()) as *mut ibv_port_attr;
+    defer! {
+        alloc::dealloc(port_attr_ptr as *mut u8, Layout::new::<ibv_port_attr>());
+    };
+
+    let rc = ibv_query_port(ctx, port_num, port_attr_ptr as *mut _);
+    if rc != 0 {
+        return Err(HcaError::verbs_error(name, "ibv_query_port", rc));
+    };
+
+    let guid_ptr = alloc::alloc(Layout::new::<ibv_gid>()) as *mut ibv_gid;
+    defer! {
+        alloc::dealloc(guid_ptr as *mut u8, Layout::new::<ibv_gid>());
+    };
+
+    // ibv_query_gid reports the failure in errno.
+    let has_gid = ibv_query_gid(ctx, port_num, 0, guid_ptr) == 0;
+    if !has_gid {
+        warnings.push(DiscoveryWarning::new(
+            name,
+            HcaError::port_error(
+                name,
+                port_num,
+                HcaError::last_verbs_error(name, "ibv_query_gid"),
+            ),
+        ));
+    }
+
+    let link_type = IbPortLinkType::try_from((*port_attr_ptr).link_layer)?;
+    let width = IbPortWidth::from((*port_attr_ptr).active_width);
+    let speed = port_speed(&*port_attr_ptr);
+
+    let guid = match link_type {
+        IbPortLinkType::Ethernet => None,
+        IbPortLinkType::Infiniband if !has_gid => None,
+        IbPortLinkType::Infiniband => Some(format!(
+            "{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}",
+            (*guid_ptr).raw[8],
+            (*guid_ptr).raw[9],
+            (*guid_ptr).raw[10],
+            (*guid_ptr).raw[11],
+            (*guid_ptr).raw[12],
+            (*guid_ptr).raw[13],
+            (*guid_ptr).raw[14],
+            (*guid_ptr).raw[15]
+        )),
+    };
+
+    Ok(IbPort {
+        port_num,
+        lid: (*port_attr_ptr).lid,
+        link_type,
+        guid,
+        state: IbPortState::from((*port_attr_ptr).state),
+        phys_state: IbPortPhysState::from((*port_attr_ptr).phys_state),
+        width,
+        speed,
+        supported_speeds: match link_type {
+            IbPortLinkType::Infiniband => {
+                umad::query_supported_speeds(&Sysfs::default(), name, port_num).unwrap_or_default()
+            }
+            IbPortLinkType::Ethernet => vec![],
+        },
+        rate: IbPort::data_rate(width, speed),
+        max_mtu: IbMtu::from((*port_attr_ptr).max_mtu),
+        active_mtu: IbMtu::from((*port_attr_ptr).active_mtu),
+        sm_lid: (*port_attr_ptr).sm_lid,
+        sm_sl: (*port_attr_ptr).sm_sl,
+        lmc: (*port_attr_ptr).lmc,
+        subnet_timeout: Some((*port_attr_ptr).subnet_timeout),
+        max_vl_num: Some((*port_attr_ptr).max_vl_num),
+        gid_tbl_len: u32::try_from((*port_attr_ptr).gid_tbl_len).unwrap_or(0),
+        pkey_tbl_len: (*port_attr_ptr).pkey_tbl_len,
+        capabilities: PortCapabilities::from_raw(
+            (*port_attr_ptr).port_cap_flags,
+            (*port_attr_ptr).port_cap_flags2,
+        ),
+        gids: vec![],
+        pkeys: match query_pkey_table(ctx, name, port_num, (*port_attr_ptr).pkey_tbl_len) {
+            Ok(pkeys) => pkeys,
+            Err(e) => {
+                warnings.push(DiscoveryWarning::new(
+                    name,
+                    HcaError::port_error(name, port_num, e),
+                ));
+                vec![]
+            }
+        },
+        netdevs: vec![],
+    })
+}
+
+/// The active speed of a port; `active_speed` is a bitmask of 8 bits, so the speeds from XDR
+/// on are in `active_speed_ex` if the provider sets it.
+#[cfg(hca_active_speed_ex)]
+fn port_speed(attr: &ibv_port_attr) -> IbPortSpeed {
+    match attr.active_speed_ex {
+        0 => IbPortSpeed::from(attr.active_speed as u32),
+        speed => IbPortSpeed::from(speed),
+    }
+}
+
+/// The active speed of a port; the verbs headers have no `active_speed_ex`.
+#[cfg(not(hca_active_speed_ex))]
+fn port_speed(attr: &ibv_port_attr) -> IbPortSpeed {
+    IbPortSpeed::from(attr.active_speed as u32)
+}
+
+/// Query the valid entries of the P_Key table of a port.
+unsafe fn query_pkey_table(
+    ctx: *mut ibv_context,
+    name: &str,
+    port_num: u8,
+    pkey_tbl_len: u16,
+) -> Result<Vec<PKeyEntry>> {
+    let mut pkeys = vec![];
+
+    for index in 0..pkey_tbl_len {
+        let mut pkey: __be16 = 0;
+        // ibv_query_pkey returns -1 and reports the failure in errno.
+        if ibv_query_pkey(ctx, port_num, index as c_int, &mut pkey) != 0 {
+            return Err(HcaError::last_verbs_error(name, "ibv_query_pkey"));
+        }
+
+        let pkey = PKey(u16::from_be(pkey));
+        if pkey.is_valid() {
+            pkeys.push(PKeyEntry::new(index, pkey));
+        }
+    }
+
+    Ok(pkeys)
 }
@@ -0,0 +1,200 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! The network interfaces of the RDMA ports, e.g. `ens1f0` of a RoCE port or `ib0` of IPoIB.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fmt::{self, Display};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::ptr;
+
+use scopeguard::defer;
+use serde::{Serialize, Serializer};
+
+use super::types::IbPort;
+use super::utils::{read_sysfs_attr, read_sysfs_dir};
+
+/// A network interface of a port.
+#[derive(Clone, Debug, Serialize)]
+pub struct NetDev {
+    pub name: String,
+    /// The hardware address, e.g. `0c:42:a1:5b:2f:10`; 20 bytes for IPoIB.
+    pub mac: String,
+    pub mtu: Option<u32>,
+    /// The RFC 2863 state of the interface, e.g. `up`, `down` or `lowerlayerdown`.
+    pub operstate: String,
+    /// The IPv4 and IPv6 addresses of the interface, in the network namespace of the process.
+    pub addresses: Vec<IfAddr>,
+}
+
+impl NetDev {
+    pub fn is_up(&self) -> bool {
+        self.operstate == "up"
+    }
+
+    pub fn ipv4_addresses(&self) -> impl Iterator<Item = &IfAddr> {
+        self.addresses.iter().filter(|a| a.addr.is_ipv4())
+    }
+
+    pub fn ipv6_addresses(&self) -> impl Iterator<Item = &IfAddr> {
+        self.addresses.iter().filter(|a| a.addr.is_ipv6())
+    }
+}
+
+/// An address of a network interface with its prefix length, e.g. `10.0.0.5/24`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IfAddr {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl Display for IfAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl Serialize for IfAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// The addresses of the network interfaces by name, see `getifaddrs(3)`.
+pub(crate) type InterfaceAddresses = HashMap<String, Vec<IfAddr>>;
+
+/// Read the addresses of all the network interfaces once, for [`read_port_netdevs`]; empty if
+/// they can not be listed.
+pub(crate) fn read_interface_addresses() -> InterfaceAddresses {
+    let mut addrs = InterfaceAddresses::new();
+
+    unsafe {
+        let mut ifap: *mut libc::ifaddrs = ptr::null_mut();
+        if libc::getifaddrs(&mut ifap) != 0 {
+            return addrs;
+        }
+        defer! {
+            libc::freeifaddrs(ifap);
+        }
+
+        let mut ifa = ifap;
+        while !ifa.is_null() {
+            let entry = &*ifa;
+            ifa = entry.ifa_next;
+
+            if entry.ifa_addr.is_null() || entry.ifa_name.is_null() {
+                continue;
+            }
+            let addr = match sockaddr_to_ip(entry.ifa_addr) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            let prefix_len = match sockaddr_to_ip(entry.ifa_netmask) {
+                Some(IpAddr::V4(mask)) => u32::from(mask).count_ones() as u8,
+                Some(IpAddr::V6(mask)) => u128::from(mask).count_ones() as u8,
+                None => 0,
+            };
+
+            let name = CStr::from_ptr(entry.ifa_name).to_string_lossy().to_string();
+            addrs
+                .entry(name)
+                .or_default()
+                .push(IfAddr { addr, prefix_len });
+        }
+    }
+
+    addrs
+}
+
+/// The IP address of a `sockaddr`; `None` if it is null or not an IPv4 or IPv6 address.
+unsafe fn sockaddr_to_ip(sa: *const libc::sockaddr) -> Option<IpAddr> {
+    if sa.is_null() {
+        return None;
+    }
+
+    match (*sa).sa_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = &*(sa as *const libc::sockaddr_in);
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                sin.sin_addr.s_addr,
+            ))))
+        }
+        libc::AF_INET6 => {
+            let sin6 = &*(sa as *const libc::sockaddr_in6);
+            Some(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)))
+        }
+        _ => None,
+    }
+}
+
+/// Resolve the network interfaces of a port of the RDMA device at `ib_path`, e.g.
+/// `/sys/class/infiniband/mlx5_2`: the interfaces of its PCI device whose `dev_port` is the
+/// port, e.g. `ens1f0` or `ib0`, followed by the other interfaces of its GIDs, e.g. the VLANs
+/// and bonds of RoCE. Their attributes are read from `class_net`, e.g. `/sys/class/net`.
+pub(crate) fn read_port_netdevs(
+    ib_path: &Path,
+    class_net: &Path,
+    port: &IbPort,
+    addrs: &InterfaceAddresses,
+) -> Vec<NetDev> {
+    let net_path = ib_path.join("device").join("net");
+    let mut names: Vec<String> = read_sysfs_dir(&net_path)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|name| read_dev_port(&net_path.join(name)) == Some(port.port_num))
+        .collect();
+
+    for gid in &port.gids {
+        if let Some(netdev) = &gid.netdev {
+            if !names.contains(netdev) {
+                names.push(netdev.clone());
+            }
+        }
+    }
+
+    names
+        .into_iter()
+        .map(|name| read_netdev(&class_net.join(&name), name, addrs))
+        .collect()
+}
+
+/// The RDMA port of a network interface, i.e. `dev_port` plus one; the older kernels only
+/// report `dev_id` of mlx4.
+fn read_dev_port(path: &Path) -> Option<u8> {
+    let dev_port = match read_sysfs_attr(path.join("dev_port")) {
+        Ok(v) => v.parse::<u8>().ok()?,
+        Err(_) => {
+            let dev_id = read_sysfs_attr(path.join("dev_id")).ok()?;
+            u8::from_str_radix(dev_id.trim_start_matches("0x"), 16).ok()?
+        }
+    };
+
+    dev_port.checked_add(1)
+}
+
+fn read_netdev(path: &Path, name: String, addrs: &InterfaceAddresses) -> NetDev {
+    NetDev {
+        mac: read_sysfs_attr(path.join("address")).unwrap_or_default(),
+        mtu: read_sysfs_attr(path.join("mtu"))
+            .ok()
+            .and_then(|v| v.parse().ok()),
+        operstate: read_sysfs_attr(path.join("operstate")).unwrap_or_default(),
+        addresses: addrs.get(&name).cloned().unwrap_or_default(),
+        name,
+    }
+}
@@ -0,0 +1,129 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use std::io;
+use std::mem;
+use std::path::Path;
+
+use super::utils::read_sysfs_attr;
+
+/// Read the NUMA node of a PCI device; `None` if the host has no NUMA, i.e. the node is `-1`.
+pub fn read_numa_node(path: &Path) -> Option<u32> {
+    read_sysfs_attr(path.join("numa_node")).ok()?.parse().ok()
+}
+
+/// Read the CPUs local to a PCI device, e.g. `0-13,28-41`; empty if they are unknown.
+pub fn read_local_cpulist(path: &Path) -> String {
+    read_sysfs_attr(path.join("local_cpulist")).unwrap_or_default()
+}
+
+/// Parse a CPU list, e.g. `0-13,28-41`; the invalid ranges are skipped.
+pub fn parse_cpulist(v: &str) -> Vec<u32> {
+    let mut cpus = vec![];
+
+    for range in v.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        if let (Ok(first), Ok(last)) = (first.parse::<u32>(), last.parse::<u32>()) {
+            cpus.extend(first..=last);
+        }
+    }
+
+    cpus
+}
+
+/// Restrict the CPU affinity of the calling thread to the given CPUs.
+pub fn set_thread_affinity(cpus: &[u32]) -> io::Result<()> {
+    // A mask as large as the CPUs, as `cpu_set_t` only has the first 1024 CPUs.
+    let mut mask = cpu_mask(cpus);
+
+    // The pid 0 is the calling thread.
+    let rc = unsafe {
+        libc::sched_setaffinity(
+            0,
+            mem::size_of_val(mask.as_slice()),
+            mask.as_mut_ptr().cast::<libc::cpu_set_t>(),
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// The CPU mask of the kernel, i.e. one bit per CPU in an array of `unsigned long`.
+fn cpu_mask(cpus: &[u32]) -> Vec<libc::c_ulong> {
+    const BITS: usize = libc::c_ulong::BITS as usize;
+
+    let len = cpus.iter().max().map_or(1, |cpu| *cpu as usize / BITS + 1);
+    let mut mask = vec![0; len];
+    for cpu in cpus {
+        let cpu = *cpu as usize;
+        mask[cpu / BITS] |= 1 << (cpu % BITS);
+    }
+
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ranges() {
+        assert_eq!(parse_cpulist("0-3,8,10-11"), [0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(parse_cpulist("5"), [5]);
+        assert_eq!(parse_cpulist("0-1, 4-5"), [0, 1, 4, 5]);
+    }
+
+    #[test]
+    fn parse_trailing_newline() {
+        assert_eq!(parse_cpulist("0-13,28-41\n").len(), 28);
+        assert_eq!(parse_cpulist("7\n"), [7]);
+    }
+
+    #[test]
+    fn parse_empty() {
+        assert!(parse_cpulist("").is_empty());
+        assert!(parse_cpulist("\n").is_empty());
+        assert!(parse_cpulist(",,").is_empty());
+    }
+
+    #[test]
+    fn parse_malformed() {
+        assert_eq!(parse_cpulist("0-3,x,8"), [0, 1, 2, 3, 8]);
+        assert!(parse_cpulist("a-b").is_empty());
+        assert!(parse_cpulist("1-").is_empty());
+        assert!(parse_cpulist("-1").is_empty());
+        assert!(parse_cpulist("1-2-3").is_empty());
+        // A reversed range is empty.
+        assert!(parse_cpulist("5-3").is_empty());
+    }
+
+    #[test]
+    fn cpu_masks() {
+        let bits = libc::c_ulong::BITS;
+        assert_eq!(cpu_mask(&[]), [0]);
+        assert_eq!(cpu_mask(&[0, 2]), [0b101]);
+        assert_eq!(cpu_mask(&[bits]), [0, 1]);
+
+        // Beyond the 1024 CPUs of `cpu_set_t`.
+        let mask = cpu_mask(&[1, 4095]);
+        assert_eq!(mask.len(), 4096 / bits as usize);
+        assert_eq!(mask[0], 0b10);
+        assert_eq!(mask[mask.len() - 1], 1 << (bits - 1));
+    }
+}
@@ -0,0 +1,286 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! The PCIe link of an adapter.
+
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::Path;
+
+use serde::Serialize;
+
+use super::utils::read_sysfs_attr;
+
+// The offsets in the PCI configuration space, see the PCI Express Base Specification, 7.5.
+const PCI_STATUS: usize = 0x06;
+const PCI_STATUS_CAP_LIST: u16 = 0x10;
+const PCI_CAPABILITY_LIST: usize = 0x34;
+const PCI_CAP_ID_EXP: u8 = 0x10;
+const PCI_EXP_DEVCAP: usize = 0x04;
+const PCI_EXP_DEVCTL: usize = 0x08;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PcieLink {
+    /// The speed the link trained at, in GT/s, e.g. `8.0` for Gen3.
+    pub current_speed: f64,
+    pub current_width: u8,
+    /// The highest speed the adapter supports, in GT/s.
+    pub max_speed: f64,
+    pub max_width: u8,
+    /// The max payload size in bytes; `None` if the configuration space is not readable, e.g. without root.
+    pub max_payload: Option<u16>,
+    pub max_payload_supported: Option<u16>,
+    /// The max read request size in bytes.
+    pub max_read_req: Option<u16>,
+}
+
+impl PcieLink {
+    /// Read the link of a PCI device, e.g. `/sys/bus/pci/devices/0000:b1:00.0`; `None` if the
+    /// device is not a PCIe device or the link is unknown, e.g. of a VF.
+    pub fn read<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let path = path.as_ref();
+
+        let mut link = Self {
+            current_speed: parse_speed(&read_sysfs_attr(path.join("current_link_speed")).ok()?)?,
+            current_width: read_sysfs_attr(path.join("current_link_width"))
+                .ok()?
+                .parse()
+                .ok()?,
+            max_speed: parse_speed(&read_sysfs_attr(path.join("max_link_speed")).ok()?)?,
+            max_width: read_sysfs_attr(path.join("max_link_width"))
+                .ok()?
+                .parse()
+                .ok()?,
+            max_payload: None,
+            max_payload_supported: None,
+            max_read_req: None,
+        };
+
+        // Only the header of the configuration space is readable without root.
+        if let Ok(config) = fs::read(path.join("config")) {
+            let regs = find_pcie_cap(&config).and_then(|cap| {
+                Some((
+                    read_u16(&config, cap + PCI_EXP_DEVCAP)?,
+                    read_u16(&config, cap + PCI_EXP_DEVCTL)?,
+                ))
+            });
+            if let Some((devcap, devctl)) = regs {
+                link.max_payload_supported = Some(128 << (devcap & 0x7));
+                link.max_payload = Some(128 << ((devctl >> 5) & 0x7));
+                link.max_read_req = Some(128 << ((devctl >> 12) & 0x7));
+            }
+        }
+
+        Some(link)
+    }
+
+    /// Whether the link trained below the speed or width the adapter supports, e.g. a Gen4 x16
+    /// adapter at Gen3 x8; usually because of the slot, a riser or the BIOS settings.
+    pub fn is_degraded(&self) -> bool {
+        self.current_speed < self.max_speed || self.current_width < self.max_width
+    }
+
+    pub fn current_gen(&self) -> Option<u8> {
+        generation(self.current_speed)
+    }
+
+    pub fn max_gen(&self) -> Option<u8> {
+        generation(self.max_speed)
+    }
+
+    /// The max link, e.g. `Gen4 x16`.
+    pub fn max_link(&self) -> String {
+        format_link(self.max_speed, self.max_width)
+    }
+}
+
+impl Display for PcieLink {
+    /// The current link, e.g. `Gen3 x8`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&format_link(self.current_speed, self.current_width))
+    }
+}
+
+/// The PCIe generation of a link speed in GT/s, e.g. 3 of `8.0`.
+fn generation(speed: f64) -> Option<u8> {
+    match (speed * 10.0).round() as u32 {
+        25 => Some(1),
+        50 => Some(2),
+        80 => Some(3),
+        160 => Some(4),
+        320 => Some(5),
+        640 => Some(6),
+        _ => None,
+    }
+}
+
+fn format_link(speed: f64, width: u8) -> String {
+    match generation(speed) {
+        Some(gen) => format!("Gen{} x{}", gen, width),
+        None => format!("{} GT/s x{}", speed, width),
+    }
+}
+
+/// Parse the link speed attributes, e.g. `8.0 GT/s PCIe`, or `8 GT/s` of older kernels.
+fn parse_speed(v: &str) -> Option<f64> {
+    v.split_whitespace().next()?.parse().ok()
+}
+
+/// Find the offset of the PCI Express capability in the configuration space.
+fn find_pcie_cap(config: &[u8]) -> Option<usize> {
+    if read_u16(config, PCI_STATUS)? & PCI_STATUS_CAP_LIST == 0 {
+        return None;
+    }
+
+    let mut pos = (*config.get(PCI_CAPABILITY_LIST)? & !0x3) as usize;
+    // The list has at most 48 capabilities in the 192 bytes after the header.
+    for _ in 0..48 {
+        if pos < 0x40 {
+            return None;
+        }
+        if *config.get(pos)? == PCI_CAP_ID_EXP {
+            return Some(pos);
+        }
+        pos = (*config.get(pos + 1)? & !0x3) as usize;
+    }
+
+    None
+}
+
+fn read_u16(config: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_le_bytes([
+        *config.get(pos)?,
+        *config.get(pos + 1)?,
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    /// A configuration space with the capabilities at the given offsets, as `(offset, id, next)`.
+    fn config_space(caps: &[(usize, u8, u8)]) -> Vec<u8> {
+        let mut config = vec![0u8; 256];
+        config[PCI_STATUS..PCI_STATUS + 2].copy_from_slice(&PCI_STATUS_CAP_LIST.to_le_bytes());
+        config[PCI_CAPABILITY_LIST] = caps.first().map_or(0, |c| c.0 as u8);
+        for (pos, id, next) in caps {
+            config[*pos] = *id;
+            config[*pos + 1] = *next;
+        }
+        config
+    }
+
+    /// A PCIe adapter with the power management capability before the PCI Express one.
+    fn adapter_config(devcap: u16, devctl: u16) -> Vec<u8> {
+        let mut config = config_space(&[(0x40, 0x01, 0x60), (0x60, PCI_CAP_ID_EXP, 0)]);
+        config[0x60 + PCI_EXP_DEVCAP..0x62 + PCI_EXP_DEVCAP].copy_from_slice(&devcap.to_le_bytes());
+        config[0x60 + PCI_EXP_DEVCTL..0x62 + PCI_EXP_DEVCTL].copy_from_slice(&devctl.to_le_bytes());
+        config
+    }
+
+    fn fabricate_link(dir: &TempDir, current: (&str, &str), max: (&str, &str)) {
+        dir.write("current_link_speed", current.0);
+        dir.write("current_link_width", current.1);
+        dir.write("max_link_speed", max.0);
+        dir.write("max_link_width", max.1);
+    }
+
+    #[test]
+    fn walk_capabilities() {
+        assert_eq!(find_pcie_cap(&adapter_config(0, 0)), Some(0x60));
+
+        // The low bits of the pointers are reserved.
+        let config = config_space(&[(0x40, 0x01, 0x63), (0x60, PCI_CAP_ID_EXP, 0)]);
+        assert_eq!(find_pcie_cap(&config), Some(0x60));
+
+        let config = config_space(&[(0x40, 0x01, 0)]);
+        assert_eq!(find_pcie_cap(&config), None);
+
+        let mut config = adapter_config(0, 0);
+        config[PCI_STATUS] = 0;
+        assert_eq!(find_pcie_cap(&config), None);
+    }
+
+    #[test]
+    fn walk_broken_capabilities() {
+        // A loop ends after the max number of capabilities.
+        let config = config_space(&[(0x40, 0x01, 0x50), (0x50, 0x05, 0x40)]);
+        assert_eq!(find_pcie_cap(&config), None);
+        let config = config_space(&[(0x40, 0x01, 0x40)]);
+        assert_eq!(find_pcie_cap(&config), None);
+
+        // A pointer into the header.
+        let config = config_space(&[(0x40, 0x01, 0x20)]);
+        assert_eq!(find_pcie_cap(&config), None);
+
+        // A pointer beyond the header, which is all that is readable without root.
+        let config = adapter_config(0, 0);
+        assert_eq!(find_pcie_cap(&config[..64]), None);
+        assert_eq!(find_pcie_cap(&config[..4]), None);
+    }
+
+    #[test]
+    fn decode_payload_sizes() {
+        let dir = TempDir::new();
+        fabricate_link(&dir, ("8.0 GT/s PCIe", "16"), ("8.0 GT/s PCIe", "16"));
+        // MPS supported 512, MPS 256 and MRRS 4096.
+        dir.write("config", adapter_config(0x2, 1 << 5 | 5 << 12));
+
+        let link = PcieLink::read(dir.path()).unwrap();
+        assert_eq!(link.max_payload_supported, Some(512));
+        assert_eq!(link.max_payload, Some(256));
+        assert_eq!(link.max_read_req, Some(4096));
+
+        // Without root, only the header of the configuration space is readable.
+        dir.write("config", &adapter_config(0x2, 0)[..64]);
+        let link = PcieLink::read(dir.path()).unwrap();
+        assert_eq!(link.max_payload, None);
+        assert_eq!(link.max_read_req, None);
+    }
+
+    #[test]
+    fn degraded_link() {
+        let dir = TempDir::new();
+        fabricate_link(&dir, ("8.0 GT/s PCIe", "8"), ("16.0 GT/s PCIe", "16"));
+
+        let link = PcieLink::read(dir.path()).unwrap();
+        assert!(link.is_degraded());
+        assert_eq!(link.to_string(), "Gen3 x8");
+        assert_eq!(link.max_link(), "Gen4 x16");
+        assert_eq!((link.current_gen(), link.max_gen()), (Some(3), Some(4)));
+
+        // The width alone.
+        fabricate_link(&dir, ("16.0 GT/s PCIe", "8"), ("16.0 GT/s PCIe", "16"));
+        assert!(PcieLink::read(dir.path()).unwrap().is_degraded());
+
+        // The speeds of older kernels have no decimals.
+        fabricate_link(&dir, ("16 GT/s", "16"), ("16.0 GT/s PCIe", "16"));
+        let link = PcieLink::read(dir.path()).unwrap();
+        assert!(!link.is_degraded());
+        assert_eq!(link.to_string(), "Gen4 x16");
+    }
+
+    #[test]
+    fn unknown_link() {
+        let dir = TempDir::new();
+        // The link of a VF.
+        fabricate_link(&dir, ("Unknown", "0"), ("Unknown", "0"));
+        assert!(PcieLink::read(dir.path()).is_none());
+
+        assert_eq!(format_link(2.0, 1), "2 GT/s x1");
+    }
+}
@@ -0,0 +1,469 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! The SR-IOV physical and virtual functions (PF and VF) of an adapter.
+
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use super::error::{HcaError, Result};
+use super::sysfs::Sysfs;
+use super::utils::{read_sysfs_attr, read_sysfs_dir, read_sysfs_link_name};
+
+/// The SR-IOV of a physical function.
+#[derive(Clone, Debug, Serialize)]
+pub struct Sriov {
+    pub total_vfs: u32,
+    pub num_vfs: u32,
+    /// The slots of the enabled VFs, by VF index.
+    pub vfs: Vec<String>,
+}
+
+impl Sriov {
+    /// Read the SR-IOV of a PCI function, e.g. `/sys/bus/pci/devices/0000:b1:00.0`; `None` if it is
+    /// not a PF which supports SR-IOV.
+    pub fn read(path: &Path) -> Option<Self> {
+        let total_vfs = read_sysfs_attr(path.join("sriov_totalvfs"))
+            .ok()?
+            .parse()
+            .ok()?;
+        let num_vfs = read_sysfs_attr(path.join("sriov_numvfs"))
+            .ok()?
+            .parse()
+            .ok()?;
+
+        Some(Self {
+            total_vfs,
+            num_vfs,
+            vfs: read_virtfns(path)
+                .into_iter()
+                .map(|(_, slot)| slot)
+                .collect(),
+        })
+    }
+}
+
+/// The PF of a VF, i.e. its slot and the VF index; `None` if the PCI function is not a VF.
+pub fn read_physfn(path: &Path, slot_name: &str) -> Option<(String, Option<u32>)> {
+    let physfn = path.join("physfn");
+    let pf_slot = read_sysfs_link_name(&physfn).ok()?;
+
+    let vf_index = read_virtfns(&physfn)
+        .into_iter()
+        .find(|(_, slot)| slot == slot_name)
+        .map(|(index, _)| index);
+
+    Some((pf_slot, vf_index))
+}
+
+/// The `virtfn<index>` links of a PF to its VFs, sorted by index.
+fn read_virtfns(path: &Path) -> Vec<(u32, String)> {
+    let mut virtfns: Vec<(u32, String)> = read_sysfs_dir(path)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|name| {
+            let index = name.strip_prefix("virtfn")?.parse().ok()?;
+            let slot = read_sysfs_link_name(path.join(name)).ok()?;
+            Some((index, slot))
+        })
+        .collect();
+    virtfns.sort();
+
+    virtfns
+}
+
+/// The link state policy of a VF, see `sriov/<vf>/policy` of mlx5.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VfLinkPolicy {
+    /// The link state of the VF follows the PF.
+    Follow,
+    Up,
+    Down,
+}
+
+impl Display for VfLinkPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Follow => f.write_str("Follow"),
+            Self::Up => f.write_str("Up"),
+            Self::Down => f.write_str("Down"),
+        }
+    }
+}
+
+/// The configuration of a VF; the unset fields are left as they are.
+#[derive(Clone, Debug, Default)]
+pub struct VfConfig {
+    pub index: u32,
+    /// The node GUID, e.g. `11:22:33:44:55:66:77:88`.
+    pub node_guid: Option<String>,
+    pub port_guid: Option<String>,
+    pub policy: Option<VfLinkPolicy>,
+}
+
+/// The SR-IOV configuration of a PF; the unset fields are left as they are.
+#[derive(Clone, Debug, Default)]
+pub struct SriovConfig {
+    pub num_vfs: Option<u32>,
+    pub vfs: Vec<VfConfig>,
+}
+
+/// A write of a sysfs attribute.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SysfsWrite {
+    pub path: PathBuf,
+    pub value: String,
+}
+
+impl SysfsWrite {
+    pub fn apply(&self) -> Result<()> {
+        fs::write(&self.path, &self.value).map_err(|error| HcaError::Sysfs {
+            path: self.path.clone(),
+            error,
+        })
+    }
+}
+
+impl Display for SysfsWrite {
+    /// The write as a shell command, e.g. `echo 4 > /sys/.../sriov_numvfs`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "echo {} > {}", self.value, self.path.display())
+    }
+}
+
+impl SriovConfig {
+    /// Plan the sysfs writes which apply the configuration to the PF of an RDMA device, e.g. `mlx5_0`;
+    /// nothing is written, so the plan can be printed as a dry run, or applied by [`SriovConfig::apply`].
+    pub fn plan(&self, sysfs: &Sysfs, device: &str) -> Result<Vec<SysfsWrite>> {
+        let pci_path = sysfs.class_infiniband().join(device).join("device");
+        let sriov = Sriov::read(&pci_path).ok_or_else(|| HcaError::NotSriovPf {
+            device: device.to_string(),
+        })?;
+
+        let mut writes = vec![];
+        let num_vfs = self.num_vfs.unwrap_or(sriov.num_vfs);
+
+        if let Some(n) = self.num_vfs.filter(|n| *n != sriov.num_vfs) {
+            if n > sriov.total_vfs {
+                return Err(HcaError::InvalidValue {
+                    device: device.to_string(),
+                    name: "sriov_numvfs".to_string(),
+                    value: format!("{} > sriov_totalvfs {}", n, sriov.total_vfs),
+                });
+            }
+
+            // The kernel does not change the number of enabled VFs directly, disable them first.
+            let path = pci_path.join("sriov_numvfs");
+            if sriov.num_vfs != 0 && n != 0 {
+                writes.push(SysfsWrite {
+                    path: path.clone(),
+                    value: "0".to_string(),
+                });
+            }
+            writes.push(SysfsWrite {
+                path,
+                value: n.to_string(),
+            });
+        }
+
+        for vf in &self.vfs {
+            if vf.index >= num_vfs {
+                return Err(HcaError::InvalidValue {
+                    device: device.to_string(),
+                    name: "vf".to_string(),
+                    value: format!("{} >= sriov_numvfs {}", vf.index, num_vfs),
+                });
+            }
+
+            let vf_path = pci_path.join("sriov").join(vf.index.to_string());
+            let attrs = [
+                (
+                    "node",
+                    vf.node_guid
+                        .as_deref()
+                        .map(|g| parse_guid(device, g))
+                        .transpose()?,
+                ),
+                (
+                    "port",
+                    vf.port_guid
+                        .as_deref()
+                        .map(|g| parse_guid(device, g))
+                        .transpose()?,
+                ),
+                ("policy", vf.policy.map(|p| p.to_string())),
+            ];
+            for (name, value) in attrs {
+                if let Some(value) = value {
+                    writes.push(SysfsWrite {
+                        path: vf_path.join(name),
+                        value,
+                    });
+                }
+            }
+        }
+
+        Ok(writes)
+    }
+
+    /// Apply the configuration, i.e. the writes of [`SriovConfig::plan`] in order.
+    pub fn apply(&self, sysfs: &Sysfs, device: &str) -> Result<Vec<SysfsWrite>> {
+        let writes = self.plan(sysfs, device)?;
+        for write in &writes {
+            write.apply()?;
+        }
+
+        Ok(writes)
+    }
+}
+
+/// Parse a GUID, e.g. `1122:3344:5566:7788` or `11:22:33:44:55:66:77:88`, into the format of the
+/// mlx5 `sriov` attributes, i.e. the latter.
+fn parse_guid(device: &str, v: &str) -> Result<String> {
+    let digits: String = v.chars().filter(|c| *c != ':').collect();
+    if digits.len() != 16 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(HcaError::InvalidValue {
+            device: device.to_string(),
+            name: "guid".to_string(),
+            value: v.to_string(),
+        });
+    }
+
+    let bytes: Vec<&str> = (0..16).step_by(2).map(|i| &digits[i..i + 2]).collect();
+    Ok(bytes.join(":").to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    const PF: &str = "sys/devices/pci0000:b0/0000:b0:02.0/0000:b1:00.0";
+
+    /// A PF `mlx5_0` with 8 VFs at most and `num_vfs` enabled, and its first VF `mlx5_2`.
+    fn fabricate_pf(dir: &TempDir, num_vfs: u32) -> Sysfs {
+        dir.write(format!("{}/sriov_totalvfs", PF), "8\n");
+        dir.write(format!("{}/sriov_numvfs", PF), format!("{}\n", num_vfs));
+        dir.link("sys/class/infiniband/mlx5_0/device", PF);
+
+        for index in 0..num_vfs {
+            let vf = format!(
+                "sys/devices/pci0000:b0/0000:b0:02.0/0000:b1:00.{}",
+                index + 2
+            );
+            dir.mkdir(&vf);
+            dir.link(format!("{}/physfn", vf), PF);
+            dir.link(format!("{}/virtfn{}", PF, index), &vf);
+            for name in ["node", "port", "policy"] {
+                dir.write(format!("{}/sriov/{}/{}", PF, index, name), "");
+            }
+        }
+        if num_vfs > 0 {
+            dir.link(
+                "sys/class/infiniband/mlx5_2/device",
+                "sys/devices/pci0000:b0/0000:b0:02.0/0000:b1:00.2",
+            );
+        }
+
+        Sysfs::new(dir.path().join("sys"))
+    }
+
+    fn num_vfs(n: u32) -> SriovConfig {
+        SriovConfig {
+            num_vfs: Some(n),
+            vfs: vec![],
+        }
+    }
+
+    /// The writes by their path relative to the PF.
+    fn values<'a>(sysfs: &Sysfs, writes: &'a [SysfsWrite]) -> Vec<(String, &'a str)> {
+        let pf = sysfs.class_infiniband().join("mlx5_0/device");
+        writes
+            .iter()
+            .map(|w| {
+                let path = w.path.strip_prefix(&pf).unwrap();
+                (path.display().to_string(), w.value.as_str())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn read_sriov() {
+        let dir = TempDir::new();
+        let sysfs = fabricate_pf(&dir, 2);
+
+        let sriov = Sriov::read(&dir.path().join(PF)).unwrap();
+        assert_eq!((sriov.total_vfs, sriov.num_vfs), (8, 2));
+        assert_eq!(sriov.vfs, ["0000:b1:00.2", "0000:b1:00.3"]);
+
+        let vf = sysfs.class_infiniband().join("mlx5_2/device");
+        assert_eq!(
+            read_physfn(&vf, "0000:b1:00.2"),
+            Some(("0000:b1:00.0".to_string(), Some(0)))
+        );
+        assert_eq!(read_physfn(&dir.path().join(PF), "0000:b1:00.0"), None);
+    }
+
+    #[test]
+    fn disable_vfs_first() {
+        let dir = TempDir::new();
+        let sysfs = fabricate_pf(&dir, 4);
+
+        let writes = num_vfs(2).plan(&sysfs, "mlx5_0").unwrap();
+        assert_eq!(
+            values(&sysfs, &writes),
+            [
+                ("sriov_numvfs".to_string(), "0"),
+                ("sriov_numvfs".to_string(), "2")
+            ]
+        );
+
+        // Disabling them is a single write, and the same number none.
+        let writes = num_vfs(0).plan(&sysfs, "mlx5_0").unwrap();
+        assert_eq!(values(&sysfs, &writes), [("sriov_numvfs".to_string(), "0")]);
+        assert!(num_vfs(4).plan(&sysfs, "mlx5_0").unwrap().is_empty());
+    }
+
+    #[test]
+    fn enable_vfs() {
+        let dir = TempDir::new();
+        let sysfs = fabricate_pf(&dir, 0);
+
+        let writes = num_vfs(8).plan(&sysfs, "mlx5_0").unwrap();
+        assert_eq!(values(&sysfs, &writes), [("sriov_numvfs".to_string(), "8")]);
+    }
+
+    #[test]
+    fn reject_num_vfs_above_total() {
+        let dir = TempDir::new();
+        let sysfs = fabricate_pf(&dir, 0);
+
+        let error = num_vfs(9).plan(&sysfs, "mlx5_0").unwrap_err();
+        assert!(matches!(
+            &error,
+            HcaError::InvalidValue { device, name, .. } if device == "mlx5_0" && name == "sriov_numvfs"
+        ));
+        assert_eq!(dir.read(format!("{}/sriov_numvfs", PF)), "0\n");
+    }
+
+    #[test]
+    fn reject_vf_index() {
+        let dir = TempDir::new();
+        let sysfs = fabricate_pf(&dir, 2);
+
+        let mut config = SriovConfig {
+            num_vfs: None,
+            vfs: vec![VfConfig {
+                index: 2,
+                policy: Some(VfLinkPolicy::Up),
+                ..Default::default()
+            }],
+        };
+        let error = config.plan(&sysfs, "mlx5_0").unwrap_err();
+        assert!(matches!(&error, HcaError::InvalidValue { name, .. } if name == "vf"));
+
+        // The VF is in range of the new number of VFs.
+        config.num_vfs = Some(4);
+        let writes = config.plan(&sysfs, "mlx5_0").unwrap();
+        assert_eq!(
+            values(&sysfs, &writes),
+            [
+                ("sriov_numvfs".to_string(), "0"),
+                ("sriov_numvfs".to_string(), "4"),
+                ("sriov/2/policy".to_string(), "Up"),
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_non_pf() {
+        let dir = TempDir::new();
+        let sysfs = fabricate_pf(&dir, 1);
+
+        for device in ["mlx5_2", "mlx5_9"] {
+            let error = num_vfs(1).plan(&sysfs, device).unwrap_err();
+            assert!(matches!(error, HcaError::NotSriovPf { device: d } if d == device));
+        }
+    }
+
+    #[test]
+    fn parse_guids() {
+        let guid = "11:22:33:44:55:66:77:88";
+        assert_eq!(parse_guid("mlx5_0", guid).unwrap(), guid);
+        assert_eq!(parse_guid("mlx5_0", "1122:3344:5566:7788").unwrap(), guid);
+        assert_eq!(parse_guid("mlx5_0", "1122334455667788").unwrap(), guid);
+        assert_eq!(
+            parse_guid("mlx5_0", "AABB:CCDD:EEFF:0011").unwrap(),
+            "aa:bb:cc:dd:ee:ff:00:11"
+        );
+
+        for v in [
+            "",
+            "1122:3344:5566",
+            "1122:3344:5566:7788:99",
+            "1122:3344:5566:77gg",
+            "0x11223344556677",
+        ] {
+            assert!(
+                matches!(parse_guid("mlx5_0", v), Err(HcaError::InvalidValue { value, .. }) if value == v),
+                "{}",
+                v
+            );
+        }
+    }
+
+    #[test]
+    fn dry_run_matches_apply() {
+        let dir = TempDir::new();
+        let sysfs = fabricate_pf(&dir, 2);
+        let config = SriovConfig {
+            num_vfs: Some(2),
+            vfs: vec![VfConfig {
+                index: 1,
+                node_guid: Some("1122:3344:5566:7788".to_string()),
+                port_guid: Some("1122:3344:5566:7789".to_string()),
+                policy: Some(VfLinkPolicy::Follow),
+            }],
+        };
+
+        let plan = config.plan(&sysfs, "mlx5_0").unwrap();
+        let vf = sysfs.class_infiniband().join("mlx5_0/device/sriov/1");
+        let dry_run: Vec<String> = plan.iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            dry_run,
+            [
+                format!(
+                    "echo 11:22:33:44:55:66:77:88 > {}",
+                    vf.join("node").display()
+                ),
+                format!(
+                    "echo 11:22:33:44:55:66:77:89 > {}",
+                    vf.join("port").display()
+                ),
+                format!("echo Follow > {}", vf.join("policy").display()),
+            ]
+        );
+
+        let writes = config.apply(&sysfs, "mlx5_0").unwrap();
+        assert_eq!(writes, plan);
+        for write in &writes {
+            assert_eq!(fs::read_to_string(&write.path).unwrap(), write.value);
+        }
+    }
+}
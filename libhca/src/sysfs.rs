@@ -0,0 +1,833 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Discovery backend which reads sysfs directly, without libudev or libibverbs.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::caps::PortCapabilities;
+use super::counters::PortCounters;
+use super::error::{HcaError, Result};
+use super::netdev;
+use super::numa;
+use super::pcie::PcieLink;
+use super::sriov::{read_physfn, Sriov};
+use super::types::{
+    Discovery, DiscoveryWarning, Gid, GidEntry, GidType, IbDevice, IbMtu, IbPort, IbPortLinkType,
+    IbPortPhysState, IbPortSpeed, IbPortState, IbPortWidth, NodeType, PKey, PKeyEntry, PciDevice,
+    Transport,
+};
+use super::utils::{parse_code, read_sysfs_attr, read_sysfs_dir, read_sysfs_link_name};
+use super::vpd::Vpd;
+
+/// The default mount point of sysfs.
+pub const DEFAULT_SYSFS_ROOT: &str = "/sys";
+
+/// The well-known locations of the PCI ID database, relative to the root of the filesystem
+/// of the sysfs.
+const PCI_IDS_PATHS: [&str; 3] = [
+    "usr/share/misc/pci.ids",
+    "usr/share/hwdata/pci.ids",
+    "usr/share/pci.ids",
+];
+
+/// A sysfs tree mounted at `root`, e.g. `/sys`, `/host/sys` inside a container,
+/// or a fabricated directory tree.
+#[derive(Clone, Debug)]
+pub struct Sysfs {
+    root: PathBuf,
+}
+
+impl Default for Sysfs {
+    fn default() -> Self {
+        Self::new(DEFAULT_SYSFS_ROOT)
+    }
+}
+
+impl Sysfs {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// List the HCAs under the sysfs root; see [`Sysfs::discover_pci_devices`] for the
+    /// devices and ports which were skipped.
+    pub fn list_pci_devices(&self) -> Result<Vec<PciDevice>> {
+        Ok(self.discover_pci_devices()?.pci_devices)
+    }
+
+    /// Read the counters of a port, e.g. port 1 of `mlx5_0`; they are read again on every call.
+    pub fn read_port_counters(&self, device: &str, port_num: u8) -> Result<PortCounters> {
+        let path = self
+            .class_infiniband()
+            .join(device)
+            .join("ports")
+            .join(port_num.to_string());
+
+        PortCounters::read(&path, device, port_num)
+    }
+
+    /// Discover the HCAs under the sysfs root, skipping the devices and ports which can not be read.
+    pub fn discover_pci_devices(&self) -> Result<Discovery> {
+        let mut pci_devs = BTreeMap::<String, PciDevice>::new();
+        let mut warnings = vec![];
+        let if_addrs = netdev::read_interface_addresses();
+
+        for name in self.list_ib_device_names()? {
+            let mut ib_dev = match self.read_ib_device(&name, &mut warnings) {
+                Ok(ib_dev) => ib_dev,
+                Err(e) => {
+                    warnings.push(DiscoveryWarning::new(&name, e));
+                    continue;
+                }
+            };
+            let ib_path = self.class_infiniband().join(&name);
+            for port in &mut ib_dev.ib_ports {
+                port.netdevs =
+                    netdev::read_port_netdevs(&ib_path, &self.class_net(), port, &if_addrs);
+                // The active MTU of RoCE follows the netdev, as the kernel derives it.
+                if port.link_type == IbPortLinkType::Ethernet {
+                    if let Some(mtu) = port.netdevs.first().and_then(|n| n.mtu) {
+                        port.active_mtu = roce_mtu(mtu);
+                    }
+                }
+            }
+            // A software device, e.g. rxe or siw, has no PCI device; it is an adapter of its own.
+            let pci_dev = if ib_dev.slot_name.is_empty() {
+                PciDevice::software(&ib_dev)
+            } else {
+                match self.read_pci_device(&ib_dev.slot_name) {
+                    Ok(pci_dev) => pci_dev,
+                    Err(e) => {
+                        warnings.push(DiscoveryWarning::new(&name, e));
+                        continue;
+                    }
+                }
+            };
+
+            super::merge_ib_device(&mut pci_devs, pci_dev, ib_dev);
+        }
+
+        Ok(Discovery {
+            pci_devices: pci_devs.into_values().collect(),
+            warnings,
+        })
+    }
+
+    pub(crate) fn class_infiniband(&self) -> PathBuf {
+        self.root.join("class").join("infiniband")
+    }
+
+    pub(crate) fn class_net(&self) -> PathBuf {
+        self.root.join("class").join("net")
+    }
+
+    fn bus_pci_devices(&self) -> PathBuf {
+        self.root.join("bus").join("pci").join("devices")
+    }
+
+    fn list_ib_device_names(&self) -> Result<Vec<String>> {
+        let path = self.class_infiniband();
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+
+        read_sysfs_dir(&path)
+    }
+
+    fn read_pci_device(&self, slot_name: &str) -> Result<PciDevice> {
+        let path = self.bus_pci_devices().join(slot_name);
+
+        let vendor = read_sysfs_attr(path.join("vendor"))?;
+        let device = read_sysfs_attr(path.join("device"))?;
+        let subsys_vendor = read_sysfs_attr(path.join("subsystem_vendor"))?;
+        let subsys_device = read_sysfs_attr(path.join("subsystem_device"))?;
+
+        let (vendor_name, model_name) = self.lookup_pci_names(&vendor, &device);
+        let local_cpulist = numa::read_local_cpulist(&path);
+
+        Ok(PciDevice {
+            adapter_id: String::new(),
+            subsys_id: format!(
+                "{}:{}",
+                strip_hex_prefix(&subsys_vendor).to_uppercase(),
+                strip_hex_prefix(&subsys_device).to_uppercase()
+            ),
+            model_name: model_name.unwrap_or(device),
+            vendor_name: vendor_name.unwrap_or_else(|| vendor.clone()),
+            vendor,
+            vpd: Vpd::read(path.join("vpd")),
+            pcie_link: PcieLink::read(&path),
+            numa_node: numa::read_numa_node(&path),
+            local_cpus: numa::parse_cpulist(&local_cpulist),
+            local_cpulist,
+            ib_devices: vec![],
+
+            board_id: None,
+            fw_ver: None,
+        })
+    }
+
+    /// Look up the vendor and device names in the PCI ID database of the filesystem of the
+    /// sysfs, e.g. `/host/usr/share/misc/pci.ids` for `/host/sys`, if there is one.
+    fn lookup_pci_names(&self, vendor: &str, device: &str) -> (Option<String>, Option<String>) {
+        let vendor = strip_hex_prefix(vendor).to_lowercase();
+        let device = strip_hex_prefix(device).to_lowercase();
+
+        let fs_root = self.root.parent().unwrap_or(Path::new("/"));
+        let ids = match PCI_IDS_PATHS
+            .iter()
+            .find_map(|p| fs::read_to_string(fs_root.join(p)).ok())
+        {
+            Some(ids) => ids,
+            None => return (None, None),
+        };
+
+        let mut vendor_name = None;
+        for line in ids.lines() {
+            if line.starts_with('#') || line.is_empty() {
+                continue;
+            }
+
+            match vendor_name {
+                None => {
+                    if let Some(name) = line.strip_prefix(vendor.as_str()) {
+                        if name.starts_with(' ') {
+                            vendor_name = Some(name.trim().to_string());
+                        }
+                    }
+                }
+                Some(_) => {
+                    // The device entries of a vendor are indented by one tab.
+                    let entry = match line.strip_prefix('\t') {
+                        Some(entry) => entry,
+                        None => break,
+                    };
+                    if let Some(name) = entry.strip_prefix(device.as_str()) {
+                        if name.starts_with(' ') {
+                            return (vendor_name, Some(name.trim().to_string()));
+                        }
+                    }
+                }
+            }
+        }
+
+        (vendor_name, None)
+    }
+
+    fn read_ib_device(&self, name: &str, warnings: &mut Vec<DiscoveryWarning>) -> Result<IbDevice> {
+        let path = self.class_infiniband().join(name);
+
+        // The parent device is the PCI device of a hardware device; a software device, e.g.
+        // rxe0, has none.
+        let pci_path = path.join("device");
+        let (slot_name, driver) = match read_sysfs_link_name(&pci_path) {
+            Ok(parent) => {
+                // A parent of another bus, e.g. a platform device, has no PCI slot.
+                let is_pci = read_sysfs_link_name(pci_path.join("subsystem"))
+                    .map(|s| s == "pci")
+                    .unwrap_or(true);
+                (
+                    if is_pci { parent } else { String::new() },
+                    read_sysfs_link_name(pci_path.join("driver")).ok(),
+                )
+            }
+            Err(_) => (String::new(), IbDevice::software_driver(name)),
+        };
+        let (physfn, vf_index) = match read_physfn(&pci_path, &slot_name) {
+            Some((physfn, vf_index)) => (Some(physfn), vf_index),
+            None => (None, None),
+        };
+        let node_type = NodeType::from(
+            read_sysfs_attr(path.join("node_type"))
+                .map(|v| parse_code(&v))
+                .unwrap_or(-1),
+        );
+
+        let mut ib_dev = IbDevice {
+            name: name.to_string(),
+            slot_name,
+            driver,
+            node_type,
+            transport: Transport::from(node_type),
+            node_guid: read_sysfs_attr(path.join("node_guid")).ok(),
+            node_desc: read_sysfs_attr(path.join("node_desc")).ok(),
+            sys_image_guid: read_sysfs_attr(path.join("sys_image_guid")).ok(),
+            fw_ver: read_sysfs_attr(path.join("fw_ver"))
+                .ok()
+                .filter(|v| !v.is_empty()),
+            board_id: read_sysfs_attr(path.join("board_id")).ok(),
+            caps: None,
+            ext_caps: None,
+            sriov: Sriov::read(&pci_path),
+            physfn,
+            vf_index,
+            ib_ports: vec![],
+        };
+
+        let ports_path = path.join("ports");
+        let mut port_nums: Vec<u8> = read_sysfs_dir(&ports_path)?
+            .iter()
+            .filter_map(|n| n.parse().ok())
+            .collect();
+        port_nums.sort();
+
+        for port_num in port_nums {
+            match read_ib_port(name, &ports_path.join(port_num.to_string()), port_num) {
+                Ok(port) => ib_dev.ib_ports.push(port),
+                Err(e) => warnings.push(DiscoveryWarning::new(
+                    name,
+                    HcaError::port_error(name, port_num, e),
+                )),
+            }
+        }
+
+        Ok(ib_dev)
+    }
+}
+
+fn read_ib_port(device: &str, path: &Path, port_num: u8) -> Result<IbPort> {
+    // The kernel shows an unspecified link layer as "Unknown", see `IbPortLinkType::try_from`.
+    let link_type = match read_sysfs_attr(path.join("link_layer"))?.as_str() {
+        "InfiniBand" | "Unknown" => IbPortLinkType::Infiniband,
+        "Ethernet" => IbPortLinkType::Ethernet,
+        v => {
+            return Err(HcaError::InvalidAttribute {
+                device: device.to_string(),
+                path: path.join("link_layer"),
+                value: v.to_string(),
+            })
+        }
+    };
+
+    let guid = match link_type {
+        IbPortLinkType::Ethernet => None,
+        IbPortLinkType::Infiniband => {
+            let gid = read_sysfs_attr(path.join("gids").join("0"))?;
+            Some(gid.split(':').skip(4).collect::<Vec<_>>().join(":"))
+        }
+    };
+
+    // The rate is not readable if the link is down, e.g. for some Ethernet ports.
+    let (width, speed) = match read_sysfs_attr(path.join("rate")) {
+        Ok(rate) => parse_rate(&rate),
+        Err(_) => (IbPortWidth::Unknown(0), IbPortSpeed::Unknown(0)),
+    };
+
+    Ok(IbPort {
+        port_num,
+        guid,
+        lid: parse_hex_attr(device, path, "lid")?,
+        link_type,
+        state: IbPortState::from(parse_state_code::<u32>(device, path, "state")?),
+        phys_state: IbPortPhysState::from(parse_state_code::<u8>(device, path, "phys_state")?),
+        width,
+        speed,
+        // The supported speeds are only in the PortInfo of the port, which needs its umad device.
+        supported_speeds: vec![],
+        rate: IbPort::data_rate(width, speed),
+        // The MTUs, subnet timeout and VLs are not in sysfs; the active MTU of RoCE is
+        // derived from its netdev later.
+        max_mtu: IbMtu::Unknown(0),
+        active_mtu: IbMtu::Unknown(0),
+        sm_lid: parse_hex_attr(device, path, "sm_lid")?,
+        sm_sl: parse_attr(device, path, "sm_sl")?,
+        lmc: parse_attr(device, path, "lid_mask_count")?,
+        subnet_timeout: None,
+        max_vl_num: None,
+        gid_tbl_len: count_entries(&path.join("gids")),
+        pkey_tbl_len: count_entries(&path.join("pkeys")) as u16,
+        // The port_cap_flags2 are not in sysfs.
+        capabilities: PortCapabilities::from_raw(parse_hex_attr(device, path, "cap_mask")?, 0),
+        gids: read_gid_table(path, link_type),
+        pkeys: read_pkey_table(device, path),
+        netdevs: vec![],
+    })
+}
+
+/// Read the valid entries of the P_Key table of a port, e.g. `pkeys/0` of `0xffff`.
+fn read_pkey_table(device: &str, path: &Path) -> Vec<PKeyEntry> {
+    let pkeys_path = path.join("pkeys");
+    let mut indexes: Vec<u16> = read_sysfs_dir(&pkeys_path)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|n| n.parse().ok())
+        .collect();
+    indexes.sort();
+
+    indexes
+        .into_iter()
+        .filter_map(|index| {
+            let pkey = PKey(parse_hex_attr(device, &pkeys_path, &index.to_string()).ok()?);
+            pkey.is_valid().then(|| PKeyEntry::new(index, pkey))
+        })
+        .collect()
+}
+
+/// Read the valid entries of the GID table of a port; the type of an empty entry is not readable.
+fn read_gid_table(path: &Path, link_type: IbPortLinkType) -> Vec<GidEntry> {
+    let mut indexes: Vec<u32> = read_sysfs_dir(path.join("gids"))
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|n| n.parse().ok())
+        .collect();
+    indexes.sort();
+
+    let attrs = path.join("gid_attrs");
+    indexes
+        .into_iter()
+        .filter_map(|index| {
+            let gid: Gid = read_sysfs_attr(path.join("gids").join(index.to_string()))
+                .ok()?
+                .parse()
+                .ok()?;
+            if gid.is_zero() {
+                return None;
+            }
+
+            let gid_type = gid_type(
+                read_sysfs_attr(attrs.join("types").join(index.to_string())).ok(),
+                link_type,
+            );
+            let netdev = read_sysfs_attr(attrs.join("ndevs").join(index.to_string())).ok();
+
+            Some(GidEntry {
+                index,
+                gid,
+                gid_type,
+                netdev,
+            })
+        })
+        .collect()
+}
+
+/// The type of a GID by its `gid_attrs/types` attribute, e.g. `RoCE v2`; the IB and RoCE v1
+/// GIDs share the type `IB/RoCE v1`, so they are told apart by the link layer.
+fn gid_type(attr: Option<String>, link_type: IbPortLinkType) -> GidType {
+    match attr {
+        Some(t) if t == "RoCE v2" => GidType::RoceV2,
+        _ if link_type == IbPortLinkType::Infiniband => GidType::Ib,
+        _ => GidType::RoceV1,
+    }
+}
+
+fn parse_attr<T: std::str::FromStr>(device: &str, path: &Path, name: &str) -> Result<T> {
+    let v = read_sysfs_attr(path.join(name))?;
+    v.parse()
+        .map_err(|_| invalid_attribute(device, &path.join(name), &v))
+}
+
+/// Parse the hexadecimal attributes, e.g. `lid` of `0x3`.
+fn parse_hex_attr<T: TryFrom<u64>>(device: &str, path: &Path, name: &str) -> Result<T> {
+    let v = read_sysfs_attr(path.join(name))?;
+    u64::from_str_radix(strip_hex_prefix(&v), 16)
+        .ok()
+        .and_then(|n| T::try_from(n).ok())
+        .ok_or_else(|| invalid_attribute(device, &path.join(name), &v))
+}
+
+/// Count the entries of a table directory, e.g. `gids` or `pkeys`; 0 if there is no such table.
+fn count_entries(path: &Path) -> u32 {
+    read_sysfs_dir(path).map(|n| n.len() as u32).unwrap_or(0)
+}
+
+/// Parse the width and speed of the rate attribute, e.g. `100 Gb/sec (4X EDR)`;
+/// the speed is omitted for SDR, e.g. `10 Gb/sec (4X)`.
+fn parse_rate(v: &str) -> (IbPortWidth, IbPortSpeed) {
+    let detail = v
+        .split_once('(')
+        .and_then(|(_, d)| d.strip_suffix(')'))
+        .unwrap_or_default();
+    let mut tokens = detail.split_whitespace();
+
+    let width = match tokens.next() {
+        Some("1X") => IbPortWidth::X1,
+        Some("2X") => IbPortWidth::X2,
+        Some("4X") => IbPortWidth::X4,
+        Some("8X") => IbPortWidth::X8,
+        Some("12X") => IbPortWidth::X12,
+        _ => return (IbPortWidth::Unknown(0), IbPortSpeed::Unknown(0)),
+    };
+
+    let speed = match tokens.next() {
+        None => IbPortSpeed::Sdr,
+        Some("DDR") => IbPortSpeed::Ddr,
+        Some("QDR") => IbPortSpeed::Qdr,
+        Some("FDR10") => IbPortSpeed::Fdr10,
+        Some("FDR") => IbPortSpeed::Fdr,
+        Some("EDR") => IbPortSpeed::Edr,
+        Some("HDR") => IbPortSpeed::Hdr,
+        Some("NDR") => IbPortSpeed::Ndr,
+        Some("XDR") => IbPortSpeed::Xdr,
+        Some(_) => IbPortSpeed::Unknown(0),
+    };
+
+    (width, speed)
+}
+
+/// Parse the numeric code of the state attributes, e.g. `4: ACTIVE` or `5: LinkUp`.
+fn parse_state_code<T: std::str::FromStr>(device: &str, path: &Path, name: &str) -> Result<T> {
+    let v = read_sysfs_attr(path.join(name))?;
+    v.split(':')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .parse()
+        .map_err(|_| invalid_attribute(device, &path.join(name), &v))
+}
+
+fn invalid_attribute(device: &str, path: &Path, value: &str) -> HcaError {
+    HcaError::InvalidAttribute {
+        device: device.to_string(),
+        path: path.to_path_buf(),
+        value: value.to_string(),
+    }
+}
+
+/// The IB MTU of a RoCE port with a netdev of the given MTU, i.e. the largest one which fits
+/// with the RoCE headers, see `iboe_get_mtu` of the kernel; if none fits, the invalid code 0
+/// which the kernel reports then.
+fn roce_mtu(netdev_mtu: u32) -> IbMtu {
+    // GRH, UDP, BTH, XRC ETH, Atomic ETH and ICRC.
+    const ROCE_HEADERS: u32 = 40 + 8 + 12 + 4 + 28 + 4;
+
+    IbMtu::fitting(netdev_mtu.saturating_sub(ROCE_HEADERS)).unwrap_or(IbMtu::Unknown(0))
+}
+
+fn strip_hex_prefix(v: &str) -> &str {
+    v.trim_start_matches("0x")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+    use crate::types::PKeyMembership;
+
+    /// A ConnectX-5 with one InfiniBand port at `0000:b1:00.0`.
+    fn fabricate_hca(dir: &TempDir) {
+        let pci = "sys/bus/pci/devices/0000:b1:00.0";
+        dir.write(format!("{}/vendor", pci), "0x15b3\n");
+        dir.write(format!("{}/device", pci), "0x1017\n");
+        dir.write(format!("{}/subsystem_vendor", pci), "0x15b3\n");
+        dir.write(format!("{}/subsystem_device", pci), "0x0007\n");
+        dir.write(format!("{}/numa_node", pci), "1\n");
+        dir.write(format!("{}/local_cpulist", pci), "4-5,8\n");
+        dir.mkdir("sys/bus/pci/drivers/mlx5_core");
+        dir.link(format!("{}/subsystem", pci), "sys/bus/pci");
+        dir.link(format!("{}/driver", pci), "sys/bus/pci/drivers/mlx5_core");
+
+        let dev = "sys/class/infiniband/mlx5_0";
+        dir.link(format!("{}/device", dev), pci);
+        dir.write(format!("{}/node_type", dev), "1: CA\n");
+        dir.write(format!("{}/node_guid", dev), "b83f:d203:006a:e616\n");
+        dir.write(format!("{}/node_desc", dev), "node01 mlx5_0\n");
+        dir.write(format!("{}/sys_image_guid", dev), "b83f:d203:006a:e616\n");
+        dir.write(format!("{}/fw_ver", dev), "16.35.3006\n");
+        dir.write(format!("{}/board_id", dev), "MT_0000000008\n");
+
+        let port = format!("{}/ports/1", dev);
+        for (name, value) in [
+            ("link_layer", "InfiniBand"),
+            ("rate", "100 Gb/sec (4X EDR)"),
+            ("lid", "0x3"),
+            ("state", "4: ACTIVE"),
+            ("phys_state", "5: LinkUp"),
+            ("sm_lid", "0x1"),
+            ("sm_sl", "0"),
+            ("lid_mask_count", "0"),
+            ("cap_mask", "0x2651e848"),
+            ("gids/0", "fe80:0000:0000:0000:b83f:d203:006a:e616"),
+            ("gids/1", "0000:0000:0000:0000:0000:0000:0000:0000"),
+            ("gid_attrs/types/0", "IB/RoCE v1"),
+            ("pkeys/0", "0xffff"),
+            ("pkeys/1", "0x0000"),
+            ("pkeys/2", "0x0001"),
+        ] {
+            dir.write(format!("{}/{}", port, name), format!("{}\n", value));
+        }
+
+        dir.write(
+            "usr/share/misc/pci.ids",
+            "# PCI IDs\n15b3  Mellanox Technologies\n\t1017  MT27800 Family [ConnectX-5]\n\t\t15b3 0007  ConnectX-5 VPI\n1af4  Red Hat, Inc.\n",
+        );
+    }
+
+    /// A Soft-RoCE device on the netdev `tst0`, which has no PCI parent.
+    fn fabricate_rxe(dir: &TempDir) {
+        let dev = "sys/class/infiniband/rxe0";
+        dir.write(format!("{}/node_type", dev), "1: CA\n");
+        dir.write(format!("{}/node_guid", dev), "505b:c2ff:fe4a:b2c0\n");
+        dir.write(format!("{}/node_desc", dev), "rxe0\n");
+        dir.write(format!("{}/sys_image_guid", dev), "505b:c2ff:fe4a:b2c0\n");
+        dir.write(format!("{}/fw_ver", dev), "\n");
+
+        let port = format!("{}/ports/1", dev);
+        for (name, value) in [
+            ("link_layer", "Ethernet"),
+            ("lid", "0x0"),
+            ("state", "1: DOWN"),
+            ("phys_state", "3: Disabled"),
+            ("sm_lid", "0x0"),
+            ("sm_sl", "0"),
+            ("lid_mask_count", "0"),
+            ("cap_mask", "0x00010000"),
+            ("gids/0", "fe80:0000:0000:0000:505b:c2ff:fe4a:b2c0"),
+            ("gid_attrs/types/0", "RoCE v2"),
+            ("gid_attrs/ndevs/0", "tst0"),
+        ] {
+            dir.write(format!("{}/{}", port, name), format!("{}\n", value));
+        }
+
+        dir.write("sys/class/net/tst0/mtu", "1500\n");
+        dir.write("sys/class/net/tst0/address", "50:5b:c2:4a:b2:c0\n");
+        dir.write("sys/class/net/tst0/operstate", "down\n");
+    }
+
+    #[test]
+    fn discover_pci_device() {
+        let dir = TempDir::new();
+        fabricate_hca(&dir);
+
+        let discovery = Sysfs::new(dir.path().join("sys"))
+            .discover_pci_devices()
+            .unwrap();
+        assert!(discovery.warnings.is_empty());
+        assert_eq!(discovery.pci_devices.len(), 1);
+
+        let hca = &discovery.pci_devices[0];
+        assert_eq!(hca.adapter_id, "0000:b1:00");
+        assert_eq!(hca.subsys_id, "15B3:0007");
+        assert_eq!(hca.vendor_name, "Mellanox Technologies");
+        assert_eq!(hca.model_name, "MT27800 Family [ConnectX-5]");
+        assert_eq!(hca.fw_ver.as_deref(), Some("16.35.3006"));
+        assert_eq!(hca.board_id.as_deref(), Some("MT_0000000008"));
+        assert_eq!(hca.numa_node, Some(1));
+        assert_eq!(hca.local_cpus, vec![4, 5, 8]);
+        assert!(!hca.is_software());
+
+        let dev = &hca.ib_devices[0];
+        assert_eq!(dev.name, "mlx5_0");
+        assert_eq!(dev.slot_name, "0000:b1:00.0");
+        assert_eq!(dev.driver.as_deref(), Some("mlx5_core"));
+        assert_eq!(dev.node_type, NodeType::Ca);
+        assert_eq!(dev.transport, Transport::Ib);
+        assert_eq!(dev.node_guid.as_deref(), Some("b83f:d203:006a:e616"));
+        assert_eq!(dev.node_desc.as_deref(), Some("node01 mlx5_0"));
+        assert!(dev.physfn.is_none());
+
+        let port = &dev.ib_ports[0];
+        assert_eq!(port.port_num, 1);
+        assert_eq!(port.link_type, IbPortLinkType::Infiniband);
+        assert_eq!(port.guid.as_deref(), Some("b83f:d203:006a:e616"));
+        assert_eq!(port.lid, 3);
+        assert_eq!(port.sm_lid, 1);
+        assert_eq!(port.state, IbPortState::Active);
+        assert_eq!(port.phys_state, IbPortPhysState::LinkUp);
+        assert_eq!(port.width, IbPortWidth::X4);
+        assert_eq!(port.speed, IbPortSpeed::Edr);
+        assert_eq!(port.rate, 100.0);
+        assert_eq!(port.active_mtu, IbMtu::Unknown(0));
+        assert_eq!(port.subnet_timeout, None);
+        assert_eq!(port.gid_tbl_len, 2);
+        assert_eq!(port.pkey_tbl_len, 3);
+
+        // The zero GID and the invalid P_Key are not entries.
+        assert_eq!(port.gids.len(), 1);
+        assert_eq!(
+            port.gids[0].gid.to_string(),
+            "fe80:0000:0000:0000:b83f:d203:006a:e616"
+        );
+        assert_eq!(port.gids[0].gid_type, GidType::Ib);
+        assert_eq!(port.pkeys.len(), 2);
+        assert_eq!(port.pkeys[0].index, 0);
+        assert_eq!(port.pkeys[0].membership, PKeyMembership::Full);
+        assert!(port.pkeys[0].is_default);
+        assert_eq!(port.pkeys[1].index, 2);
+        assert_eq!(port.pkeys[1].membership, PKeyMembership::Limited);
+    }
+
+    #[test]
+    fn discover_software_device() {
+        let dir = TempDir::new();
+        fabricate_rxe(&dir);
+
+        let discovery = Sysfs::new(dir.path().join("sys"))
+            .discover_pci_devices()
+            .unwrap();
+        assert!(discovery.warnings.is_empty());
+
+        let hca = &discovery.pci_devices[0];
+        assert!(hca.is_software());
+        assert_eq!(hca.adapter_id, "rxe0");
+        assert_eq!(hca.model_name, "Soft-RoCE (rxe)");
+        assert!(hca.pcie_link.is_none());
+
+        let dev = &hca.ib_devices[0];
+        assert_eq!(dev.slot_name, "");
+        assert_eq!(dev.driver.as_deref(), Some("rxe"));
+        assert_eq!(dev.fw_ver, None);
+        assert_eq!(dev.board_id, None);
+
+        let port = &dev.ib_ports[0];
+        assert_eq!(port.link_type, IbPortLinkType::Ethernet);
+        assert_eq!(port.guid, None);
+        assert_eq!(port.state, IbPortState::Down);
+        assert_eq!(port.width, IbPortWidth::Unknown(0));
+        assert_eq!(port.gids[0].gid_type, GidType::RoceV2);
+        assert_eq!(port.gids[0].netdev.as_deref(), Some("tst0"));
+        assert_eq!(port.netdevs[0].name, "tst0");
+        // 1500 bytes less the RoCE headers.
+        assert_eq!(port.active_mtu, IbMtu::Mtu1024);
+    }
+
+    #[test]
+    fn discover_without_node_attrs() {
+        let dir = TempDir::new();
+        fabricate_rxe(&dir);
+        for name in ["node_guid", "node_desc", "sys_image_guid"] {
+            std::fs::remove_file(dir.path().join("sys/class/infiniband/rxe0").join(name)).unwrap();
+        }
+
+        let discovery = Sysfs::new(dir.path().join("sys"))
+            .discover_pci_devices()
+            .unwrap();
+        assert!(discovery.warnings.is_empty());
+
+        let dev = &discovery.pci_devices[0].ib_devices[0];
+        assert_eq!(dev.node_guid, None);
+        assert_eq!(dev.node_desc, None);
+        assert_eq!(dev.sys_image_guid, None);
+        assert_eq!(dev.ib_ports.len(), 1);
+    }
+
+    #[test]
+    fn skip_unreadable_port() {
+        let dir = TempDir::new();
+        fabricate_hca(&dir);
+        dir.write("sys/class/infiniband/mlx5_0/ports/2/link_layer", "bogus\n");
+
+        let discovery = Sysfs::new(dir.path().join("sys"))
+            .discover_pci_devices()
+            .unwrap();
+        assert_eq!(discovery.pci_devices[0].ib_devices[0].ib_ports.len(), 1);
+        assert_eq!(discovery.warnings.len(), 1);
+        assert_eq!(discovery.warnings[0].port_num, Some(2));
+    }
+
+    #[test]
+    fn unspecified_link_layer() {
+        let dir = TempDir::new();
+        fabricate_hca(&dir);
+        dir.write(
+            "sys/class/infiniband/mlx5_0/ports/1/link_layer",
+            "Unknown\n",
+        );
+
+        let discovery = Sysfs::new(dir.path().join("sys"))
+            .discover_pci_devices()
+            .unwrap();
+        assert!(discovery.warnings.is_empty());
+        let port = &discovery.pci_devices[0].ib_devices[0].ib_ports[0];
+        assert_eq!(port.link_type, IbPortLinkType::Infiniband);
+        assert_eq!(port.guid.as_deref(), Some("b83f:d203:006a:e616"));
+    }
+
+    #[test]
+    fn invalid_attribute_names_device() {
+        let dir = TempDir::new();
+        fabricate_hca(&dir);
+        dir.write("sys/class/infiniband/mlx5_0/ports/1/lid", "bogus\n");
+
+        let discovery = Sysfs::new(dir.path().join("sys"))
+            .discover_pci_devices()
+            .unwrap();
+        let warning = &discovery.warnings[0];
+        assert_eq!(warning.device, "mlx5_0");
+        assert_eq!(warning.port_num, Some(1));
+        assert!(warning
+            .message
+            .starts_with("mlx5_0: invalid value <bogus> of "));
+        assert!(warning.message.ends_with("ports/1/lid"));
+    }
+
+    #[test]
+    fn pci_names_without_database() {
+        let dir = TempDir::new();
+        fabricate_hca(&dir);
+        std::fs::remove_dir_all(dir.path().join("usr")).unwrap();
+
+        let hcas = Sysfs::new(dir.path().join("sys"))
+            .list_pci_devices()
+            .unwrap();
+        assert_eq!(hcas[0].vendor_name, "0x15b3");
+        assert_eq!(hcas[0].model_name, "0x1017");
+    }
+
+    #[test]
+    fn parse_rates() {
+        assert_eq!(
+            parse_rate("100 Gb/sec (4X EDR)"),
+            (IbPortWidth::X4, IbPortSpeed::Edr)
+        );
+        assert_eq!(
+            parse_rate("10 Gb/sec (4X)"),
+            (IbPortWidth::X4, IbPortSpeed::Sdr)
+        );
+        assert_eq!(
+            parse_rate("400 Gb/sec (4X NDR)"),
+            (IbPortWidth::X4, IbPortSpeed::Ndr)
+        );
+        assert_eq!(
+            parse_rate("invalid"),
+            (IbPortWidth::Unknown(0), IbPortSpeed::Unknown(0))
+        );
+    }
+
+    #[test]
+    fn roce_mtus() {
+        assert_eq!(roce_mtu(9000), IbMtu::Mtu4096);
+        assert_eq!(roce_mtu(4200), IbMtu::Mtu4096);
+        assert_eq!(roce_mtu(4000), IbMtu::Mtu2048);
+        assert_eq!(roce_mtu(1500), IbMtu::Mtu1024);
+        assert_eq!(roce_mtu(1400), IbMtu::Mtu1024);
+        assert_eq!(roce_mtu(352), IbMtu::Mtu256);
+        assert_eq!(roce_mtu(351), IbMtu::Unknown(0));
+        assert_eq!(roce_mtu(100), IbMtu::Unknown(0));
+    }
+
+    #[test]
+    fn gid_types() {
+        let roce_v2 = Some("RoCE v2".to_string());
+        let roce_v1 = Some("IB/RoCE v1".to_string());
+        assert_eq!(gid_type(roce_v2, IbPortLinkType::Ethernet), GidType::RoceV2);
+        assert_eq!(
+            gid_type(roce_v1.clone(), IbPortLinkType::Ethernet),
+            GidType::RoceV1
+        );
+        assert_eq!(gid_type(roce_v1, IbPortLinkType::Infiniband), GidType::Ib);
+        // The types are missing on old kernels.
+        assert_eq!(gid_type(None, IbPortLinkType::Infiniband), GidType::Ib);
+        assert_eq!(gid_type(None, IbPortLinkType::Ethernet), GidType::RoceV1);
+    }
+}
@@ -0,0 +1,81 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Fabricated sysfs trees for the unit tests.
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A temporary directory which is removed when dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "libhca-test-{}-{}",
+            process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path).unwrap();
+
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write a file, e.g. an attribute, creating its parent directories.
+    pub fn write<P: AsRef<Path>, C: AsRef<[u8]>>(&self, path: P, content: C) -> PathBuf {
+        let path = self.path.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+
+        path
+    }
+
+    /// Create a directory and its parents.
+    pub fn mkdir<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        let path = self.path.join(path);
+        fs::create_dir_all(&path).unwrap();
+
+        path
+    }
+
+    /// Create a symbolic link at `path` to `target`, both relative to the directory.
+    pub fn link<P: AsRef<Path>, Q: AsRef<Path>>(&self, path: P, target: Q) {
+        let path = self.path.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        symlink(self.path.join(target), path).unwrap();
+    }
+
+    pub fn read<P: AsRef<Path>>(&self, path: P) -> String {
+        fs::read_to_string(self.path.join(path)).unwrap()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
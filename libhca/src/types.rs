@@ -15,88 +15,562 @@ limitations under the License.
 */
 
 use std::fmt::{self, Display};
-use std::io;
+use std::net::{IpAddr, Ipv6Addr};
+use std::os::raw::c_int;
 use std::ptr::NonNull;
+use std::str::FromStr;
 
 use libudev::Device;
+use serde::{Serialize, Serializer};
 
-use super::utils::{get_property, get_sysattr};
-use super::wrappers::ibverbs::{
-    self, ibv_device, ibv_device_attr,
-};
+use super::caps::{DeviceCapabilities, OdpCapabilities, PortCapabilities, QpTypes};
+use super::error::HcaError;
+use super::netdev::NetDev;
+use super::numa;
+use super::pcie::PcieLink;
+use super::sriov::{read_physfn, Sriov};
+use super::utils::{get_property, get_sysattr, parse_code};
+use super::vpd::Vpd;
+use super::wrappers::ibverbs::{self, ibv_device, ibv_device_attr, ibv_device_attr_ex};
 
-#[derive(Clone)]
+/// A physical adapter, i.e. the PCI functions of one card, e.g. the two ports of a ConnectX-5.
+#[derive(Clone, Serialize)]
 pub struct PciDevice {
+    /// The PCI domain, bus and device of the adapter, e.g. `0000:b1:00`; the PCI functions
+    /// of an adapter differ in the function number only.
+    pub adapter_id: String,
     pub subsys_id: String,
     pub model_name: String,
     pub vendor_name: String,
     pub vendor: String,
-    pub board_id: String,
-    pub fw_ver: String,
+    pub board_id: Option<String>,
+    pub fw_ver: Option<String>,
+    /// The Vital Product Data, e.g. the serial number; `None` if it is not readable, e.g. without root.
+    pub vpd: Option<Vpd>,
+    /// The PCIe link of the adapter; `None` if it is unknown.
+    pub pcie_link: Option<PcieLink>,
+    /// The NUMA node the adapter is attached to; `None` if the host has no NUMA.
+    pub numa_node: Option<u32>,
+    /// The CPUs local to the adapter, e.g. `0-13,28-41`; empty if they are unknown.
+    pub local_cpulist: String,
+    pub local_cpus: Vec<u32>,
     pub ib_devices: Vec<IbDevice>,
 }
 
+impl PciDevice {
+    /// The adapter of a software device, e.g. Soft-RoCE, which has no PCI device.
+    pub fn software(ib_dev: &IbDevice) -> Self {
+        let driver = ib_dev.driver.as_deref().unwrap_or("-");
+        let model_name = match driver {
+            "rxe" => "Soft-RoCE (rxe)".to_string(),
+            "siw" => "Soft-iWARP (siw)".to_string(),
+            driver => format!("Software RDMA device ({})", driver),
+        };
+
+        Self {
+            adapter_id: String::new(),
+            subsys_id: String::new(),
+            model_name,
+            vendor_name: "-".to_string(),
+            vendor: String::new(),
+            board_id: None,
+            fw_ver: None,
+            vpd: None,
+            pcie_link: None,
+            numa_node: None,
+            local_cpulist: String::new(),
+            local_cpus: vec![],
+            ib_devices: vec![],
+        }
+    }
+
+    /// Whether the adapter is a software device, see [`PciDevice::software`].
+    pub fn is_software(&self) -> bool {
+        self.subsys_id.is_empty()
+    }
+
+    /// The adapter of an RDMA device, see [`PciDevice::adapter_id`]; the adapter of the PF for
+    /// a VF, and the device name if the device has no PCI slot, e.g. `rxe0`.
+    pub fn adapter_id_of(ib_dev: &IbDevice) -> String {
+        let slot_name = ib_dev.physfn.as_ref().unwrap_or(&ib_dev.slot_name);
+        match slot_name.rsplit_once('.') {
+            Some((adapter_id, _)) => adapter_id.to_string(),
+            None => ib_dev.name.clone(),
+        }
+    }
+
+    /// Restrict the CPU affinity of the calling thread to the CPUs local to the adapter, e.g.
+    /// before allocating the buffers and queues of an RDMA process.
+    pub fn bind_to_local_cpus(&self) -> Result<(), HcaError> {
+        if self.local_cpus.is_empty() {
+            return Err(HcaError::NoLocalCpus {
+                device: self.adapter_id.clone(),
+            });
+        }
+
+        numa::set_thread_affinity(&self.local_cpus).map_err(|error| HcaError::Affinity {
+            device: self.adapter_id.clone(),
+            error,
+        })
+    }
+
+    /// The highest data rate among the ports of the adapter whose physical link is up.
+    pub fn max_port_rate(&self) -> Option<f64> {
+        self.ib_devices
+            .iter()
+            .flat_map(|dev| dev.ib_ports.iter())
+            .filter(|port| port.phys_state == IbPortPhysState::LinkUp)
+            .map(|port| port.rate)
+            .reduce(f64::max)
+    }
+
+    /// The data rate a port is expected to run at: the rate it supports, see
+    /// [`IbPort::supported_rate`]; if that is unknown, the ports of the adapter are compared
+    /// with each other instead, see [`PciDevice::max_port_rate`].
+    pub fn expected_port_rate(&self, port: &IbPort) -> Option<f64> {
+        port.supported_rate().or_else(|| self.max_port_rate())
+    }
+
+    /// Whether the link of the port is up below its expected rate, see
+    /// [`PciDevice::expected_port_rate`], e.g. fallen back to a lower width or speed because of
+    /// a bad cable.
+    pub fn is_port_degraded(&self, port: &IbPort) -> bool {
+        port.phys_state == IbPortPhysState::LinkUp
+            && self
+                .expected_port_rate(port)
+                .is_some_and(|expected| port.rate < expected)
+    }
+}
+
 impl TryFrom<Device> for PciDevice {
-    type Error = io::Error;
+    type Error = HcaError;
     fn try_from(dev: Device) -> Result<Self, Self::Error> {
+        let vendor = get_sysattr(&dev, "vendor")?.to_string();
+
+        // The names are missing if the device is not in the hwdb yet, fall back to the IDs.
+        let model_name = get_property(&dev, "ID_MODEL_FROM_DATABASE")
+            .or_else(|_| get_property(&dev, "PCI_ID"))?
+            .to_string();
+        let vendor_name = get_property(&dev, "ID_VENDOR_FROM_DATABASE")
+            .map(|v| v.to_string())
+            .unwrap_or_else(|_| vendor.clone());
+        let local_cpulist = dev
+            .syspath()
+            .map(numa::read_local_cpulist)
+            .unwrap_or_default();
+
         Ok(Self {
+            adapter_id: String::new(),
             subsys_id: get_property(&dev, "PCI_SUBSYS_ID")?.to_string(),
-            model_name: get_property(&dev, "ID_MODEL_FROM_DATABASE")?.to_string(),
-            vendor_name: get_property(&dev, "ID_VENDOR_FROM_DATABASE")?.to_string(),
-            vendor: get_sysattr(&dev, "vendor")?.to_string(),
+            model_name,
+            vendor_name,
+            vendor,
+            vpd: dev.syspath().and_then(|p| Vpd::read(p.join("vpd"))),
+            pcie_link: dev.syspath().and_then(PcieLink::read),
+            numa_node: dev.syspath().and_then(numa::read_numa_node),
+            local_cpulist: local_cpulist.clone(),
+            local_cpus: numa::parse_cpulist(&local_cpulist),
             ib_devices: vec![],
 
-            board_id: String::new(),
-            fw_ver: String::new(),
+            board_id: None,
+            fw_ver: None,
         })
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize)]
 pub struct IbDevice {
     pub name: String,
+    /// The PCI slot, e.g. `0000:b1:00.0`; empty if the device has no PCI device, e.g. `rxe0`.
     pub slot_name: String,
-    pub node_guid: String,
-    pub node_desc: String,
-    pub sys_image_guid: String,
-    pub fw_ver: String,
-    pub board_id: String,
+    /// The kernel driver, e.g. `mlx5_core`, `irdma` or `rxe`.
+    pub driver: Option<String>,
+    pub node_type: NodeType,
+    pub transport: Transport,
+    /// The node GUID, node description and system image GUID; `None` if the device does not
+    /// report them, e.g. a device being removed.
+    pub node_guid: Option<String>,
+    pub node_desc: Option<String>,
+    pub sys_image_guid: Option<String>,
+    /// The firmware version; `None` if the provider does not report one, e.g. `siw`.
+    pub fw_ver: Option<String>,
+    /// The board ID; `None` if the provider does not report one, it is specific to mlx4 and mlx5.
+    pub board_id: Option<String>,
+    /// The limits and capabilities of the device; `None` if they can not be queried, e.g. by the sysfs backend.
+    pub caps: Option<IbDeviceCaps>,
+    /// The extended capabilities; `None` if the provider does not implement `ibv_query_device_ex`.
+    pub ext_caps: Option<IbDeviceExtendedCaps>,
+    /// The SR-IOV of the PCI function if it is a PF which supports it.
+    pub sriov: Option<Sriov>,
+    /// The slot of the PF if the PCI function is a VF, e.g. `0000:b1:00.0`.
+    pub physfn: Option<String>,
+    pub vf_index: Option<u32>,
     pub ib_ports: Vec<IbPort>,
 }
 
+impl IbDevice {
+    pub fn is_vf(&self) -> bool {
+        self.physfn.is_some()
+    }
+}
+
+impl IbDevice {
+    /// The driver of a device without a parent device, i.e. a software device: the prefix of
+    /// its name, e.g. `rxe` of `rxe0`.
+    pub(crate) fn software_driver(name: &str) -> Option<String> {
+        let driver = name.trim_end_matches(|c: char| c.is_ascii_digit());
+        (!driver.is_empty()).then(|| driver.to_string())
+    }
+}
+
 impl TryFrom<Device> for IbDevice {
-    type Error = io::Error;
+    type Error = HcaError;
     fn try_from(dev: Device) -> Result<Self, Self::Error> {
-        let slot_name = match dev.parent() {
-            Some(p) => get_property(&p, "PCI_SLOT_NAME")?.to_string(),
+        let name = get_property(&dev, "NAME")?.to_string();
+
+        // The parent is the PCI device of a hardware device, e.g. not the one of `rxe0`.
+        let parent = dev.parent();
+        let pci_parent = parent
+            .as_ref()
+            .filter(|p| p.subsystem().is_some_and(|s| s == "pci"));
+        let slot_name = match pci_parent {
+            Some(p) => get_property(p, "PCI_SLOT_NAME")?.to_string(),
             None => String::new(),
         };
+        let driver = match &parent {
+            Some(p) => p.driver().map(|d| d.to_string_lossy().to_string()),
+            None => Self::software_driver(&name),
+        };
+        let node_type = NodeType::from(
+            get_sysattr(&dev, "node_type")
+                .map(parse_code)
+                .unwrap_or(ibverbs::IBV_NODE_UNKNOWN),
+        );
+        let pci_path = pci_parent.and_then(|p| p.syspath());
+        let (physfn, vf_index) = match pci_path.and_then(|p| read_physfn(p, &slot_name)) {
+            Some((physfn, vf_index)) => (Some(physfn), vf_index),
+            None => (None, None),
+        };
+
         Ok(Self {
-            name: get_property(&dev, "NAME")?.to_string(),
+            name,
             slot_name,
-            node_guid: get_sysattr(&dev, "node_guid")?.to_string(),
-            node_desc: get_sysattr(&dev, "node_desc")?.to_string(),
-            sys_image_guid: get_sysattr(&dev, "sys_image_guid")?.to_string(),
-            fw_ver: get_sysattr(&dev, "fw_ver")?.to_string(),
-            board_id: get_sysattr(&dev, "board_id")?.to_string(),
+            driver,
+            node_type,
+            transport: Transport::from(node_type),
+            node_guid: get_sysattr(&dev, "node_guid").ok().map(|v| v.to_string()),
+            node_desc: get_sysattr(&dev, "node_desc").ok().map(|v| v.to_string()),
+            sys_image_guid: get_sysattr(&dev, "sys_image_guid")
+                .ok()
+                .map(|v| v.to_string()),
+            fw_ver: get_sysattr(&dev, "fw_ver")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .map(|v| v.to_string()),
+            board_id: get_sysattr(&dev, "board_id").ok().map(|v| v.to_string()),
+            caps: None,
+            ext_caps: None,
+            sriov: pci_path.and_then(Sriov::read),
+            physfn,
+            vf_index,
             ib_ports: vec![],
         })
     }
 }
 
-#[derive(Clone)]
+/// The node type of a device, see `enum ibv_node_type`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum NodeType {
+    Ca,
+    Switch,
+    Router,
+    Rnic,
+    Usnic,
+    UsnicUdp,
+    Unspecified,
+    Unknown(i32),
+}
+
+impl Display for NodeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ca => f.write_str("CA"),
+            Self::Switch => f.write_str("Switch"),
+            Self::Router => f.write_str("Router"),
+            Self::Rnic => f.write_str("RNIC"),
+            Self::Usnic => f.write_str("usNIC"),
+            Self::UsnicUdp => f.write_str("usNIC UDP"),
+            Self::Unspecified => f.write_str("Unspecified"),
+            Self::Unknown(v) => write!(f, "Unknown({})", v),
+        }
+    }
+}
+
+impl From<i32> for NodeType {
+    fn from(v: i32) -> Self {
+        match v {
+            ibverbs::IBV_NODE_CA => Self::Ca,
+            ibverbs::IBV_NODE_SWITCH => Self::Switch,
+            ibverbs::IBV_NODE_ROUTER => Self::Router,
+            ibverbs::IBV_NODE_RNIC => Self::Rnic,
+            ibverbs::IBV_NODE_USNIC => Self::Usnic,
+            ibverbs::IBV_NODE_USNIC_UDP => Self::UsnicUdp,
+            ibverbs::IBV_NODE_UNSPECIFIED => Self::Unspecified,
+
+            _ => Self::Unknown(v),
+        }
+    }
+}
+
+/// The transport of a device, see `enum ibv_transport_type`; RoCE is an IB transport.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum Transport {
+    Ib,
+    Iwarp,
+    Usnic,
+    UsnicUdp,
+    Unspecified,
+    Unknown,
+}
+
+impl Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ib => f.write_str("IB"),
+            Self::Iwarp => f.write_str("iWARP"),
+            Self::Usnic => f.write_str("usNIC"),
+            Self::UsnicUdp => f.write_str("usNIC UDP"),
+            Self::Unspecified => f.write_str("Unspecified"),
+            Self::Unknown => f.write_str("Unknown"),
+        }
+    }
+}
+
+impl From<NodeType> for Transport {
+    /// The transport of a node type, as libibverbs derives it.
+    fn from(node_type: NodeType) -> Self {
+        match node_type {
+            NodeType::Ca | NodeType::Switch | NodeType::Router => Self::Ib,
+            NodeType::Rnic => Self::Iwarp,
+            NodeType::Usnic => Self::Usnic,
+            NodeType::UsnicUdp => Self::UsnicUdp,
+            NodeType::Unspecified => Self::Unspecified,
+            NodeType::Unknown(_) => Self::Unknown,
+        }
+    }
+}
+
+/// The limits and capabilities of a device, see `ibv_query_device(3)`.
+#[derive(Clone, Debug, Serialize)]
+pub struct IbDeviceCaps {
+    pub vendor_id: u32,
+    pub vendor_part_id: u32,
+    pub hw_ver: u32,
+    pub max_mr_size: u64,
+    pub page_size_cap: u64,
+    pub max_qp: u32,
+    pub max_qp_wr: u32,
+    pub max_sge: u32,
+    pub max_sge_rd: u32,
+    pub max_cq: u32,
+    pub max_cqe: u32,
+    pub max_mr: u32,
+    pub max_pd: u32,
+    pub max_qp_rd_atom: u32,
+    pub max_qp_init_rd_atom: u32,
+    pub max_res_rd_atom: u32,
+    pub atomic_cap: AtomicCap,
+    pub max_mw: u32,
+    pub max_mcast_grp: u32,
+    pub max_mcast_qp_attach: u32,
+    pub max_total_mcast_qp_attach: u32,
+    pub max_ah: u32,
+    pub max_srq: u32,
+    pub max_srq_wr: u32,
+    pub max_srq_sge: u32,
+    pub max_pkeys: u16,
+    pub local_ca_ack_delay: u8,
+    pub phys_port_cnt: u8,
+    pub device_cap_flags: DeviceCapabilities,
+}
+
+impl From<&ibv_device_attr> for IbDeviceCaps {
+    fn from(attr: &ibv_device_attr) -> Self {
+        Self {
+            vendor_id: attr.vendor_id,
+            vendor_part_id: attr.vendor_part_id,
+            hw_ver: attr.hw_ver,
+            max_mr_size: attr.max_mr_size,
+            page_size_cap: attr.page_size_cap,
+            max_qp: limit(attr.max_qp),
+            max_qp_wr: limit(attr.max_qp_wr),
+            max_sge: limit(attr.max_sge),
+            max_sge_rd: limit(attr.max_sge_rd),
+            max_cq: limit(attr.max_cq),
+            max_cqe: limit(attr.max_cqe),
+            max_mr: limit(attr.max_mr),
+            max_pd: limit(attr.max_pd),
+            max_qp_rd_atom: limit(attr.max_qp_rd_atom),
+            max_qp_init_rd_atom: limit(attr.max_qp_init_rd_atom),
+            max_res_rd_atom: limit(attr.max_res_rd_atom),
+            atomic_cap: AtomicCap::from(attr.atomic_cap),
+            max_mw: limit(attr.max_mw),
+            max_mcast_grp: limit(attr.max_mcast_grp),
+            max_mcast_qp_attach: limit(attr.max_mcast_qp_attach),
+            max_total_mcast_qp_attach: limit(attr.max_total_mcast_qp_attach),
+            max_ah: limit(attr.max_ah),
+            max_srq: limit(attr.max_srq),
+            max_srq_wr: limit(attr.max_srq_wr),
+            max_srq_sge: limit(attr.max_srq_sge),
+            max_pkeys: attr.max_pkeys,
+            local_ca_ack_delay: attr.local_ca_ack_delay,
+            phys_port_cnt: attr.phys_port_cnt,
+            device_cap_flags: DeviceCapabilities::from_bits_retain(attr.device_cap_flags),
+        }
+    }
+}
+
+/// A limit of a device, a C `int`; a negative value of a broken provider is taken as 0.
+fn limit(v: c_int) -> u32 {
+    u32::try_from(v).unwrap_or(0)
+}
+
+/// The extended capabilities of a device, see `ibv_query_device_ex(3)`, and the DC capabilities
+/// of mlx5, see `mlx5dv_query_device(3)`.
+#[derive(Clone, Debug, Serialize)]
+pub struct IbDeviceExtendedCaps {
+    /// Whether the device supports on-demand paging at all.
+    pub odp: bool,
+    /// Whether the device supports implicit on-demand paging, i.e. of the whole address space.
+    pub odp_implicit: bool,
+    pub rc_odp_caps: OdpCapabilities,
+    pub uc_odp_caps: OdpCapabilities,
+    pub ud_odp_caps: OdpCapabilities,
+    pub xrc_odp_caps: OdpCapabilities,
+    pub completion_timestamp_mask: u64,
+    /// The frequency of the clock of the completion timestamps, in kHz.
+    pub hca_core_clock: u64,
+    pub device_cap_flags_ex: u64,
+    pub max_tso: u32,
+    pub tso_supported_qpts: QpTypes,
+    pub rss_supported_qpts: QpTypes,
+    pub max_rwq_indirection_tables: u32,
+    pub max_rwq_indirection_table_size: u32,
+    pub rx_hash_fields_mask: u64,
+    pub rx_hash_function: u8,
+    pub max_wq_type_rq: u32,
+    /// The range of the rate limit of the packet pacing of a QP, in kbps.
+    pub qp_rate_limit_min: u32,
+    pub qp_rate_limit_max: u32,
+    pub packet_pacing_supported_qpts: QpTypes,
+    /// The size of the on-device memory, in bytes.
+    pub max_dm_size: u64,
+    /// The ODP capabilities of the DC transport of a mlx5 device; `None` for the other
+    /// providers, which have no DC transport, or if libhca is built without the mlx5 direct
+    /// verbs. No verbs report the max DC targets.
+    pub dc_odp_caps: Option<OdpCapabilities>,
+}
+
+impl IbDeviceExtendedCaps {
+    pub fn is_packet_pacing_supported(&self) -> bool {
+        self.qp_rate_limit_max > 0
+    }
+}
+
+impl From<&ibv_device_attr_ex> for IbDeviceExtendedCaps {
+    fn from(attr: &ibv_device_attr_ex) -> Self {
+        let odp = &attr.odp_caps;
+        Self {
+            odp: odp.general_caps & ibverbs::IBV_ODP_SUPPORT as u64 != 0,
+            odp_implicit: odp.general_caps & ibverbs::IBV_ODP_SUPPORT_IMPLICIT as u64 != 0,
+            rc_odp_caps: OdpCapabilities::from_bits_retain(odp.per_transport_caps.rc_odp_caps),
+            uc_odp_caps: OdpCapabilities::from_bits_retain(odp.per_transport_caps.uc_odp_caps),
+            ud_odp_caps: OdpCapabilities::from_bits_retain(odp.per_transport_caps.ud_odp_caps),
+            xrc_odp_caps: OdpCapabilities::from_bits_retain(attr.xrc_odp_caps),
+            completion_timestamp_mask: attr.completion_timestamp_mask,
+            hca_core_clock: attr.hca_core_clock,
+            device_cap_flags_ex: attr.device_cap_flags_ex,
+            max_tso: attr.tso_caps.max_tso,
+            tso_supported_qpts: QpTypes::from_bits_retain(attr.tso_caps.supported_qpts),
+            rss_supported_qpts: QpTypes::from_bits_retain(attr.rss_caps.supported_qpts),
+            max_rwq_indirection_tables: attr.rss_caps.max_rwq_indirection_tables,
+            max_rwq_indirection_table_size: attr.rss_caps.max_rwq_indirection_table_size,
+            rx_hash_fields_mask: attr.rss_caps.rx_hash_fields_mask,
+            rx_hash_function: attr.rss_caps.rx_hash_function,
+            max_wq_type_rq: attr.max_wq_type_rq,
+            qp_rate_limit_min: attr.packet_pacing_caps.qp_rate_limit_min,
+            qp_rate_limit_max: attr.packet_pacing_caps.qp_rate_limit_max,
+            packet_pacing_supported_qpts: QpTypes::from_bits_retain(
+                attr.packet_pacing_caps.supported_qpts,
+            ),
+            max_dm_size: attr.max_dm_size,
+            // Not in the attributes of any provider, see `query_dc_odp_caps`.
+            dc_odp_caps: None,
+        }
+    }
+}
+
+/// The atomic operations support of a device, see `enum ibv_atomic_cap`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum AtomicCap {
+    None,
+    Hca,
+    Glob,
+    Unknown(u32),
+}
+
+impl AtomicCap {
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::None => "atomic operations are not supported",
+            Self::Hca => "atomic operations are atomic within the device only",
+            Self::Glob => "atomic operations are atomic with the CPU and other devices",
+            Self::Unknown(_) => "unknown atomic operations support",
+        }
+    }
+}
+
+impl Display for AtomicCap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => f.write_str("NONE"),
+            Self::Hca => f.write_str("HCA"),
+            Self::Glob => f.write_str("GLOB"),
+            Self::Unknown(v) => write!(f, "Unknown({})", v),
+        }
+    }
+}
+
+impl From<u32> for AtomicCap {
+    fn from(v: u32) -> Self {
+        match v {
+            ibverbs::IBV_ATOMIC_NONE => Self::None,
+            ibverbs::IBV_ATOMIC_HCA => Self::Hca,
+            ibverbs::IBV_ATOMIC_GLOB => Self::Glob,
+
+            _ => Self::Unknown(v),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
 pub enum IbPortLinkType {
     Ethernet,
     Infiniband,
 }
 
 impl TryFrom<u8> for IbPortLinkType {
-    type Error = io::Error;
-    fn try_from(v: u8) -> io::Result<Self> {
+    type Error = HcaError;
+    /// The link layer of `ibv_port_attr`; `IBV_LINK_LAYER_UNSPECIFIED` of the providers which
+    /// do not set it, e.g. older ones, is taken as InfiniBand, as libibverbs does.
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
         match v {
-            1 => Ok(Self::Infiniband),
+            0 | 1 => Ok(Self::Infiniband),
             2 => Ok(Self::Ethernet),
-            _ => Err(io::Error::last_os_error()),
+            _ => Err(HcaError::UnknownValue {
+                kind: "port link layer",
+                value: v as u32,
+            }),
         }
     }
 }
@@ -110,67 +584,502 @@ impl Display for IbPortLinkType {
     }
 }
 
-#[derive(Clone)]
+/// The logical state of a port, see `enum ibv_port_state`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
 pub enum IbPortState {
+    Nop,
+    Down,
     Initializing,
+    Armed,
     Active,
-    Down,
+    ActiveDefer,
+    Unknown(u32),
+}
+
+impl IbPortState {
+    /// A short explanation of what the state usually means.
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Nop => "no state change",
+            Self::Down => "logical link is down, check the physical state",
+            Self::Initializing => "link is up, but not configured by a subnet manager yet",
+            Self::Armed => "configured by the subnet manager, waiting to become active",
+            Self::Active => "ready to transmit and receive data",
+            Self::ActiveDefer => "active, but recovering from a transient link error",
+            Self::Unknown(_) => "unknown state reported by the device",
+        }
+    }
+
+    /// The numeric value of the state, as `enum ibv_port_state`, e.g. `4` for `Active`.
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::Nop => ibverbs::ibv_port_state::IBV_PORT_NOP,
+            Self::Down => ibverbs::ibv_port_state::IBV_PORT_DOWN,
+            Self::Initializing => ibverbs::ibv_port_state::IBV_PORT_INIT,
+            Self::Armed => ibverbs::ibv_port_state::IBV_PORT_ARMED,
+            Self::Active => ibverbs::ibv_port_state::IBV_PORT_ACTIVE,
+            Self::ActiveDefer => ibverbs::ibv_port_state::IBV_PORT_ACTIVE_DEFER,
+            Self::Unknown(v) => *v,
+        }
+    }
 }
 
 impl Display for IbPortState {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Self::Nop => write!(f, "Nop"),
+            Self::Down => write!(f, "Down"),
             Self::Initializing => write!(f, "Initializing"),
+            Self::Armed => write!(f, "Armed"),
             Self::Active => write!(f, "Active"),
-            Self::Down => write!(f, "Down"),
+            Self::ActiveDefer => write!(f, "ActiveDefer"),
+            Self::Unknown(v) => write!(f, "Unknown({})", v),
         }
     }
 }
 
-impl TryFrom<u32> for IbPortState {
-    type Error = io::Error;
-    fn try_from(v: u32) -> io::Result<Self> {
+impl From<u32> for IbPortState {
+    fn from(v: u32) -> Self {
         match v {
-            ibverbs::ibv_port_state::IBV_PORT_INIT => Ok(Self::Initializing),
-            ibverbs::ibv_port_state::IBV_PORT_ACTIVE => Ok(Self::Active),
-            ibverbs::ibv_port_state::IBV_PORT_DOWN => Ok(Self::Down),
+            ibverbs::ibv_port_state::IBV_PORT_NOP => Self::Nop,
+            ibverbs::ibv_port_state::IBV_PORT_DOWN => Self::Down,
+            ibverbs::ibv_port_state::IBV_PORT_INIT => Self::Initializing,
+            ibverbs::ibv_port_state::IBV_PORT_ARMED => Self::Armed,
+            ibverbs::ibv_port_state::IBV_PORT_ACTIVE => Self::Active,
+            ibverbs::ibv_port_state::IBV_PORT_ACTIVE_DEFER => Self::ActiveDefer,
 
-            _ => Err(io::Error::last_os_error()),
+            _ => Self::Unknown(v),
         }
     }
 }
 
-#[derive(Clone)]
+/// The physical state of a port, as the `PortPhysicalState` of the IBTA `PortInfo`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
 pub enum IbPortPhysState {
+    Sleep,
     Polling,
-    LinkUp,
     Disabled,
+    PortConfigurationTraining,
+    LinkUp,
+    LinkErrorRecovery,
+    PhyTest,
+    Unknown(u8),
+}
+
+impl IbPortPhysState {
+    /// A short explanation of what the state usually means.
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Sleep => "port is asleep",
+            Self::Polling => "no cable, or the peer port is down",
+            Self::Disabled => "port is disabled, e.g. by the administrator or the subnet manager",
+            Self::PortConfigurationTraining => "link is training with the peer port",
+            Self::LinkUp => "physical link is up",
+            Self::LinkErrorRecovery => "link is recovering from errors, check the cable",
+            Self::PhyTest => "port is in the physical test mode",
+            Self::Unknown(_) => "unknown physical state reported by the device",
+        }
+    }
+
+    /// The numeric value of the state, e.g. `5` for `LinkUp`.
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Sleep => 1,
+            Self::Polling => 2,
+            Self::Disabled => 3,
+            Self::PortConfigurationTraining => 4,
+            Self::LinkUp => 5,
+            Self::LinkErrorRecovery => 6,
+            Self::PhyTest => 7,
+            Self::Unknown(v) => *v,
+        }
+    }
 }
 
 impl Display for IbPortPhysState {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Self::Sleep => f.write_str("Sleep"),
             Self::Polling => f.write_str("Polling"),
-            Self::LinkUp => f.write_str("LinkUp"),
             Self::Disabled => f.write_str("Disabled"),
+            Self::PortConfigurationTraining => f.write_str("PortConfigurationTraining"),
+            Self::LinkUp => f.write_str("LinkUp"),
+            Self::LinkErrorRecovery => f.write_str("LinkErrorRecovery"),
+            Self::PhyTest => f.write_str("PhyTest"),
+            Self::Unknown(v) => write!(f, "Unknown({})", v),
+        }
+    }
+}
+
+impl From<u8> for IbPortPhysState {
+    fn from(v: u8) -> Self {
+        match v {
+            1 => Self::Sleep,
+            2 => Self::Polling,
+            3 => Self::Disabled,
+            4 => Self::PortConfigurationTraining,
+            5 => Self::LinkUp,
+            6 => Self::LinkErrorRecovery,
+            7 => Self::PhyTest,
+
+            _ => Self::Unknown(v),
+        }
+    }
+}
+
+/// The active link width of a port, see `ibv_port_attr::active_width`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum IbPortWidth {
+    X1,
+    X2,
+    X4,
+    X8,
+    X12,
+    Unknown(u8),
+}
+
+impl IbPortWidth {
+    /// The number of lanes of the link.
+    pub fn lanes(&self) -> u32 {
+        match self {
+            Self::X1 => 1,
+            Self::X2 => 2,
+            Self::X4 => 4,
+            Self::X8 => 8,
+            Self::X12 => 12,
+            Self::Unknown(_) => 0,
+        }
+    }
+}
+
+impl Display for IbPortWidth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown(v) => write!(f, "Unknown({})", v),
+            w => write!(f, "{}x", w.lanes()),
+        }
+    }
+}
+
+impl From<u8> for IbPortWidth {
+    fn from(v: u8) -> Self {
+        match v {
+            1 => Self::X1,
+            2 => Self::X4,
+            4 => Self::X8,
+            8 => Self::X12,
+            16 => Self::X2,
+
+            _ => Self::Unknown(v),
         }
     }
 }
 
-impl TryFrom<u8> for IbPortPhysState {
-    type Error = io::Error;
-    fn try_from(v: u8) -> io::Result<Self> {
+/// The active per-lane speed of a port, see `ibv_port_attr::active_speed` and
+/// `ibv_port_attr::active_speed_ex`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum IbPortSpeed {
+    Sdr,
+    Ddr,
+    Qdr,
+    Fdr10,
+    Fdr,
+    Edr,
+    Hdr,
+    Ndr,
+    Xdr,
+    Unknown(u32),
+}
+
+impl IbPortSpeed {
+    /// The nominal rate of one lane in Gb/s, as reported by the kernel and `ibstat`.
+    pub fn lane_rate(&self) -> f64 {
+        match self {
+            Self::Sdr => 2.5,
+            Self::Ddr => 5.0,
+            Self::Qdr => 10.0,
+            Self::Fdr10 => 10.0,
+            Self::Fdr => 14.0,
+            Self::Edr => 25.0,
+            Self::Hdr => 50.0,
+            Self::Ndr => 100.0,
+            Self::Xdr => 200.0,
+            Self::Unknown(_) => 0.0,
+        }
+    }
+}
+
+impl Display for IbPortSpeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sdr => f.write_str("SDR"),
+            Self::Ddr => f.write_str("DDR"),
+            Self::Qdr => f.write_str("QDR"),
+            Self::Fdr10 => f.write_str("FDR10"),
+            Self::Fdr => f.write_str("FDR"),
+            Self::Edr => f.write_str("EDR"),
+            Self::Hdr => f.write_str("HDR"),
+            Self::Ndr => f.write_str("NDR"),
+            Self::Xdr => f.write_str("XDR"),
+            Self::Unknown(v) => write!(f, "Unknown({})", v),
+        }
+    }
+}
+
+impl From<u32> for IbPortSpeed {
+    fn from(v: u32) -> Self {
         match v {
-            2 => Ok(Self::Polling),
-            3 => Ok(Self::Disabled),
-            5 => Ok(Self::LinkUp),
+            1 => Self::Sdr,
+            2 => Self::Ddr,
+            4 => Self::Qdr,
+            8 => Self::Fdr10,
+            16 => Self::Fdr,
+            32 => Self::Edr,
+            64 => Self::Hdr,
+            128 => Self::Ndr,
+            256 => Self::Xdr,
 
-            _ => Err(io::Error::last_os_error()),
+            _ => Self::Unknown(v),
         }
     }
 }
 
-#[derive(Clone)]
+/// The MTU of a port, see `enum ibv_mtu`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum IbMtu {
+    Mtu256,
+    Mtu512,
+    Mtu1024,
+    Mtu2048,
+    Mtu4096,
+    Unknown(u32),
+}
+
+impl IbMtu {
+    /// The largest MTU which fits in the given bytes, e.g. 1024 of 1400; `None` below 256.
+    pub fn fitting(bytes: u32) -> Option<Self> {
+        [
+            Self::Mtu4096,
+            Self::Mtu2048,
+            Self::Mtu1024,
+            Self::Mtu512,
+            Self::Mtu256,
+        ]
+        .into_iter()
+        .find(|mtu| mtu.bytes() <= bytes)
+    }
+
+    /// The MTU in bytes, or 0 if it is unknown.
+    pub fn bytes(&self) -> u32 {
+        match self {
+            Self::Mtu256 => 256,
+            Self::Mtu512 => 512,
+            Self::Mtu1024 => 1024,
+            Self::Mtu2048 => 2048,
+            Self::Mtu4096 => 4096,
+            Self::Unknown(_) => 0,
+        }
+    }
+}
+
+impl Display for IbMtu {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown(v) => write!(f, "Unknown({})", v),
+            mtu => write!(f, "{}", mtu.bytes()),
+        }
+    }
+}
+
+impl From<u32> for IbMtu {
+    fn from(v: u32) -> Self {
+        match v {
+            ibverbs::IBV_MTU_256 => Self::Mtu256,
+            ibverbs::IBV_MTU_512 => Self::Mtu512,
+            ibverbs::IBV_MTU_1024 => Self::Mtu1024,
+            ibverbs::IBV_MTU_2048 => Self::Mtu2048,
+            ibverbs::IBV_MTU_4096 => Self::Mtu4096,
+
+            _ => Self::Unknown(v),
+        }
+    }
+}
+
+/// A GID of a port, i.e. a 128-bit address shown in the IPv6 style.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Gid(pub [u8; 16]);
+
+impl Gid {
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|b| *b == 0)
+    }
+
+    pub fn subnet_prefix(&self) -> u64 {
+        u64::from_be_bytes(self.0[..8].try_into().unwrap_or_default())
+    }
+
+    pub fn interface_id(&self) -> u64 {
+        u64::from_be_bytes(self.0[8..].try_into().unwrap_or_default())
+    }
+
+    /// The IP address of a RoCE GID; an IPv4-mapped GID, e.g. `::ffff:10.0.0.5`, gives the IPv4 address.
+    pub fn ip_addr(&self) -> IpAddr {
+        let addr = Ipv6Addr::from(self.0);
+        match addr.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(addr),
+        }
+    }
+}
+
+impl Display for Gid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let groups: Vec<String> = self
+            .0
+            .chunks(2)
+            .map(|c| format!("{:02x}{:02x}", c[0], c[1]))
+            .collect();
+        f.write_str(&groups.join(":"))
+    }
+}
+
+impl FromStr for Gid {
+    type Err = HcaError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let addr = Ipv6Addr::from_str(s).map_err(|_| HcaError::InvalidValue {
+            device: "-".to_string(),
+            name: "gid".to_string(),
+            value: s.to_string(),
+        })?;
+        Ok(Self(addr.octets()))
+    }
+}
+
+impl Serialize for Gid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// The type of a GID, see `enum ibv_gid_type`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum GidType {
+    Ib,
+    RoceV1,
+    RoceV2,
+    Unknown(u32),
+}
+
+impl Display for GidType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ib => f.write_str("IB"),
+            Self::RoceV1 => f.write_str("RoCE v1"),
+            Self::RoceV2 => f.write_str("RoCE v2"),
+            Self::Unknown(v) => write!(f, "Unknown({})", v),
+        }
+    }
+}
+
+impl From<u32> for GidType {
+    fn from(v: u32) -> Self {
+        match v {
+            ibverbs::IBV_GID_TYPE_IB => Self::Ib,
+            ibverbs::IBV_GID_TYPE_ROCE_V1 => Self::RoceV1,
+            ibverbs::IBV_GID_TYPE_ROCE_V2 => Self::RoceV2,
+
+            _ => Self::Unknown(v),
+        }
+    }
+}
+
+/// A valid entry of the GID table of a port.
+#[derive(Clone, Serialize)]
+pub struct GidEntry {
+    pub index: u32,
+    pub gid: Gid,
+    pub gid_type: GidType,
+    /// The network device of a RoCE GID, e.g. `ens1f0`.
+    pub netdev: Option<String>,
+}
+
+/// A partition key; the high bit is the membership and the low 15 bits are the partition.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PKey(pub u16);
+
+/// The partition of the default P_Key, `0xffff` or `0x7fff`.
+pub const DEFAULT_PARTITION: u16 = 0x7fff;
+
+impl PKey {
+    pub fn partition(&self) -> u16 {
+        self.0 & 0x7fff
+    }
+
+    pub fn membership(&self) -> PKeyMembership {
+        match self.0 & 0x8000 {
+            0 => PKeyMembership::Limited,
+            _ => PKeyMembership::Full,
+        }
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.partition() == DEFAULT_PARTITION
+    }
+
+    /// An entry of a P_Key table is invalid if its partition is 0.
+    pub fn is_valid(&self) -> bool {
+        self.partition() != 0
+    }
+}
+
+impl Display for PKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#06x}", self.0)
+    }
+}
+
+impl Serialize for PKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum PKeyMembership {
+    Full,
+    Limited,
+}
+
+impl Display for PKeyMembership {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full => f.write_str("full"),
+            Self::Limited => f.write_str("limited"),
+        }
+    }
+}
+
+/// A valid entry of the P_Key table of a port.
+#[derive(Clone, Serialize)]
+pub struct PKeyEntry {
+    pub index: u16,
+    pub pkey: PKey,
+    pub membership: PKeyMembership,
+    pub is_default: bool,
+}
+
+impl PKeyEntry {
+    pub fn new(index: u16, pkey: PKey) -> Self {
+        Self {
+            index,
+            pkey,
+            membership: pkey.membership(),
+            is_default: pkey.is_default(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
 pub struct IbPort {
     pub port_num: u8,
     pub guid: Option<String>,
@@ -178,6 +1087,134 @@ pub struct IbPort {
     pub link_type: IbPortLinkType,
     pub state: IbPortState,
     pub phys_state: IbPortPhysState,
+    pub width: IbPortWidth,
+    pub speed: IbPortSpeed,
+    /// The speeds the port supports, from its PortInfo; empty if it is unknown, e.g. the umad
+    /// device of the port is only accessible by root, or from sysfs.
+    pub supported_speeds: Vec<IbPortSpeed>,
+    /// The data rate of the port in Gb/s, i.e. the lane rate of `speed` times the lanes of `width`.
+    pub rate: f64,
+    pub max_mtu: IbMtu,
+    pub active_mtu: IbMtu,
+    pub sm_lid: u16,
+    pub sm_sl: u8,
+    pub lmc: u8,
+    /// The subnet timeout, i.e. 4.096us * 2^subnet_timeout; `None` if it is unknown, e.g. from
+    /// sysfs.
+    pub subnet_timeout: Option<u8>,
+    /// The number of VLs; `None` if it is unknown, e.g. from sysfs.
+    pub max_vl_num: Option<u8>,
+    pub gid_tbl_len: u32,
+    pub pkey_tbl_len: u16,
+    pub capabilities: PortCapabilities,
+    pub gids: Vec<GidEntry>,
+    pub pkeys: Vec<PKeyEntry>,
+    /// The network interfaces of the port, e.g. `ens1f0` of RoCE or `ib0` of IPoIB.
+    pub netdevs: Vec<NetDev>,
+}
+
+impl IbPort {
+    /// The data rate in Gb/s of a port with the given width and speed.
+    pub fn data_rate(width: IbPortWidth, speed: IbPortSpeed) -> f64 {
+        width.lanes() as f64 * speed.lane_rate()
+    }
+
+    /// Whether a subnet manager was reachable when the port was configured; an InfiniBand port
+    /// without subnet manager has no `sm_lid`. It is always true for Ethernet ports.
+    pub fn is_sm_reachable(&self) -> bool {
+        match self.link_type {
+            IbPortLinkType::Ethernet => true,
+            IbPortLinkType::Infiniband => self.sm_lid != 0,
+        }
+    }
+
+    /// The highest speed the port supports: the highest of its supported speeds and of the
+    /// speeds from HDR on in its capabilities; `None` if both are unknown.
+    pub fn max_supported_speed(&self) -> Option<IbPortSpeed> {
+        let caps = self.capabilities;
+        let cap_speeds = [
+            (PortCapabilities::LINK_SPEED_HDR_SUP, IbPortSpeed::Hdr),
+            (PortCapabilities::LINK_SPEED_NDR_SUP, IbPortSpeed::Ndr),
+            (PortCapabilities::LINK_SPEED_XDR_SUP, IbPortSpeed::Xdr),
+        ]
+        .into_iter()
+        .filter(|(cap, _)| caps.contains(*cap))
+        .map(|(_, speed)| speed);
+
+        self.supported_speeds
+            .iter()
+            .copied()
+            .chain(cap_speeds)
+            .max_by(|a, b| a.lane_rate().total_cmp(&b.lane_rate()))
+    }
+
+    /// The lowest data rate the port is known to support, i.e. its highest supported speed,
+    /// see [`IbPort::max_supported_speed`], at 4X, the width of the HCA ports; `None` if it is
+    /// unknown, e.g. for an Ethernet port.
+    pub fn supported_rate(&self) -> Option<f64> {
+        if self.link_type != IbPortLinkType::Infiniband {
+            return None;
+        }
+
+        let speed = self.max_supported_speed()?;
+        let width = match self.width.lanes() > IbPortWidth::X4.lanes() {
+            true => self.width,
+            false => IbPortWidth::X4,
+        };
+
+        // A port is never expected below what it runs at, e.g. FDR10 of a port of QDR.
+        Some(Self::data_rate(width, speed).max(self.rate))
+    }
+
+    /// The subnet timeout in microseconds.
+    pub fn subnet_timeout_us(&self) -> Option<f64> {
+        self.subnet_timeout
+            .map(|timeout| 4.096 * 2f64.powi(timeout as i32))
+    }
+}
+
+/// The HCAs found by a discovery, and the problems of the devices and ports which were skipped.
+#[derive(Clone, Serialize)]
+pub struct Discovery {
+    pub pci_devices: Vec<PciDevice>,
+    pub warnings: Vec<DiscoveryWarning>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct DiscoveryWarning {
+    pub device: String,
+    pub port_num: Option<u8>,
+    pub message: String,
+}
+
+impl DiscoveryWarning {
+    pub fn new(device: &str, e: HcaError) -> Self {
+        match e {
+            HcaError::Port {
+                device,
+                port_num,
+                error,
+            } => Self {
+                device,
+                port_num: Some(port_num),
+                message: error.to_string(),
+            },
+            e => Self {
+                device: device.to_string(),
+                port_num: None,
+                message: e.to_string(),
+            },
+        }
+    }
+}
+
+impl Display for DiscoveryWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port_num {
+            Some(port_num) => write!(f, "{} port {}: {}", self.device, port_num, self.message),
+            None => write!(f, "{}: {}", self.device, self.message),
+        }
+    }
 }
 
 #[allow(missing_copy_implementations)] // This type can not copy
@@ -199,3 +1236,311 @@ impl DeviceAttrPtr {
         self.0.as_ptr()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ib_port(port_num: u8, width: IbPortWidth, speed: IbPortSpeed, caps: u64) -> IbPort {
+        IbPort {
+            port_num,
+            guid: None,
+            lid: 1,
+            link_type: IbPortLinkType::Infiniband,
+            state: IbPortState::Active,
+            phys_state: IbPortPhysState::LinkUp,
+            width,
+            speed,
+            supported_speeds: vec![],
+            rate: IbPort::data_rate(width, speed),
+            max_mtu: IbMtu::Mtu4096,
+            active_mtu: IbMtu::Mtu4096,
+            sm_lid: 1,
+            sm_sl: 0,
+            lmc: 0,
+            subnet_timeout: Some(18),
+            max_vl_num: Some(4),
+            gid_tbl_len: 0,
+            pkey_tbl_len: 0,
+            capabilities: PortCapabilities::from_bits_retain(caps),
+            gids: vec![],
+            pkeys: vec![],
+            netdevs: vec![],
+        }
+    }
+
+    fn adapter(ports: Vec<IbPort>) -> PciDevice {
+        let ib_dev = IbDevice {
+            name: "mlx5_0".to_string(),
+            slot_name: "0000:b1:00.0".to_string(),
+            driver: Some("mlx5_core".to_string()),
+            node_type: NodeType::Ca,
+            transport: Transport::Ib,
+            node_guid: None,
+            node_desc: None,
+            sys_image_guid: None,
+            fw_ver: None,
+            board_id: None,
+            caps: None,
+            ext_caps: None,
+            sriov: None,
+            physfn: None,
+            vf_index: None,
+            ib_ports: ports,
+        };
+        let mut hca = PciDevice::software(&ib_dev);
+        hca.ib_devices.push(ib_dev);
+
+        hca
+    }
+
+    #[test]
+    fn decode_widths() {
+        assert_eq!(IbPortWidth::from(1), IbPortWidth::X1);
+        assert_eq!(IbPortWidth::from(2), IbPortWidth::X4);
+        assert_eq!(IbPortWidth::from(4), IbPortWidth::X8);
+        assert_eq!(IbPortWidth::from(8), IbPortWidth::X12);
+        assert_eq!(IbPortWidth::from(16), IbPortWidth::X2);
+        assert_eq!(IbPortWidth::from(3), IbPortWidth::Unknown(3));
+        assert_eq!(IbPortWidth::X12.lanes(), 12);
+    }
+
+    #[test]
+    fn decode_speeds() {
+        assert_eq!(IbPortSpeed::from(1), IbPortSpeed::Sdr);
+        assert_eq!(IbPortSpeed::from(8), IbPortSpeed::Fdr10);
+        assert_eq!(IbPortSpeed::from(16), IbPortSpeed::Fdr);
+        assert_eq!(IbPortSpeed::from(32), IbPortSpeed::Edr);
+        assert_eq!(IbPortSpeed::from(64), IbPortSpeed::Hdr);
+        assert_eq!(IbPortSpeed::from(128), IbPortSpeed::Ndr);
+        // Only in active_speed_ex, it does not fit the 8 bits of active_speed.
+        assert_eq!(IbPortSpeed::from(256), IbPortSpeed::Xdr);
+        assert_eq!(IbPortSpeed::from(3), IbPortSpeed::Unknown(3));
+    }
+
+    #[test]
+    fn data_rates() {
+        assert_eq!(IbPort::data_rate(IbPortWidth::X4, IbPortSpeed::Edr), 100.0);
+        assert_eq!(IbPort::data_rate(IbPortWidth::X4, IbPortSpeed::Hdr), 200.0);
+        assert_eq!(IbPort::data_rate(IbPortWidth::X4, IbPortSpeed::Xdr), 800.0);
+        assert_eq!(IbPort::data_rate(IbPortWidth::X1, IbPortSpeed::Sdr), 2.5);
+        assert_eq!(
+            IbPort::data_rate(IbPortWidth::Unknown(0), IbPortSpeed::Edr),
+            0.0
+        );
+    }
+
+    #[test]
+    fn supported_rates() {
+        let hdr = PortCapabilities::LINK_SPEED_HDR_SUP | PortCapabilities::EXTENDED_SPEEDS_SUP;
+
+        let port = ib_port(1, IbPortWidth::X1, IbPortSpeed::Sdr, hdr.bits());
+        assert_eq!(port.supported_rate(), Some(200.0));
+
+        let port = ib_port(
+            1,
+            IbPortWidth::X4,
+            IbPortSpeed::Ndr,
+            PortCapabilities::LINK_SPEED_NDR_SUP.bits(),
+        );
+        assert_eq!(port.supported_rate(), Some(400.0));
+
+        // The extended speeds are FDR or EDR, so the speeds of the PortInfo are needed.
+        let ext = PortCapabilities::EXTENDED_SPEEDS_SUP.bits();
+        let mut port = ib_port(1, IbPortWidth::X4, IbPortSpeed::Fdr, ext);
+        assert_eq!(port.max_supported_speed(), None);
+        assert_eq!(port.supported_rate(), None);
+        port.supported_speeds = vec![IbPortSpeed::Sdr, IbPortSpeed::Fdr, IbPortSpeed::Edr];
+        assert_eq!(port.max_supported_speed(), Some(IbPortSpeed::Edr));
+        assert_eq!(port.supported_rate(), Some(100.0));
+
+        // The capabilities of HDR on are combined with the PortInfo.
+        let mut port = ib_port(1, IbPortWidth::X4, IbPortSpeed::Hdr, hdr.bits());
+        port.supported_speeds = vec![IbPortSpeed::Fdr, IbPortSpeed::Edr];
+        assert_eq!(port.max_supported_speed(), Some(IbPortSpeed::Hdr));
+
+        let mut port = ib_port(1, IbPortWidth::X4, IbPortSpeed::Qdr, 0);
+        assert_eq!(port.supported_rate(), None);
+        port.supported_speeds = vec![IbPortSpeed::Sdr, IbPortSpeed::Ddr, IbPortSpeed::Qdr];
+        assert_eq!(port.supported_rate(), Some(40.0));
+
+        // FDR10 is not in the PortInfo, so a port of QDR may run faster.
+        let mut port = ib_port(1, IbPortWidth::X4, IbPortSpeed::Fdr10, 0);
+        port.supported_speeds = vec![IbPortSpeed::Qdr];
+        assert_eq!(port.supported_rate(), Some(40.0));
+
+        let mut port = ib_port(1, IbPortWidth::X4, IbPortSpeed::Edr, hdr.bits());
+        port.link_type = IbPortLinkType::Ethernet;
+        assert_eq!(port.supported_rate(), None);
+    }
+
+    #[test]
+    fn degraded_ports() {
+        let hdr =
+            (PortCapabilities::LINK_SPEED_HDR_SUP | PortCapabilities::EXTENDED_SPEEDS_SUP).bits();
+
+        // Both ports of an HDR adapter at 1X SDR.
+        let hca = adapter(vec![
+            ib_port(1, IbPortWidth::X1, IbPortSpeed::Sdr, hdr),
+            ib_port(2, IbPortWidth::X1, IbPortSpeed::Sdr, hdr),
+        ]);
+        for port in &hca.ib_devices[0].ib_ports {
+            assert!(hca.is_port_degraded(port));
+            assert_eq!(hca.expected_port_rate(port), Some(200.0));
+        }
+
+        let hca = adapter(vec![ib_port(1, IbPortWidth::X4, IbPortSpeed::Hdr, hdr)]);
+        assert!(!hca.is_port_degraded(&hca.ib_devices[0].ib_ports[0]));
+
+        // An EDR port which trained at FDR, e.g. with an FDR cable.
+        let edr = [
+            IbPortSpeed::Sdr,
+            IbPortSpeed::Qdr,
+            IbPortSpeed::Fdr,
+            IbPortSpeed::Edr,
+        ];
+        let ext = PortCapabilities::EXTENDED_SPEEDS_SUP.bits();
+        let mut port = ib_port(1, IbPortWidth::X4, IbPortSpeed::Fdr, ext);
+        port.supported_speeds = edr.to_vec();
+        let hca = adapter(vec![port]);
+        assert!(hca.is_port_degraded(&hca.ib_devices[0].ib_ports[0]));
+        assert_eq!(
+            hca.expected_port_rate(&hca.ib_devices[0].ib_ports[0]),
+            Some(100.0)
+        );
+
+        let mut port = ib_port(1, IbPortWidth::X4, IbPortSpeed::Edr, ext);
+        port.supported_speeds = edr.to_vec();
+        let hca = adapter(vec![port]);
+        assert!(!hca.is_port_degraded(&hca.ib_devices[0].ib_ports[0]));
+
+        // Without the supported speeds, the ports are compared with each other.
+        let hca = adapter(vec![
+            ib_port(1, IbPortWidth::X4, IbPortSpeed::Qdr, 0),
+            ib_port(2, IbPortWidth::X1, IbPortSpeed::Qdr, 0),
+        ]);
+        let ports = &hca.ib_devices[0].ib_ports;
+        assert!(!hca.is_port_degraded(&ports[0]));
+        assert!(hca.is_port_degraded(&ports[1]));
+
+        // A port whose link is down is not degraded.
+        let mut port = ib_port(1, IbPortWidth::X1, IbPortSpeed::Sdr, hdr);
+        port.phys_state = IbPortPhysState::Polling;
+        let hca = adapter(vec![port]);
+        assert!(!hca.is_port_degraded(&hca.ib_devices[0].ib_ports[0]));
+    }
+
+    #[test]
+    fn display_gids() {
+        let gid: Gid = "fe80::1070:fd03:17:660d".parse().unwrap();
+        assert_eq!(gid.to_string(), "fe80:0000:0000:0000:1070:fd03:0017:660d");
+        assert_eq!(gid.subnet_prefix(), 0xfe80_0000_0000_0000);
+        assert_eq!(gid.interface_id(), 0x1070_fd03_0017_660d);
+        assert_eq!(
+            gid.ip_addr(),
+            "fe80::1070:fd03:17:660d".parse::<IpAddr>().unwrap()
+        );
+        assert!(!gid.is_zero());
+
+        // The RoCE GID of an IPv4 address.
+        let gid: Gid = "::ffff:10.0.0.5".parse().unwrap();
+        assert_eq!(gid.to_string(), "0000:0000:0000:0000:0000:ffff:0a00:0005");
+        assert_eq!(gid.ip_addr(), "10.0.0.5".parse::<IpAddr>().unwrap());
+
+        // The sysfs attribute is in the same format as the Display.
+        let gid: Gid = "0000:0000:0000:0000:0000:0000:0000:0000".parse().unwrap();
+        assert!(gid.is_zero());
+
+        assert!("fe80::1::2".parse::<Gid>().is_err());
+        assert!("10.0.0.5".parse::<Gid>().is_err());
+    }
+
+    #[test]
+    fn gid_types() {
+        assert_eq!(GidType::from(ibverbs::IBV_GID_TYPE_IB), GidType::Ib);
+        assert_eq!(
+            GidType::from(ibverbs::IBV_GID_TYPE_ROCE_V1),
+            GidType::RoceV1
+        );
+        assert_eq!(
+            GidType::from(ibverbs::IBV_GID_TYPE_ROCE_V2),
+            GidType::RoceV2
+        );
+        assert_eq!(GidType::from(7), GidType::Unknown(7));
+        assert_eq!(GidType::RoceV2.to_string(), "RoCE v2");
+        assert_eq!(GidType::Unknown(7).to_string(), "Unknown(7)");
+    }
+
+    #[test]
+    fn fitting_mtus() {
+        assert_eq!(IbMtu::fitting(9000), Some(IbMtu::Mtu4096));
+        assert_eq!(IbMtu::fitting(4096), Some(IbMtu::Mtu4096));
+        assert_eq!(IbMtu::fitting(4095), Some(IbMtu::Mtu2048));
+        assert_eq!(IbMtu::fitting(1400), Some(IbMtu::Mtu1024));
+        assert_eq!(IbMtu::fitting(600), Some(IbMtu::Mtu512));
+        assert_eq!(IbMtu::fitting(256), Some(IbMtu::Mtu256));
+        assert_eq!(IbMtu::fitting(255), None);
+        assert_eq!(IbMtu::fitting(0), None);
+    }
+
+    #[test]
+    fn link_layers() {
+        assert_eq!(
+            IbPortLinkType::try_from(0).unwrap(),
+            IbPortLinkType::Infiniband
+        );
+        assert_eq!(
+            IbPortLinkType::try_from(1).unwrap(),
+            IbPortLinkType::Infiniband
+        );
+        assert_eq!(
+            IbPortLinkType::try_from(2).unwrap(),
+            IbPortLinkType::Ethernet
+        );
+        assert!(matches!(
+            IbPortLinkType::try_from(3),
+            Err(HcaError::UnknownValue { value: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn pkey_membership() {
+        let full = PKey(0xffff);
+        assert_eq!(full.membership(), PKeyMembership::Full);
+        assert_eq!(full.partition(), DEFAULT_PARTITION);
+        assert!(full.is_default() && full.is_valid());
+        assert_eq!(full.to_string(), "0xffff");
+
+        let limited = PKey(0x7fff);
+        assert_eq!(limited.membership(), PKeyMembership::Limited);
+        assert!(limited.is_default() && limited.is_valid());
+
+        let pkey = PKey(0x8001);
+        assert_eq!(pkey.membership(), PKeyMembership::Full);
+        assert_eq!(pkey.partition(), 1);
+        assert!(!pkey.is_default());
+        assert_eq!(PKey(0x0012).to_string(), "0x0012");
+
+        // Partition 0 is an empty entry of the table, whatever the membership.
+        assert!(!PKey(0x0000).is_valid());
+        assert!(!PKey(0x8000).is_valid());
+
+        let entry = PKeyEntry::new(1, PKey(0x7fff));
+        assert_eq!(entry.membership, PKeyMembership::Limited);
+        assert!(entry.is_default);
+    }
+
+    #[test]
+    fn negative_limits() {
+        let attr = ibv_device_attr {
+            max_qp: 131072,
+            max_cqe: -1,
+            max_srq_sge: i32::MIN,
+            ..Default::default()
+        };
+        let caps = IbDeviceCaps::from(&attr);
+        assert_eq!(caps.max_qp, 131072);
+        assert_eq!(caps.max_cqe, 0);
+        assert_eq!(caps.max_srq_sge, 0);
+    }
+}
@@ -0,0 +1,270 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Query the PortInfo of a local InfiniBand port with a directed-route SMP through its umad
+//! device, e.g. `/dev/infiniband/umad0`, for what the verbs do not report: the supported speeds.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+use super::sysfs::Sysfs;
+use super::types::IbPortSpeed;
+use super::utils::{read_sysfs_attr, read_sysfs_dir};
+
+/// The directory of the umad devices.
+const UMAD_DEV_DIR: &str = "/dev/infiniband";
+/// `IB_USER_MAD_REGISTER_AGENT`, i.e. `_IOWR(0x1b, 1, struct ib_user_mad_reg_req)`.
+const IB_USER_MAD_REGISTER_AGENT: u32 = 0xc01c_1b01;
+/// The size of `struct ib_user_mad_hdr_old`, used until `IB_USER_MAD_ENABLE_PKEY`.
+const UMAD_HDR_SIZE: usize = 56;
+/// The size of a MAD.
+const MAD_SIZE: usize = 256;
+/// The offset of the SMP data in a directed-route SMP.
+const SMP_DATA: usize = 64;
+/// `IB_MGMT_CLASS_SUBN_DIRECTED_ROUTE`.
+const MGMT_CLASS_SUBN_DIRECTED_ROUTE: u8 = 0x81;
+/// `IB_MGMT_METHOD_GET`.
+const MGMT_METHOD_GET: u8 = 0x01;
+/// The attribute ID of PortInfo.
+const ATTR_PORT_INFO: u16 = 0x0015;
+/// The permissive LID, i.e. the local port of a directed route.
+const PERMISSIVE_LID: u16 = 0xffff;
+/// `IsExtendedSpeedsSupported` of the CapabilityMask of PortInfo.
+const CAP_EXTENDED_SPEEDS: u32 = 1 << 14;
+/// The time to wait for the answer of the SMA.
+const TIMEOUT_MS: u32 = 1000;
+
+/// `struct ib_user_mad_reg_req`.
+#[repr(C)]
+#[derive(Default)]
+struct UmadRegReq {
+    id: u32,
+    method_mask: [u32; 4],
+    qpn: u8,
+    mgmt_class: u8,
+    mgmt_class_version: u8,
+    oui: [u8; 3],
+    rmpp_version: u8,
+}
+
+/// The speeds a port supports, from its PortInfo; `None` if it can not be queried, e.g. the
+/// umad device is only accessible by root, or the port is not InfiniBand.
+pub(crate) fn query_supported_speeds(
+    sysfs: &Sysfs,
+    device: &str,
+    port_num: u8,
+) -> Option<Vec<IbPortSpeed>> {
+    let path = umad_device(sysfs, device, port_num)?;
+    let mut file = OpenOptions::new().read(true).write(true).open(path).ok()?;
+
+    let agent = register_agent(&file)?;
+    file.write_all(&port_info_request(agent, port_num)).ok()?;
+
+    let mut fds = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // The umad device reports the timeout of a request as its status, so this is a fallback.
+    if unsafe { libc::poll(&mut fds, 1, 2 * TIMEOUT_MS as i32) } <= 0 {
+        return None;
+    }
+    let mut response = [0u8; UMAD_HDR_SIZE + MAD_SIZE];
+    let n = file.read(&mut response).ok()?;
+
+    let status = u32::from_ne_bytes(response[4..8].try_into().ok()?);
+    // The status of a directed-route SMP is after the direction bit.
+    let mad_status = u16::from_be_bytes([response[UMAD_HDR_SIZE + 4], response[UMAD_HDR_SIZE + 5]]);
+    if n < response.len() || status != 0 || mad_status & 0x7fff != 0 {
+        return None;
+    }
+
+    Some(decode_supported_speeds(
+        &response[UMAD_HDR_SIZE + SMP_DATA..UMAD_HDR_SIZE + SMP_DATA + 64],
+    ))
+}
+
+/// The umad device of a port, by the `ibdev` and `port` of `class/infiniband_mad/umad*`.
+fn umad_device(sysfs: &Sysfs, device: &str, port_num: u8) -> Option<PathBuf> {
+    let class_path = sysfs.root().join("class").join("infiniband_mad");
+
+    read_sysfs_dir(&class_path)
+        .ok()?
+        .into_iter()
+        .filter(|name| name.starts_with("umad"))
+        .find(|name| {
+            let path = class_path.join(name);
+            read_sysfs_attr(path.join("ibdev")).is_ok_and(|v| v == device)
+                && read_sysfs_attr(path.join("port")).is_ok_and(|v| v == port_num.to_string())
+        })
+        .map(|name| PathBuf::from(UMAD_DEV_DIR).join(name))
+}
+
+/// Register an agent to send the directed-route SMPs from, and receive their answers; it is
+/// unregistered when the device is closed.
+fn register_agent(file: &File) -> Option<u32> {
+    let mut req = UmadRegReq {
+        mgmt_class: MGMT_CLASS_SUBN_DIRECTED_ROUTE,
+        mgmt_class_version: 1,
+        ..Default::default()
+    };
+
+    match unsafe { libc::ioctl(file.as_raw_fd(), IB_USER_MAD_REGISTER_AGENT as _, &mut req) } {
+        0 => Some(req.id),
+        _ => None,
+    }
+}
+
+/// A `Get(PortInfo)` of the local port, i.e. a directed-route SMP of no hops, after its umad
+/// header.
+fn port_info_request(agent: u32, port_num: u8) -> [u8; UMAD_HDR_SIZE + MAD_SIZE] {
+    let mut request = [0u8; UMAD_HDR_SIZE + MAD_SIZE];
+
+    // The umad header: the agent, the timeout and the permissive LID; QP0 is all zeros.
+    request[0..4].copy_from_slice(&agent.to_ne_bytes());
+    request[8..12].copy_from_slice(&TIMEOUT_MS.to_ne_bytes());
+    request[28..30].copy_from_slice(&PERMISSIVE_LID.to_be_bytes());
+
+    let mad = &mut request[UMAD_HDR_SIZE..];
+    mad[0] = 1;
+    mad[1] = MGMT_CLASS_SUBN_DIRECTED_ROUTE;
+    mad[2] = 1;
+    mad[3] = MGMT_METHOD_GET;
+    // The high 32 bits of the transaction ID are set by the kernel to route the answer.
+    mad[12..16].copy_from_slice(&1u32.to_be_bytes());
+    mad[16..18].copy_from_slice(&ATTR_PORT_INFO.to_be_bytes());
+    mad[20..24].copy_from_slice(&u32::from(port_num).to_be_bytes());
+    mad[32..34].copy_from_slice(&PERMISSIVE_LID.to_be_bytes());
+    mad[34..36].copy_from_slice(&PERMISSIVE_LID.to_be_bytes());
+
+    request
+}
+
+/// Decode the speeds of a PortInfo: `LinkSpeedSupported`, i.e. SDR, DDR and QDR, and
+/// `LinkSpeedExtSupported` if `IsExtendedSpeedsSupported`, i.e. FDR, EDR, HDR and NDR.
+fn decode_supported_speeds(port_info: &[u8]) -> Vec<IbPortSpeed> {
+    let cap_mask = u32::from_be_bytes([port_info[20], port_info[21], port_info[22], port_info[23]]);
+    let speeds = port_info[32] >> 4;
+    let ext_speeds = match cap_mask & CAP_EXTENDED_SPEEDS {
+        0 => 0,
+        _ => port_info[62] & 0x0f,
+    };
+
+    [
+        (speeds & 0x1, IbPortSpeed::Sdr),
+        (speeds & 0x2, IbPortSpeed::Ddr),
+        (speeds & 0x4, IbPortSpeed::Qdr),
+        (ext_speeds & 0x1, IbPortSpeed::Fdr),
+        (ext_speeds & 0x2, IbPortSpeed::Edr),
+        (ext_speeds & 0x4, IbPortSpeed::Hdr),
+        (ext_speeds & 0x8, IbPortSpeed::Ndr),
+    ]
+    .into_iter()
+    .filter(|(bit, _)| *bit != 0)
+    .map(|(_, speed)| speed)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    /// A PortInfo with the given capabilities, `LinkSpeedSupported` and
+    /// `LinkSpeedExtSupported`.
+    fn port_info(cap_mask: u32, speeds: u8, ext_speeds: u8) -> [u8; 64] {
+        let mut port_info = [0u8; 64];
+        port_info[20..24].copy_from_slice(&cap_mask.to_be_bytes());
+        // PortState Active, after the supported speeds.
+        port_info[32] = speeds << 4 | 4;
+        // LinkSpeedExtActive EDR, before the supported ones.
+        port_info[62] = 2 << 4 | ext_speeds;
+
+        port_info
+    }
+
+    #[test]
+    fn decode_speeds() {
+        use IbPortSpeed::*;
+
+        assert_eq!(
+            decode_supported_speeds(&port_info(CAP_EXTENDED_SPEEDS, 0x7, 0x7)),
+            vec![Sdr, Ddr, Qdr, Fdr, Edr, Hdr]
+        );
+        assert_eq!(
+            decode_supported_speeds(&port_info(CAP_EXTENDED_SPEEDS | 0x2, 0x1, 0xf)),
+            vec![Sdr, Fdr, Edr, Hdr, Ndr]
+        );
+        // The extended speeds are not valid without IsExtendedSpeedsSupported.
+        assert_eq!(
+            decode_supported_speeds(&port_info(0x2, 0x5, 0x3)),
+            vec![Sdr, Qdr]
+        );
+        assert_eq!(decode_supported_speeds(&[0u8; 64]), vec![]);
+    }
+
+    #[test]
+    fn port_info_requests() {
+        let request = port_info_request(7, 2);
+        let mad = &request[UMAD_HDR_SIZE..];
+
+        assert_eq!(&request[0..4], &7u32.to_ne_bytes());
+        assert_eq!(&request[28..30], &[0xff, 0xff]);
+        assert_eq!(&mad[0..4], &[1, 0x81, 1, 1]);
+        // No hops, so the SMA of the local port answers.
+        assert_eq!(&mad[4..8], &[0, 0, 0, 0]);
+        assert_eq!(&mad[16..18], &[0x00, 0x15]);
+        assert_eq!(&mad[20..24], &[0, 0, 0, 2]);
+        assert_eq!(&mad[32..36], &[0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn reg_req_size() {
+        // The size in IB_USER_MAD_REGISTER_AGENT.
+        assert_eq!(mem::size_of::<UmadRegReq>(), 0x1c);
+        assert_eq!((IB_USER_MAD_REGISTER_AGENT >> 16) & 0x3fff, 0x1c);
+    }
+
+    #[test]
+    fn find_umad_devices() {
+        let dir = TempDir::new();
+        for (umad, ibdev, port) in [
+            ("umad0", "mlx5_0", "1"),
+            ("umad1", "mlx5_1", "1"),
+            ("umad2", "mlx5_1", "2"),
+        ] {
+            dir.write(format!("class/infiniband_mad/{}/ibdev", umad), ibdev);
+            dir.write(format!("class/infiniband_mad/{}/port", umad), port);
+        }
+        dir.write("class/infiniband_mad/abi_version", "5");
+        let sysfs = Sysfs::new(dir.path());
+
+        assert_eq!(
+            umad_device(&sysfs, "mlx5_1", 2),
+            Some(PathBuf::from("/dev/infiniband/umad2"))
+        );
+        assert_eq!(
+            umad_device(&sysfs, "mlx5_0", 1),
+            Some(PathBuf::from("/dev/infiniband/umad0"))
+        );
+        assert_eq!(umad_device(&sysfs, "mlx5_0", 2), None);
+        assert_eq!(umad_device(&sysfs, "rxe0", 1), None);
+    }
+}
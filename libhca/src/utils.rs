@@ -15,10 +15,13 @@ limitations under the License.
 */
 
 use std::ffi::CStr;
-use std::io;
+use std::fs;
+use std::path::Path;
 
 use libudev::Device;
 
+use super::error::{HcaError, Result};
+
 pub unsafe fn cstr_to_string(s: *const i8) -> String {
     CStr::from_ptr(s)
         .to_str()
@@ -26,22 +29,112 @@ pub unsafe fn cstr_to_string(s: *const i8) -> String {
         .to_string()
 }
 
-pub fn get_property<'a>(device: &'a Device, name: &'a str) -> io::Result<&'a str> {
+fn device_name(device: &Device) -> String {
+    device
+        .sysname()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// The name of a network interface, e.g. `ens1f0`.
+pub fn ifindex_to_name(ifindex: u32) -> Option<String> {
+    let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+    unsafe {
+        if libc::if_indextoname(ifindex, buf.as_mut_ptr()).is_null() {
+            return None;
+        }
+        Some(cstr_to_string(buf.as_ptr()))
+    }
+}
+
+pub fn get_property<'a>(device: &'a Device, name: &'a str) -> Result<&'a str> {
     match device.property_value(name) {
-        None => Err(io::Error::last_os_error()),
+        None => Err(HcaError::MissingProperty {
+            device: device_name(device),
+            name: name.to_string(),
+        }),
         Some(p) => p
             .to_str()
             .map(|s| s.trim())
-            .ok_or_else(io::Error::last_os_error),
+            .ok_or_else(|| HcaError::InvalidValue {
+                device: device_name(device),
+                name: name.to_string(),
+                value: p.to_string_lossy().to_string(),
+            }),
     }
 }
 
-pub fn get_sysattr<'a>(device: &'a Device, name: &'a str) -> io::Result<&'a str> {
+pub fn get_sysattr<'a>(device: &'a Device, name: &'a str) -> Result<&'a str> {
     match device.attribute_value(name) {
-        None => Err(io::Error::last_os_error()),
+        None => Err(HcaError::MissingAttribute {
+            device: device_name(device),
+            name: name.to_string(),
+        }),
         Some(p) => p
             .to_str()
             .map(|s| s.trim())
-            .ok_or_else(io::Error::last_os_error),
+            .ok_or_else(|| HcaError::InvalidValue {
+                device: device_name(device),
+                name: name.to_string(),
+                value: p.to_string_lossy().to_string(),
+            }),
+    }
+}
+
+/// Parse the numeric code of the attributes like `1: CA` or `4: ACTIVE`; `-1` if it is invalid.
+pub fn parse_code<T: std::str::FromStr + From<i8>>(v: &str) -> T {
+    v.split(':')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .parse()
+        .unwrap_or(T::from(-1))
+}
+
+pub fn read_sysfs_attr<P: AsRef<Path>>(path: P) -> Result<String> {
+    let path = path.as_ref();
+    fs::read_to_string(path)
+        .map(|s| s.trim().to_string())
+        .map_err(|error| HcaError::Sysfs {
+            path: path.to_path_buf(),
+            error,
+        })
+}
+
+pub fn read_sysfs_link_name<P: AsRef<Path>>(path: P) -> Result<String> {
+    let path = path.as_ref();
+    let target = fs::read_link(path).map_err(|error| HcaError::Sysfs {
+        path: path.to_path_buf(),
+        error,
+    })?;
+
+    target
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.to_string())
+        .ok_or_else(|| HcaError::InvalidValue {
+            device: path.display().to_string(),
+            name: "link".to_string(),
+            value: target.display().to_string(),
+        })
+}
+
+/// List the names of the entries in a sysfs directory, sorted.
+pub fn read_sysfs_dir<P: AsRef<Path>>(path: P) -> Result<Vec<String>> {
+    let path = path.as_ref();
+    let sysfs_error = |error| HcaError::Sysfs {
+        path: path.to_path_buf(),
+        error,
+    };
+
+    let mut names = vec![];
+    for entry in fs::read_dir(path).map_err(sysfs_error)? {
+        let entry = entry.map_err(sysfs_error)?;
+        if let Some(name) = entry.file_name().to_str() {
+            names.push(name.to_string());
+        }
     }
+    names.sort();
+
+    Ok(names)
 }
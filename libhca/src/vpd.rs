@@ -0,0 +1,241 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! The PCI Vital Product Data (VPD) of an adapter, see the PCI Local Bus Specification, 6.4.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde::Serialize;
+
+// The resource tags.
+const TAG_ID_STRING: u8 = 0x82;
+const TAG_VPD_R: u8 = 0x90;
+const TAG_VPD_W: u8 = 0x91;
+const TAG_END: u8 = 0x0f;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Vpd {
+    /// The identifier string, i.e. the product name, e.g. `ConnectX-5 VPI adapter card`.
+    pub product_name: Option<String>,
+    /// `PN`, the part number, e.g. `MCX556A-ECAT`.
+    pub part_number: Option<String>,
+    /// `SN`, the serial number.
+    pub serial_number: Option<String>,
+    /// `EC`, the engineering change level.
+    pub engineering_change: Option<String>,
+    /// `MN`, the manufacture ID.
+    pub manufacture_id: Option<String>,
+    /// The vendor specific fields `V0` to `VZ`, e.g. the firmware ID of Mellanox.
+    pub vendor_fields: BTreeMap<String, String>,
+}
+
+impl Vpd {
+    /// Read the VPD of a PCI device, e.g. `/sys/bus/pci/devices/0000:b1:00.0/vpd`; `None` if it is
+    /// not readable, e.g. without root, or malformed.
+    pub fn read<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let data = fs::read(path).ok()?;
+        Self::parse(&data)
+    }
+
+    /// Parse the resources of the VPD up to the end tag; the read-write resource is skipped.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let mut vpd = Self::default();
+
+        let mut pos = 0;
+        while pos < data.len() {
+            let tag = data[pos];
+
+            // A small resource has the name in bits 6:3 and the length in bits 2:0.
+            if tag & 0x80 == 0 {
+                if (tag >> 3) & 0x0f == TAG_END {
+                    return Some(vpd);
+                }
+                pos += 1 + (tag & 0x07) as usize;
+                continue;
+            }
+
+            // A large resource has the length in the two bytes after the tag.
+            let len = u16::from_le_bytes([*data.get(pos + 1)?, *data.get(pos + 2)?]) as usize;
+            let value = data.get(pos + 3..pos + 3 + len)?;
+            match tag {
+                TAG_ID_STRING => vpd.product_name = Some(decode_string(value)),
+                TAG_VPD_R => vpd.parse_keywords(value)?,
+                TAG_VPD_W => {}
+                _ => return None,
+            }
+            pos += 3 + len;
+        }
+
+        // There is no end tag, e.g. the data is truncated.
+        None
+    }
+
+    fn parse_keywords(&mut self, data: &[u8]) -> Option<()> {
+        let mut pos = 0;
+        while pos + 3 <= data.len() {
+            let keyword = &data[pos..pos + 2];
+            let len = data[pos + 2] as usize;
+            let value = decode_string(data.get(pos + 3..pos + 3 + len)?);
+            pos += 3 + len;
+
+            match keyword {
+                b"PN" => self.part_number = Some(value),
+                b"SN" => self.serial_number = Some(value),
+                b"EC" => self.engineering_change = Some(value),
+                b"MN" => self.manufacture_id = Some(value),
+                // The checksum and the reserved space are the end of the read-only resource.
+                b"RV" => break,
+                [b'V', _] => {
+                    self.vendor_fields
+                        .insert(String::from_utf8_lossy(keyword).to_string(), value);
+                }
+                _ => {}
+            }
+        }
+
+        Some(())
+    }
+}
+
+/// Decode an ASCII field; the fields are often padded with spaces or NULs.
+fn decode_string(data: &[u8]) -> String {
+    String::from_utf8_lossy(data)
+        .trim_end_matches(|c: char| c == '\0' || c.is_whitespace())
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    /// The small resource of the end tag.
+    const END: u8 = TAG_END << 3;
+
+    fn large_resource(tag: u8, value: &[u8]) -> Vec<u8> {
+        let mut data = vec![tag];
+        data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        data.extend_from_slice(value);
+        data
+    }
+
+    fn keywords(fields: &[(&str, &str)]) -> Vec<u8> {
+        let mut data = vec![];
+        for (keyword, value) in fields {
+            data.extend_from_slice(keyword.as_bytes());
+            data.push(value.len() as u8);
+            data.extend_from_slice(value.as_bytes());
+        }
+        data
+    }
+
+    /// The VPD of a ConnectX-5, as read from sysfs.
+    fn adapter_vpd() -> Vec<u8> {
+        let mut data = large_resource(TAG_ID_STRING, b"ConnectX-5 VPI adapter card");
+        data.extend(large_resource(
+            TAG_VPD_R,
+            &keywords(&[
+                ("PN", "MCX556A-ECAT"),
+                ("EC", "AD"),
+                ("SN", "MT1915K05921\0\0\0\0"),
+                ("V0", "PCIeGen3 x16   "),
+                ("V2", "MCX556A-ECAT"),
+                ("VA", "MLX:MODL=CX556A:MN=MLNX:CSKU=V2"),
+                ("RV", "\u{5}"),
+            ]),
+        ));
+        data.extend(large_resource(TAG_VPD_W, &keywords(&[("V1", "  ")])));
+        data.push(END);
+        data
+    }
+
+    #[test]
+    fn parse_adapter_vpd() {
+        let vpd = Vpd::parse(&adapter_vpd()).unwrap();
+        assert_eq!(
+            vpd.product_name.as_deref(),
+            Some("ConnectX-5 VPI adapter card")
+        );
+        assert_eq!(vpd.part_number.as_deref(), Some("MCX556A-ECAT"));
+        assert_eq!(vpd.engineering_change.as_deref(), Some("AD"));
+        assert_eq!(vpd.serial_number.as_deref(), Some("MT1915K05921"));
+        assert_eq!(vpd.manufacture_id, None);
+    }
+
+    #[test]
+    fn parse_vendor_fields() {
+        let vpd = Vpd::parse(&adapter_vpd()).unwrap();
+        let fields: Vec<(&str, &str)> = vpd
+            .vendor_fields
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        // The vendor fields of the read-write resource are skipped.
+        assert_eq!(
+            fields,
+            [
+                ("V0", "PCIeGen3 x16"),
+                ("V2", "MCX556A-ECAT"),
+                ("VA", "MLX:MODL=CX556A:MN=MLNX:CSKU=V2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_truncated_resource() {
+        let data = adapter_vpd();
+        // The length of the identifier string is beyond the data.
+        assert!(Vpd::parse(&data[..10]).is_none());
+        // The length itself is cut.
+        assert!(Vpd::parse(&data[..2]).is_none());
+
+        // A keyword longer than its resource.
+        let mut data = large_resource(TAG_VPD_R, b"PN\x20MCX5");
+        data.push(END);
+        assert!(Vpd::parse(&data).is_none());
+    }
+
+    #[test]
+    fn parse_bad_tag() {
+        let mut data = large_resource(0xa0, b"bad");
+        data.push(END);
+        assert!(Vpd::parse(&data).is_none());
+    }
+
+    #[test]
+    fn parse_without_end_tag() {
+        let data = adapter_vpd();
+        assert!(Vpd::parse(&data[..data.len() - 1]).is_none());
+        assert!(Vpd::parse(&[]).is_none());
+
+        // An empty VPD is only the end tag.
+        let vpd = Vpd::parse(&[END]).unwrap();
+        assert!(vpd.product_name.is_none() && vpd.vendor_fields.is_empty());
+    }
+
+    #[test]
+    fn read_vpd() {
+        let dir = TempDir::new();
+        let path = dir.write("vpd", adapter_vpd());
+        assert_eq!(
+            Vpd::read(path).and_then(|v| v.part_number).as_deref(),
+            Some("MCX556A-ECAT")
+        );
+        assert!(Vpd::read(dir.path().join("missing")).is_none());
+    }
+}
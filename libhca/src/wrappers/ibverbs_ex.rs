@@ -0,0 +1,38 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! The extended verbs exported by `wrappers/ibverbs_ex.c` and `wrappers/mlx5dv_ex.c`.
+
+use std::os::raw::c_int;
+
+use super::ibverbs::{ibv_context, ibv_device_attr_ex, ibv_port_cap_flags2};
+
+extern "C" {
+    /// `ibv_query_device_ex(context, NULL, attr)`.
+    pub fn hca_query_device_ex(context: *mut ibv_context, attr: *mut ibv_device_attr_ex) -> c_int;
+}
+
+#[cfg(hca_mlx5dv)]
+extern "C" {
+    /// `mlx5dv_query_device(context, attr)` for `attr.dc_odp_caps`; -1 if it is not a mlx5
+    /// device or the caps are not reported.
+    pub fn hca_mlx5_query_dc_odp_caps(context: *mut ibv_context, caps: *mut u32) -> c_int;
+}
+
+/// The `ibv_port_cap_flags2` of the XDR ports, newer than the verbs headers the bindings are
+/// generated from.
+pub const IBV_PORT_EXTENDED_SPEEDS2_SUP: ibv_port_cap_flags2 = 1 << 11;
+pub const IBV_PORT_LINK_SPEED_XDR_SUP: ibv_port_cap_flags2 = 1 << 12;
@@ -1,2 +1,3 @@
 pub mod ibverbs;
+pub mod ibverbs_ex;
 pub mod pci;
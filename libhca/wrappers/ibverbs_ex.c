@@ -0,0 +1,27 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+#include <infiniband/verbs.h>
+
+/*
+ * The extended verbs are static inline functions in verbs.h, so bindgen can not
+ * bind them; export them as real symbols instead.
+ */
+
+int hca_query_device_ex(struct ibv_context *context, struct ibv_device_attr_ex *attr)
+{
+	return ibv_query_device_ex(context, NULL, attr);
+}
@@ -0,0 +1,36 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+#include <stdint.h>
+#include <infiniband/mlx5dv.h>
+
+/*
+ * Query the ODP capabilities of the DC transport of a device, see
+ * `enum ibv_odp_transport_cap_bits`; -1 if the device is not driven by mlx5, or the provider
+ * does not report them. Only built if the mlx5 direct verbs are installed, see build.rs.
+ */
+int hca_mlx5_query_dc_odp_caps(struct ibv_context *context, uint32_t *caps)
+{
+	struct mlx5dv_context attr = { .comp_mask = MLX5DV_CONTEXT_MASK_DC_ODP_CAPS };
+
+	if (!mlx5dv_is_supported(context->device) || mlx5dv_query_device(context, &attr))
+		return -1;
+	if (!(attr.comp_mask & MLX5DV_CONTEXT_MASK_DC_ODP_CAPS))
+		return -1;
+
+	*caps = attr.dc_odp_caps;
+	return 0;
+}
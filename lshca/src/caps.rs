@@ -0,0 +1,301 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use std::io;
+
+use color_eyre::eyre::eyre;
+use serde::Serialize;
+
+use libhca::caps::flag_names;
+use libhca::types::{Discovery, IbDevice, IbDeviceCaps, IbDeviceExtendedCaps};
+
+use crate::output::{OutputFormat, SCHEMA_VERSION};
+use crate::show::find_device;
+
+/// The capabilities of a device in `--output json|yaml`.
+#[derive(Serialize)]
+struct DeviceCaps<'a> {
+    schema_version: u32,
+    device: &'a str,
+    caps: &'a IbDeviceCaps,
+    ext_caps: Option<&'a IbDeviceExtendedCaps>,
+}
+
+/// One row of `--output csv`, i.e. the capabilities of one device.
+#[derive(Serialize)]
+struct CapsRecord<'a> {
+    schema_version: u32,
+    device: &'a str,
+    vendor_id: u32,
+    vendor_part_id: u32,
+    hw_ver: u32,
+    max_mr_size: u64,
+    page_size_cap: u64,
+    max_qp: u32,
+    max_qp_wr: u32,
+    max_sge: u32,
+    max_sge_rd: u32,
+    max_cq: u32,
+    max_cqe: u32,
+    max_mr: u32,
+    max_pd: u32,
+    max_qp_rd_atom: u32,
+    max_qp_init_rd_atom: u32,
+    max_res_rd_atom: u32,
+    atomic_cap: String,
+    max_mw: u32,
+    max_mcast_grp: u32,
+    max_mcast_qp_attach: u32,
+    max_total_mcast_qp_attach: u32,
+    max_ah: u32,
+    max_srq: u32,
+    max_srq_wr: u32,
+    max_srq_sge: u32,
+    max_pkeys: u16,
+    local_ca_ack_delay: u8,
+    phys_port_cnt: u8,
+    device_cap_flags: String,
+    odp: Option<bool>,
+    rc_odp_caps: Option<String>,
+    hca_core_clock: Option<u64>,
+    max_tso: Option<u32>,
+    qp_rate_limit_min: Option<u32>,
+    qp_rate_limit_max: Option<u32>,
+    max_dm_size: Option<u64>,
+    dc_odp_caps: Option<String>,
+}
+
+impl<'a> CapsRecord<'a> {
+    fn new(dev: &'a IbDevice, caps: &IbDeviceCaps) -> Self {
+        let ext_caps = dev.ext_caps.as_ref();
+        Self {
+            schema_version: SCHEMA_VERSION,
+            device: &dev.name,
+            vendor_id: caps.vendor_id,
+            vendor_part_id: caps.vendor_part_id,
+            hw_ver: caps.hw_ver,
+            max_mr_size: caps.max_mr_size,
+            page_size_cap: caps.page_size_cap,
+            max_qp: caps.max_qp,
+            max_qp_wr: caps.max_qp_wr,
+            max_sge: caps.max_sge,
+            max_sge_rd: caps.max_sge_rd,
+            max_cq: caps.max_cq,
+            max_cqe: caps.max_cqe,
+            max_mr: caps.max_mr,
+            max_pd: caps.max_pd,
+            max_qp_rd_atom: caps.max_qp_rd_atom,
+            max_qp_init_rd_atom: caps.max_qp_init_rd_atom,
+            max_res_rd_atom: caps.max_res_rd_atom,
+            atomic_cap: caps.atomic_cap.to_string(),
+            max_mw: caps.max_mw,
+            max_mcast_grp: caps.max_mcast_grp,
+            max_mcast_qp_attach: caps.max_mcast_qp_attach,
+            max_total_mcast_qp_attach: caps.max_total_mcast_qp_attach,
+            max_ah: caps.max_ah,
+            max_srq: caps.max_srq,
+            max_srq_wr: caps.max_srq_wr,
+            max_srq_sge: caps.max_srq_sge,
+            max_pkeys: caps.max_pkeys,
+            local_ca_ack_delay: caps.local_ca_ack_delay,
+            phys_port_cnt: caps.phys_port_cnt,
+            device_cap_flags: flag_names(&caps.device_cap_flags).join(" "),
+            odp: ext_caps.map(|c| c.odp),
+            rc_odp_caps: ext_caps.map(|c| flag_names(&c.rc_odp_caps).join(" ")),
+            hca_core_clock: ext_caps.map(|c| c.hca_core_clock),
+            max_tso: ext_caps.map(|c| c.max_tso),
+            qp_rate_limit_min: ext_caps.map(|c| c.qp_rate_limit_min),
+            qp_rate_limit_max: ext_caps.map(|c| c.qp_rate_limit_max),
+            max_dm_size: ext_caps.map(|c| c.max_dm_size),
+            dc_odp_caps: ext_caps
+                .and_then(|c| c.dc_odp_caps)
+                .map(|caps| flag_names(&caps).join(" ")),
+        }
+    }
+}
+
+/// Report the limits and capabilities of all RDMA devices, or of the given one.
+pub fn list_caps(
+    discovery: &Discovery,
+    device: Option<&str>,
+    format: OutputFormat,
+) -> Result<(), color_eyre::Report> {
+    let devices: Vec<(&IbDevice, &IbDeviceCaps)> = match device {
+        Some(name) => {
+            let (_, dev) = find_device(discovery, name)?;
+            let caps = dev
+                .caps
+                .as_ref()
+                .ok_or_else(|| eyre!("no capabilities of device <{}>", name))?;
+            vec![(dev, caps)]
+        }
+        None => discovery
+            .pci_devices
+            .iter()
+            .flat_map(|hca| hca.ib_devices.iter())
+            .filter_map(|dev| dev.caps.as_ref().map(|caps| (dev, caps)))
+            .collect(),
+    };
+
+    match format {
+        OutputFormat::Table => {
+            for (i, (dev, caps)) in devices.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                print_caps(dev, caps);
+            }
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(io::stdout(), &device_caps(&devices))?;
+            println!();
+        }
+        OutputFormat::Yaml => serde_yaml::to_writer(io::stdout(), &device_caps(&devices))?,
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            for (dev, caps) in &devices {
+                writer.serialize(CapsRecord::new(dev, caps))?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn device_caps<'a>(devices: &[(&'a IbDevice, &'a IbDeviceCaps)]) -> Vec<DeviceCaps<'a>> {
+    devices
+        .iter()
+        .map(|(dev, caps)| DeviceCaps {
+            schema_version: SCHEMA_VERSION,
+            device: &dev.name,
+            caps,
+            ext_caps: dev.ext_caps.as_ref(),
+        })
+        .collect()
+}
+
+fn print_caps(dev: &IbDevice, caps: &IbDeviceCaps) {
+    println!("{}", dev.name);
+
+    println!("    {:<25}: {:#x}", "Vendor ID", caps.vendor_id);
+    println!("    {:<25}: {}", "Vendor Part ID", caps.vendor_part_id);
+    println!("    {:<25}: {:#x}", "HW Version", caps.hw_ver);
+    println!("    {:<25}: {}", "Max QP", caps.max_qp);
+    println!("    {:<25}: {}", "Max QP WR", caps.max_qp_wr);
+    println!("    {:<25}: {}", "Max SGE", caps.max_sge);
+    println!("    {:<25}: {}", "Max SGE RD", caps.max_sge_rd);
+    println!("    {:<25}: {}", "Max CQ", caps.max_cq);
+    println!("    {:<25}: {}", "Max CQE", caps.max_cqe);
+    println!("    {:<25}: {}", "Max MR", caps.max_mr);
+    println!("    {:<25}: {:#x}", "Max MR Size", caps.max_mr_size);
+    println!("    {:<25}: {:#x}", "Page Size Cap", caps.page_size_cap);
+    println!("    {:<25}: {}", "Max PD", caps.max_pd);
+    println!("    {:<25}: {}", "Max MW", caps.max_mw);
+    println!("    {:<25}: {}", "Max AH", caps.max_ah);
+    println!("    {:<25}: {}", "Max SRQ", caps.max_srq);
+    println!("    {:<25}: {}", "Max SRQ WR", caps.max_srq_wr);
+    println!("    {:<25}: {}", "Max SRQ SGE", caps.max_srq_sge);
+    println!("    {:<25}: {}", "Max QP RD Atom", caps.max_qp_rd_atom);
+    println!(
+        "    {:<25}: {}",
+        "Max QP Init RD Atom", caps.max_qp_init_rd_atom
+    );
+    println!("    {:<25}: {}", "Max Res RD Atom", caps.max_res_rd_atom);
+    println!(
+        "    {:<25}: {} ({})",
+        "Atomic Cap",
+        caps.atomic_cap,
+        caps.atomic_cap.description()
+    );
+    println!("    {:<25}: {}", "Max Mcast Groups", caps.max_mcast_grp);
+    println!(
+        "    {:<25}: {}",
+        "Max Mcast QP Attach", caps.max_mcast_qp_attach
+    );
+    println!(
+        "    {:<25}: {}",
+        "Max Total Mcast QP Attach", caps.max_total_mcast_qp_attach
+    );
+    println!("    {:<25}: {}", "Max P_Keys", caps.max_pkeys);
+    println!(
+        "    {:<25}: {}",
+        "Local CA ACK Delay", caps.local_ca_ack_delay
+    );
+    println!("    {:<25}: {}", "Phys Ports", caps.phys_port_cnt);
+    println!(
+        "    {:<25}: {:#x}",
+        "Device Cap Flags",
+        caps.device_cap_flags.bits()
+    );
+    for name in flag_names(&caps.device_cap_flags) {
+        println!("    {:<25}  {}", "", name);
+    }
+
+    if let Some(ext_caps) = &dev.ext_caps {
+        print_ext_caps(ext_caps);
+    }
+}
+
+fn print_ext_caps(caps: &IbDeviceExtendedCaps) {
+    println!(
+        "    {:<25}: {}",
+        "ODP",
+        match (caps.odp, caps.odp_implicit) {
+            (false, _) => "not supported",
+            (true, false) => "supported",
+            (true, true) => "supported, implicit",
+        }
+    );
+    if caps.odp {
+        println!("    {:<25}: {}", "RC ODP Caps", caps.rc_odp_caps);
+        println!("    {:<25}: {}", "UC ODP Caps", caps.uc_odp_caps);
+        println!("    {:<25}: {}", "UD ODP Caps", caps.ud_odp_caps);
+        println!("    {:<25}: {}", "XRC ODP Caps", caps.xrc_odp_caps);
+        if let Some(dc_odp_caps) = caps.dc_odp_caps {
+            println!("    {:<25}: {}", "DC ODP Caps", dc_odp_caps);
+        }
+    }
+    println!("    {:<25}: {} kHz", "HCA Core Clock", caps.hca_core_clock);
+    println!(
+        "    {:<25}: {:#x}",
+        "Timestamp Mask", caps.completion_timestamp_mask
+    );
+    println!(
+        "    {:<25}: {} ({})",
+        "Max TSO", caps.max_tso, caps.tso_supported_qpts
+    );
+    println!(
+        "    {:<25}: {} tables of {} ({})",
+        "RSS",
+        caps.max_rwq_indirection_tables,
+        caps.max_rwq_indirection_table_size,
+        caps.rss_supported_qpts
+    );
+    println!("    {:<25}: {}", "Max WQ Type RQ", caps.max_wq_type_rq);
+    if caps.is_packet_pacing_supported() {
+        println!(
+            "    {:<25}: {} - {} kbps ({})",
+            "Packet Pacing",
+            caps.qp_rate_limit_min,
+            caps.qp_rate_limit_max,
+            caps.packet_pacing_supported_qpts
+        );
+    } else {
+        println!("    {:<25}: not supported", "Packet Pacing");
+    }
+    println!("    {:<25}: {}", "Max DM Size", caps.max_dm_size);
+}
@@ -0,0 +1,183 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use std::io;
+
+use color_eyre::eyre::eyre;
+use serde::Serialize;
+
+use libhca::counters::PortCounters;
+use libhca::sysfs::Sysfs;
+use libhca::types::{Discovery, IbDevice};
+
+use crate::output::{OutputFormat, SCHEMA_VERSION};
+use crate::show::find_device;
+
+/// The counters of one port in `--output json|yaml`.
+#[derive(Serialize)]
+struct CountersRecord<'a> {
+    schema_version: u32,
+    #[serde(flatten)]
+    counters: &'a PortCounters,
+}
+
+impl<'a> CountersRecord<'a> {
+    fn new(counters: &'a PortCounters) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            counters,
+        }
+    }
+}
+
+/// Parse the target of `lshca counters`, e.g. `mlx5_0` or `mlx5_0/1`.
+pub fn parse_target(target: &str) -> Result<(&str, Option<u8>), color_eyre::Report> {
+    match target.split_once('/') {
+        None => Ok((target, None)),
+        Some((device, port)) => {
+            let port = port
+                .parse()
+                .map_err(|_| eyre!("invalid port <{}> of <{}>", port, device))?;
+            Ok((device, Some(port)))
+        }
+    }
+}
+
+/// The ports of the given target, or all the ports of all RDMA devices.
+pub fn select_ports<'a>(
+    discovery: &'a Discovery,
+    target: Option<&str>,
+) -> Result<Vec<(&'a IbDevice, u8)>, color_eyre::Report> {
+    let (devices, port_num) = match target {
+        Some(target) => {
+            let (name, port_num) = parse_target(target)?;
+            (vec![find_device(discovery, name)?.1], port_num)
+        }
+        None => (
+            discovery
+                .pci_devices
+                .iter()
+                .flat_map(|hca| hca.ib_devices.iter())
+                .collect(),
+            None,
+        ),
+    };
+
+    let ports: Vec<(&IbDevice, u8)> = devices
+        .into_iter()
+        .flat_map(|dev| dev.ib_ports.iter().map(move |port| (dev, port.port_num)))
+        .filter(|(_, num)| port_num.is_none_or(|port_num| port_num == *num))
+        .collect();
+
+    if port_num.is_some() && ports.is_empty() {
+        return Err(eyre!("no such port <{}>", target.unwrap_or_default()));
+    }
+
+    Ok(ports)
+}
+
+/// Print the counters of all ports, or of the given device or port, e.g. `mlx5_0/1`.
+pub fn list_counters(
+    discovery: &Discovery,
+    sysfs: &Sysfs,
+    target: Option<&str>,
+    format: OutputFormat,
+) -> Result<(), color_eyre::Report> {
+    let mut counters = vec![];
+    for (dev, port_num) in select_ports(discovery, target)? {
+        match sysfs.read_port_counters(&dev.name, port_num) {
+            Ok(c) => counters.push(c),
+            Err(e) => eprintln!("{} port {}: {}", dev.name, port_num, e),
+        }
+    }
+
+    match format {
+        OutputFormat::Table => print_counters(&counters),
+        OutputFormat::Json => {
+            let records: Vec<CountersRecord> = counters.iter().map(CountersRecord::new).collect();
+            serde_json::to_writer_pretty(io::stdout(), &records)?;
+            println!();
+        }
+        OutputFormat::Yaml => {
+            let records: Vec<CountersRecord> = counters.iter().map(CountersRecord::new).collect();
+            serde_yaml::to_writer(io::stdout(), &records)?;
+        }
+        OutputFormat::Csv => write_csv(&counters)?,
+    }
+
+    Ok(())
+}
+
+fn print_counters(counters: &[PortCounters]) {
+    for c in counters {
+        println!("{} port {}", c.device, c.port_num);
+
+        let values = c.values();
+        for (name, value) in &values {
+            println!(
+                "    {:<32}: {}",
+                name,
+                value.map(|v| v.to_string()).unwrap_or("-".to_string())
+            );
+        }
+
+        // The provider specific counters which are not typed above.
+        let others: Vec<_> = c
+            .hw_counters
+            .iter()
+            .filter(|(name, _)| !values.iter().any(|(n, _)| n == name))
+            .collect();
+        if !others.is_empty() {
+            println!("    Hardware counters");
+            for (name, value) in others {
+                println!("    {:<32}: {}", name, value);
+            }
+        }
+
+        println!();
+    }
+}
+
+/// One row per port with the typed counters as columns; `hw_counters` is left out.
+fn write_csv(counters: &[PortCounters]) -> Result<(), color_eyre::Report> {
+    let mut writer = csv::Writer::from_writer(io::stdout());
+
+    let mut header = vec!["schema_version", "device", "port"];
+    header.extend(
+        PortCounters::default()
+            .values()
+            .iter()
+            .map(|(name, _)| *name),
+    );
+    writer.write_record(&header)?;
+
+    for c in counters {
+        let mut record = vec![
+            SCHEMA_VERSION.to_string(),
+            c.device.clone(),
+            c.port_num.to_string(),
+        ];
+        record.extend(
+            c.values()
+                .iter()
+                .map(|(_, v)| v.map(|v| v.to_string()).unwrap_or_default()),
+        );
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,121 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use std::io;
+
+use serde::Serialize;
+
+use libhca::types::{Discovery, GidEntry, IbDevice};
+
+use crate::output::{OutputFormat, SCHEMA_VERSION};
+use crate::show::find_device;
+
+/// One GID of `lshca gids`, i.e. one row of the table or the CSV.
+#[derive(Serialize)]
+struct GidRecord<'a> {
+    schema_version: u32,
+    device: &'a str,
+    port: u8,
+    index: u32,
+    gid: String,
+    address: String,
+    gid_type: String,
+    netdev: &'a str,
+}
+
+impl<'a> GidRecord<'a> {
+    fn new(dev: &'a IbDevice, port_num: u8, entry: &'a GidEntry) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            device: &dev.name,
+            port: port_num,
+            index: entry.index,
+            gid: entry.gid.to_string(),
+            address: entry.gid.ip_addr().to_string(),
+            gid_type: entry.gid_type.to_string(),
+            netdev: entry.netdev.as_deref().unwrap_or_default(),
+        }
+    }
+}
+
+/// List the valid GIDs of all RDMA devices, or of the given one.
+pub fn list_gids(
+    discovery: &Discovery,
+    device: Option<&str>,
+    format: OutputFormat,
+) -> Result<(), color_eyre::Report> {
+    let devices: Vec<&IbDevice> = match device {
+        Some(name) => vec![find_device(discovery, name)?.1],
+        None => discovery
+            .pci_devices
+            .iter()
+            .flat_map(|hca| hca.ib_devices.iter())
+            .collect(),
+    };
+
+    let records: Vec<GidRecord> = devices
+        .iter()
+        .flat_map(|dev| {
+            dev.ib_ports.iter().flat_map(move |port| {
+                port.gids
+                    .iter()
+                    .map(move |entry| GidRecord::new(dev, port.port_num, entry))
+            })
+        })
+        .collect();
+
+    match format {
+        OutputFormat::Table => print_gids(&records),
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(io::stdout(), &records)?;
+            println!();
+        }
+        OutputFormat::Yaml => serde_yaml::to_writer(io::stdout(), &records)?,
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            for record in &records {
+                writer.serialize(record)?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn print_gids(records: &[GidRecord]) {
+    println!(
+        "{:<15}{:<6}{:<7}{:<42}{:<25}{:<10}{:<15}",
+        "Device", "Port", "Index", "GID", "Address", "Type", "Netdev"
+    );
+
+    for record in records {
+        println!(
+            "{:<15}{:<6}{:<7}{:<42}{:<25}{:<10}{:<15}",
+            record.device,
+            record.port,
+            record.index,
+            record.gid,
+            record.address,
+            record.gid_type,
+            if record.netdev.is_empty() {
+                "-"
+            } else {
+                record.netdev
+            }
+        );
+    }
+}
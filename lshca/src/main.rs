@@ -14,50 +14,114 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
+mod caps;
+mod counters;
+mod gids;
+mod monitor;
+mod output;
+mod show;
+mod sriov;
+mod watch;
+
+use std::path::PathBuf;
+
 use ::libhca;
+use clap::{Parser, Subcommand};
+
+use libhca::sysfs::Sysfs;
+use libhca::types::{
+    Discovery, DiscoveryWarning, IbDevice, IbPort, IbPortPhysState, IbPortState, PciDevice,
+};
+use output::OutputFormat;
 
 // use libudev::Device;
 
+/// List the HCAs on the host.
+#[derive(Parser)]
+#[command(name = "lshca", version, about)]
+struct Options {
+    /// The output format; json, yaml and csv follow a versioned schema, see README.md.
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
+
+    /// Hide the SR-IOV virtual functions (VFs) and show the physical functions only.
+    #[arg(long, global = true)]
+    hide_vfs: bool,
+
+    /// Read the HCAs from the sysfs mounted here, e.g. `/host/sys` in a container or a test
+    /// tree, instead of libudev and libibverbs.
+    #[arg(long, global = true)]
+    sysfs_root: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the details of an RDMA device and its ports, e.g. `lshca show mlx5_0`.
+    Show { device: String },
+    /// List the GIDs of all RDMA devices, or of the given one, with their type and netdev.
+    Gids { device: Option<String> },
+    /// Report the limits and capabilities of all RDMA devices, or of the given one, e.g. the max QPs and CQEs.
+    Caps { device: Option<String> },
+    /// Print the traffic and error counters of all ports, or of a device or port, e.g. `mlx5_0/1`.
+    Counters { target: Option<String> },
+    /// Show the throughput and the error counter deltas of the ports at an interval.
+    Watch(watch::WatchArgs),
+    /// Print the hotplug events of the HCAs, e.g. VFs created and devices reset by the firmware.
+    Monitor(monitor::MonitorArgs),
+    /// Configure SR-IOV, see `lshca sriov set --help`.
+    Sriov {
+        #[command(subcommand)]
+        command: sriov::SriovCommand,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<(), color_eyre::Report> {
     color_eyre::install()?;
 
-    let hcas = libhca::list_pci_devices()?;
-
-    for hca in hcas {
-        println!("----------------------------------------------");
-
-        println!("{:<15}: {}", "ID", hca.subsys_id);
-        println!("{:<15}: {}", "Model", hca.model_name);
-        println!("{:<15}: {}", "Vendor", hca.vendor_name);
-        println!("{:<15}: {}", "FW", hca.fw_ver);
-        println!("{:<15}: {}", "Board", hca.board_id);
+    let options = Options::parse();
+    let sysfs = options
+        .sysfs_root
+        .as_ref()
+        .map(Sysfs::new)
+        .unwrap_or_default();
 
-        println!();
+    // The configuration does not need the discovery.
+    if let Some(Command::Sriov { command }) = &options.command {
+        return sriov::run(command, &sysfs);
+    }
+    // The devices come and go while monitoring, so the discovery is not used either.
+    if let Some(Command::Monitor(args)) = &options.command {
+        return monitor::monitor(args, options.output).await;
+    }
 
-        println!(
-            "    {:<15}{:<15}{:<25}{:<25}{:<15}{:<15}{:<15}{:<15}",
-            "Name", "Slot", "Node GUID", "Port GUID", "LID", "LinkType", "State", "PhysState"
-        );
+    let mut discovery = match options.sysfs_root {
+        Some(_) => sysfs.discover_pci_devices()?,
+        None => libhca::discover_pci_devices()?,
+    };
+    if options.hide_vfs {
+        hide_vfs(&mut discovery);
+    }
 
-        for dev in hca.ib_devices {
-            for port in dev.ib_ports {
-                println!(
-                    "    {:<15}{:<15}{:<25}{:<25}{:<15}{:<15}{:<15}{:<15}",
-                    dev.name,
-                    dev.slot_name,
-                    dev.node_guid,
-                    port.guid.unwrap_or("-".to_string()),
-                    port.lid,
-                    port.link_type.to_string(),
-                    port.state.to_string(),
-                    port.phys_state.to_string(),
-                );
-            }
+    match &options.command {
+        None => list_hcas(discovery, options.output)?,
+        Some(Command::Show { device }) => show::show_device(&discovery, device, options.output)?,
+        Some(Command::Gids { device }) => {
+            gids::list_gids(&discovery, device.as_deref(), options.output)?
         }
-
-        println!();
-        println!();
+        Some(Command::Caps { device }) => {
+            caps::list_caps(&discovery, device.as_deref(), options.output)?
+        }
+        Some(Command::Counters { target }) => {
+            counters::list_counters(&discovery, &sysfs, target.as_deref(), options.output)?
+        }
+        Some(Command::Watch(args)) => {
+            watch::watch(&discovery, &sysfs, args, options.output).await?
+        }
+        Some(Command::Sriov { .. }) | Some(Command::Monitor(_)) => unreachable!(),
     }
 
     //    let context = libudev::Context::new()?;
@@ -83,3 +147,254 @@ async fn main() -> Result<(), color_eyre::Report> {
 
     Ok(())
 }
+
+fn list_hcas(discovery: Discovery, format: OutputFormat) -> Result<(), color_eyre::Report> {
+    match format {
+        OutputFormat::Table => {
+            print_table(discovery.pci_devices);
+            print_warnings(&discovery.warnings);
+        }
+        OutputFormat::Json => output::write_json(&discovery)?,
+        OutputFormat::Yaml => output::write_yaml(&discovery)?,
+        OutputFormat::Csv => {
+            output::write_csv(&discovery.pci_devices)?;
+            print_warnings(&discovery.warnings);
+        }
+    }
+
+    Ok(())
+}
+
+fn print_table(hcas: Vec<PciDevice>) {
+    for hca in hcas {
+        println!("----------------------------------------------");
+
+        println!("{:<15}: {}", "Adapter", hca.adapter_id);
+        if !hca.is_software() {
+            println!("{:<15}: {}", "Subsys ID", hca.subsys_id);
+        }
+        println!("{:<15}: {}", "Model", hca.model_name);
+        println!("{:<15}: {}", "Vendor", hca.vendor_name);
+        println!("{:<15}: {}", "FW", hca.fw_ver.as_deref().unwrap_or("-"));
+        println!(
+            "{:<15}: {}",
+            "Board",
+            hca.board_id.as_deref().unwrap_or("-")
+        );
+        if let Some(vpd) = &hca.vpd {
+            println!(
+                "{:<15}: {}",
+                "Product",
+                vpd.product_name.as_deref().unwrap_or("-")
+            );
+            println!(
+                "{:<15}: {}",
+                "Part Number",
+                vpd.part_number.as_deref().unwrap_or("-")
+            );
+            println!(
+                "{:<15}: {}",
+                "Serial Number",
+                vpd.serial_number.as_deref().unwrap_or("-")
+            );
+        }
+        if let Some(link) = &hca.pcie_link {
+            println!(
+                "{:<15}: {}{} (max {})",
+                "PCIe",
+                link,
+                if link.is_degraded() { "*" } else { "" },
+                link.max_link()
+            );
+            if let (Some(payload), Some(read_req)) = (link.max_payload, link.max_read_req) {
+                println!(
+                    "{:<15}: {} bytes, MaxReadReq {} bytes",
+                    "MaxPayload", payload, read_req
+                );
+            }
+        }
+        println!(
+            "{:<15}: {} (CPUs {})",
+            "NUMA Node",
+            hca.numa_node
+                .map(|n| n.to_string())
+                .unwrap_or("-".to_string()),
+            if hca.local_cpulist.is_empty() {
+                "-"
+            } else {
+                &hca.local_cpulist
+            }
+        );
+
+        println!();
+
+        println!(
+            "    {:<15}{:<15}{:<25}{:<25}{:<15}{:<15}{:<20}{:<15}{:<15}{:<15}",
+            "Name",
+            "Slot",
+            "Node GUID",
+            "Port GUID",
+            "LID",
+            "LinkType",
+            "Rate",
+            "State",
+            "PhysState",
+            "Netdev"
+        );
+
+        let mut notes = vec![];
+
+        if let Some(link) = hca.pcie_link.as_ref().filter(|link| link.is_degraded()) {
+            notes.push(format!(
+                "PCIe link degraded: trained at {} but the adapter supports {}, check the slot, riser and BIOS settings",
+                link,
+                link.max_link()
+            ));
+        }
+
+        for (dev, is_vf) in device_tree(&hca) {
+            for port in &dev.ib_ports {
+                let degraded = hca.is_port_degraded(port);
+                if degraded {
+                    notes.push(format!(
+                        "{} port {}: running at {} Gb/s, below the expected {} Gb/s",
+                        dev.name,
+                        port.port_num,
+                        port.rate,
+                        hca.expected_port_rate(port).unwrap_or_default()
+                    ));
+                }
+
+                if port.state != IbPortState::Active {
+                    notes.push(format!(
+                        "{} port {}: {} - {}",
+                        dev.name,
+                        port.port_num,
+                        port.state,
+                        port.state.description()
+                    ));
+                }
+                if port.phys_state != IbPortPhysState::LinkUp {
+                    notes.push(format!(
+                        "{} port {}: {} - {}",
+                        dev.name,
+                        port.port_num,
+                        port.phys_state,
+                        port.phys_state.description()
+                    ));
+                }
+
+                println!(
+                    "    {:<15}{:<15}{:<25}{:<25}{:<15}{:<15}{:<20}{:<15}{:<15}{:<15}",
+                    if is_vf {
+                        format!("└ {}", dev.name)
+                    } else {
+                        dev.name.clone()
+                    },
+                    if dev.slot_name.is_empty() {
+                        "-"
+                    } else {
+                        &dev.slot_name
+                    },
+                    dev.node_guid.as_deref().unwrap_or("-"),
+                    port.guid.clone().unwrap_or("-".to_string()),
+                    port.lid,
+                    port.link_type.to_string(),
+                    format_rate(port, degraded),
+                    port.state.to_string(),
+                    port.phys_state.to_string(),
+                    format_netdevs(port),
+                );
+            }
+        }
+
+        if !notes.is_empty() {
+            println!();
+            for note in notes {
+                println!("    {}", note);
+            }
+        }
+
+        println!();
+        println!();
+    }
+}
+
+fn hide_vfs(discovery: &mut Discovery) {
+    for hca in &mut discovery.pci_devices {
+        hca.ib_devices.retain(|dev| !dev.is_vf());
+    }
+}
+
+/// Order the devices of an adapter as a tree: each PF is followed by its VFs, by VF index; a
+/// VF whose PF has no RDMA device is a root itself. The flag is whether the device is a VF.
+fn device_tree(hca: &PciDevice) -> Vec<(&IbDevice, bool)> {
+    let has_pf = |dev: &IbDevice| {
+        dev.physfn
+            .as_ref()
+            .is_some_and(|pf| hca.ib_devices.iter().any(|d| &d.slot_name == pf))
+    };
+
+    let mut tree = vec![];
+    for root in hca.ib_devices.iter().filter(|dev| !has_pf(dev)) {
+        tree.push((root, root.is_vf()));
+
+        let mut vfs: Vec<&IbDevice> = hca
+            .ib_devices
+            .iter()
+            .filter(|dev| dev.physfn.as_ref() == Some(&root.slot_name))
+            .collect();
+        vfs.sort_by_key(|dev| dev.vf_index);
+        tree.extend(vfs.into_iter().map(|vf| (vf, true)));
+    }
+
+    tree
+}
+
+/// Format the rate of a port, e.g. `100 (4x EDR)`; a degraded port is marked with `*`.
+fn format_rate(port: &IbPort, degraded: bool) -> String {
+    if port.rate == 0.0 {
+        return "-".to_string();
+    }
+
+    format!(
+        "{}{} ({} {})",
+        port.rate,
+        if degraded { "*" } else { "" },
+        port.width,
+        port.speed
+    )
+}
+
+/// Format the network interfaces of a port with their IPv4 addresses, e.g. `ens1f0 10.0.0.5`.
+fn format_netdevs(port: &IbPort) -> String {
+    if port.netdevs.is_empty() {
+        return "-".to_string();
+    }
+
+    port.netdevs
+        .iter()
+        .map(|netdev| {
+            let mut s = netdev.name.clone();
+            for addr in netdev.ipv4_addresses() {
+                s.push(' ');
+                s.push_str(&addr.addr.to_string());
+            }
+            s
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Report the devices and ports which could not be read on stderr, so they do not mix with the
+/// output of the good ones.
+fn print_warnings(warnings: &[DiscoveryWarning]) {
+    if warnings.is_empty() {
+        return;
+    }
+
+    eprintln!("Skipped devices and ports:");
+    for warning in warnings {
+        eprintln!("    {}", warning);
+    }
+}
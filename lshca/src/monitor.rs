@@ -0,0 +1,99 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use std::io::{self, Write};
+
+use clap::Args;
+use color_eyre::eyre::eyre;
+use serde::Serialize;
+use tokio::io::unix::AsyncFd;
+
+use libhca::hotplug::{HcaEvent, HcaMonitor};
+
+use crate::output::{OutputFormat, SCHEMA_VERSION};
+
+#[derive(Args)]
+pub struct MonitorArgs {
+    /// Stop after the given number of events; forever by default.
+    #[arg(short = 'n', long)]
+    count: Option<u32>,
+}
+
+/// One event of `lshca monitor --output json`.
+#[derive(Serialize)]
+struct EventRecord<'a> {
+    schema_version: u32,
+    #[serde(flatten)]
+    event: &'a HcaEvent,
+}
+
+/// Print the hotplug events of the HCAs as they happen, e.g. a VF created or an RDMA device
+/// removed and added again by a firmware reset.
+pub async fn monitor(args: &MonitorArgs, format: OutputFormat) -> Result<(), color_eyre::Report> {
+    if !matches!(format, OutputFormat::Table | OutputFormat::Json) {
+        return Err(eyre!("only table and json output are supported by monitor"));
+    }
+
+    let mut monitor = AsyncFd::new(HcaMonitor::new()?)?;
+    if format == OutputFormat::Table {
+        println!(
+            "{:<10}{:<10}{:<15}{:<15}{:<15}{:<15}Driver",
+            "Seq", "Action", "Kind", "Name", "Slot", "PF"
+        );
+    }
+
+    let mut printed = 0;
+    while args.count.is_none_or(|count| printed < count) {
+        let mut guard = monitor.readable_mut().await?;
+        // Drain the socket before waiting again, as the readiness is edge-triggered.
+        while let Some(event) = guard.get_inner_mut().try_next() {
+            match format {
+                OutputFormat::Json => {
+                    let record = EventRecord {
+                        schema_version: SCHEMA_VERSION,
+                        event: &event,
+                    };
+                    serde_json::to_writer(io::stdout(), &record)?;
+                    println!();
+                }
+                _ => print_event(&event),
+            }
+            // The events are read by other tools through a pipe, so do not keep them buffered.
+            io::stdout().flush()?;
+
+            printed += 1;
+            if args.count.is_some_and(|count| printed >= count) {
+                return Ok(());
+            }
+        }
+        guard.clear_ready();
+    }
+
+    Ok(())
+}
+
+fn print_event(event: &HcaEvent) {
+    println!(
+        "{:<10}{:<10}{:<15}{:<15}{:<15}{:<15}{}",
+        event.seqnum,
+        event.action,
+        event.kind,
+        event.name,
+        event.slot_name.as_deref().unwrap_or("-"),
+        event.physfn.as_deref().unwrap_or("-"),
+        event.driver.as_deref().unwrap_or("-")
+    );
+}
@@ -0,0 +1,175 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use std::io;
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use libhca::types::{Discovery, DiscoveryWarning, PciDevice};
+
+/// The version of the machine-readable output schema; bump it on any
+/// incompatible change of the JSON/YAML documents or the CSV columns.
+///
+/// Version 2: `fw_ver`, `board_id`, `node_guid`, `node_desc` and `sys_image_guid` may be `null`,
+/// as may the new port fields `subnet_timeout` and `max_vl_num`; fields were added to the
+/// adapters, devices and ports, e.g. `adapter_id`, `driver`, `sriov`, `gids` and `pkeys`, and
+/// the document has `warnings`.
+pub const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Yaml,
+    Csv,
+}
+
+/// The document written by `--output json|yaml`.
+#[derive(Serialize)]
+pub struct Inventory<'a> {
+    pub schema_version: u32,
+    pub hostname: String,
+    pub hcas: &'a [PciDevice],
+    pub warnings: &'a [DiscoveryWarning],
+}
+
+impl<'a> Inventory<'a> {
+    pub fn new(discovery: &'a Discovery) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            hostname: uname::uname().map(|u| u.nodename).unwrap_or_default(),
+            hcas: &discovery.pci_devices,
+            warnings: &discovery.warnings,
+        }
+    }
+}
+
+/// One row of `--output csv`, i.e. one port of an HCA.
+#[derive(Serialize)]
+struct PortRecord<'a> {
+    schema_version: u32,
+    hca_id: &'a str,
+    model: &'a str,
+    vendor: &'a str,
+    fw_ver: &'a str,
+    board_id: &'a str,
+    device: &'a str,
+    slot: &'a str,
+    node_guid: &'a str,
+    port: u8,
+    port_guid: &'a str,
+    lid: u16,
+    link_type: String,
+    state: String,
+    phys_state: String,
+    width: String,
+    speed: String,
+    rate: f64,
+    degraded: bool,
+    adapter_id: &'a str,
+    part_number: &'a str,
+    serial_number: &'a str,
+    pcie_link: String,
+    pcie_max_link: String,
+    pcie_degraded: bool,
+    numa_node: Option<u32>,
+    local_cpulist: &'a str,
+    driver: &'a str,
+    node_type: String,
+    transport: String,
+    netdevs: String,
+    ip_addresses: String,
+}
+
+pub fn write_json(discovery: &Discovery) -> Result<(), color_eyre::Report> {
+    serde_json::to_writer_pretty(io::stdout(), &Inventory::new(discovery))?;
+    println!();
+
+    Ok(())
+}
+
+pub fn write_yaml(discovery: &Discovery) -> Result<(), color_eyre::Report> {
+    serde_yaml::to_writer(io::stdout(), &Inventory::new(discovery))?;
+
+    Ok(())
+}
+
+pub fn write_csv(hcas: &[PciDevice]) -> Result<(), color_eyre::Report> {
+    let mut writer = csv::Writer::from_writer(io::stdout());
+
+    for hca in hcas {
+        let vpd = hca.vpd.as_ref();
+        let pcie_link = hca.pcie_link.as_ref();
+        for dev in &hca.ib_devices {
+            for port in &dev.ib_ports {
+                writer.serialize(PortRecord {
+                    schema_version: SCHEMA_VERSION,
+                    hca_id: &hca.subsys_id,
+                    model: &hca.model_name,
+                    vendor: &hca.vendor_name,
+                    fw_ver: hca.fw_ver.as_deref().unwrap_or_default(),
+                    board_id: hca.board_id.as_deref().unwrap_or_default(),
+                    device: &dev.name,
+                    slot: &dev.slot_name,
+                    node_guid: dev.node_guid.as_deref().unwrap_or_default(),
+                    port: port.port_num,
+                    port_guid: port.guid.as_deref().unwrap_or_default(),
+                    lid: port.lid,
+                    link_type: port.link_type.to_string(),
+                    state: port.state.to_string(),
+                    phys_state: port.phys_state.to_string(),
+                    width: port.width.to_string(),
+                    speed: port.speed.to_string(),
+                    rate: port.rate,
+                    degraded: hca.is_port_degraded(port),
+                    adapter_id: &hca.adapter_id,
+                    part_number: vpd
+                        .and_then(|v| v.part_number.as_deref())
+                        .unwrap_or_default(),
+                    pcie_link: pcie_link.map(|l| l.to_string()).unwrap_or_default(),
+                    pcie_max_link: pcie_link.map(|l| l.max_link()).unwrap_or_default(),
+                    pcie_degraded: pcie_link.is_some_and(|l| l.is_degraded()),
+                    numa_node: hca.numa_node,
+                    local_cpulist: &hca.local_cpulist,
+                    driver: dev.driver.as_deref().unwrap_or_default(),
+                    node_type: dev.node_type.to_string(),
+                    transport: dev.transport.to_string(),
+                    netdevs: port
+                        .netdevs
+                        .iter()
+                        .map(|n| n.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                    ip_addresses: port
+                        .netdevs
+                        .iter()
+                        .flat_map(|n| &n.addresses)
+                        .map(|a| a.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                    serial_number: vpd
+                        .and_then(|v| v.serial_number.as_deref())
+                        .unwrap_or_default(),
+                })?;
+            }
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
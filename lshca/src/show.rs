@@ -0,0 +1,245 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use std::io;
+
+use color_eyre::eyre::eyre;
+use serde::Serialize;
+
+use libhca::caps::flag_names;
+use libhca::types::{Discovery, IbDevice, IbMtu, IbPort, IbPortLinkType, PciDevice};
+
+use crate::output::{OutputFormat, SCHEMA_VERSION};
+
+/// The document of `lshca show --output json|yaml`: the device with the schema version.
+#[derive(Serialize)]
+struct DeviceRecord<'a> {
+    schema_version: u32,
+    #[serde(flatten)]
+    device: &'a IbDevice,
+}
+
+/// Find an RDMA device by name, e.g. `mlx5_0`, together with the HCA it belongs to.
+pub fn find_device<'a>(
+    discovery: &'a Discovery,
+    name: &str,
+) -> Result<(&'a PciDevice, &'a IbDevice), color_eyre::Report> {
+    discovery
+        .pci_devices
+        .iter()
+        .flat_map(|hca| hca.ib_devices.iter().map(move |dev| (hca, dev)))
+        .find(|(_, dev)| dev.name == name)
+        .ok_or_else(|| eyre!("no such device <{}>", name))
+}
+
+pub fn show_device(
+    discovery: &Discovery,
+    name: &str,
+    format: OutputFormat,
+) -> Result<(), color_eyre::Report> {
+    let (hca, dev) = find_device(discovery, name)?;
+    let record = DeviceRecord {
+        schema_version: SCHEMA_VERSION,
+        device: dev,
+    };
+
+    match format {
+        OutputFormat::Table => print_device(hca, dev),
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(io::stdout(), &record)?;
+            println!();
+        }
+        OutputFormat::Yaml => serde_yaml::to_writer(io::stdout(), &record)?,
+        OutputFormat::Csv => return Err(eyre!("csv output is not supported by show")),
+    }
+
+    Ok(())
+}
+
+fn print_device(hca: &PciDevice, dev: &IbDevice) {
+    println!("{:<15}: {}", "Name", dev.name);
+    println!(
+        "{:<15}: {}",
+        "Slot",
+        if dev.slot_name.is_empty() {
+            "-"
+        } else {
+            &dev.slot_name
+        }
+    );
+    println!("{:<15}: {}", "Driver", dev.driver.as_deref().unwrap_or("-"));
+    println!("{:<15}: {}", "Node Type", dev.node_type);
+    println!("{:<15}: {}", "Transport", dev.transport);
+    println!("{:<15}: {}", "Model", hca.model_name);
+    println!("{:<15}: {}", "Vendor", hca.vendor_name);
+    println!("{:<15}: {}", "FW", dev.fw_ver.as_deref().unwrap_or("-"));
+    println!(
+        "{:<15}: {}",
+        "Board",
+        dev.board_id.as_deref().unwrap_or("-")
+    );
+    println!(
+        "{:<15}: {}",
+        "Node GUID",
+        dev.node_guid.as_deref().unwrap_or("-")
+    );
+    println!(
+        "{:<15}: {}",
+        "Sys Image GUID",
+        dev.sys_image_guid.as_deref().unwrap_or("-")
+    );
+    println!(
+        "{:<15}: {}",
+        "Node Desc",
+        dev.node_desc.as_deref().unwrap_or("-")
+    );
+    if let Some(sriov) = &dev.sriov {
+        println!(
+            "{:<15}: {} of {} VFs enabled",
+            "SR-IOV", sriov.num_vfs, sriov.total_vfs
+        );
+    }
+    if let Some(physfn) = &dev.physfn {
+        println!(
+            "{:<15}: VF {} of {}",
+            "SR-IOV",
+            dev.vf_index
+                .map(|i| i.to_string())
+                .unwrap_or("-".to_string()),
+            physfn
+        );
+    }
+
+    for port in &dev.ib_ports {
+        println!();
+        print_port(port);
+    }
+}
+
+fn print_port(port: &IbPort) {
+    println!("Port {}", port.port_num);
+
+    println!("    {:<15}: {}", "Link Type", port.link_type);
+    println!(
+        "    {:<15}: {} ({})",
+        "State",
+        port.state,
+        port.state.description()
+    );
+    println!(
+        "    {:<15}: {} ({})",
+        "Phys State",
+        port.phys_state,
+        port.phys_state.description()
+    );
+    println!(
+        "    {:<15}: {} Gb/s ({} {})",
+        "Rate", port.rate, port.width, port.speed
+    );
+    if !port.supported_speeds.is_empty() {
+        let speeds: Vec<String> = port
+            .supported_speeds
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        println!("    {:<15}: {}", "Speeds", speeds.join(", "));
+    }
+    println!(
+        "    {:<15}: {} (max {})",
+        "MTU",
+        format_mtu(port.active_mtu),
+        format_mtu(port.max_mtu)
+    );
+
+    if port.link_type == IbPortLinkType::Infiniband {
+        println!(
+            "    {:<15}: {}",
+            "Port GUID",
+            port.guid.as_deref().unwrap_or("-")
+        );
+        println!("    {:<15}: {}", "LID", port.lid);
+        println!("    {:<15}: {}", "LMC", port.lmc);
+        println!("    {:<15}: {}", "SM LID", port.sm_lid);
+        println!("    {:<15}: {}", "SM SL", port.sm_sl);
+        match (port.subnet_timeout, port.subnet_timeout_us()) {
+            (Some(timeout), Some(us)) => {
+                println!("    {:<15}: {} ({} us)", "Subnet Timeout", timeout, us)
+            }
+            _ => println!("    {:<15}: -", "Subnet Timeout"),
+        }
+        println!(
+            "    {:<15}: {}",
+            "Max VLs",
+            port.max_vl_num
+                .map(|n| n.to_string())
+                .unwrap_or("-".to_string())
+        );
+    }
+
+    println!("    {:<15}: {}", "GID Table Len", port.gid_tbl_len);
+    println!("    {:<15}: {}", "P_Key Table Len", port.pkey_tbl_len);
+    for (i, entry) in port.pkeys.iter().enumerate() {
+        println!(
+            "    {:<15}{} {} ({}{})",
+            if i == 0 { "P_Keys" } else { "" },
+            if i == 0 { ":" } else { " " },
+            entry.pkey,
+            entry.membership,
+            if entry.is_default { ", default" } else { "" }
+        );
+    }
+    for netdev in &port.netdevs {
+        println!(
+            "    {:<15}: {} ({}, MTU {}, {})",
+            "Netdev",
+            netdev.name,
+            netdev.operstate,
+            netdev
+                .mtu
+                .map(|mtu| mtu.to_string())
+                .unwrap_or("-".to_string()),
+            if netdev.mac.is_empty() {
+                "-"
+            } else {
+                &netdev.mac
+            }
+        );
+        for addr in &netdev.addresses {
+            println!("    {:<15}  {}", "", addr);
+        }
+    }
+    println!(
+        "    {:<15}: {:#x}",
+        "Capabilities",
+        port.capabilities.bits()
+    );
+    for name in flag_names(&port.capabilities) {
+        println!("    {:<15}  {}", "", name);
+    }
+
+    if !port.is_sm_reachable() {
+        println!();
+        println!("    No subnet manager reachable: the port has no SM LID, so it can not become");
+        println!("    active; check that a subnet manager, e.g. opensm, runs on the fabric.");
+    }
+}
+
+fn format_mtu(mtu: IbMtu) -> String {
+    match mtu {
+        IbMtu::Unknown(_) => "-".to_string(),
+        mtu => mtu.to_string(),
+    }
+}
@@ -0,0 +1,109 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use clap::{Args, Subcommand, ValueEnum};
+use color_eyre::eyre::eyre;
+
+use libhca::sriov::{SriovConfig, VfConfig, VfLinkPolicy};
+use libhca::sysfs::Sysfs;
+
+#[derive(Subcommand)]
+pub enum SriovCommand {
+    /// Configure the SR-IOV of a PF, e.g. `lshca sriov set mlx5_0 --num-vfs 4`.
+    Set(SetArgs),
+}
+
+#[derive(Args)]
+pub struct SetArgs {
+    /// The RDMA device of the PF, e.g. `mlx5_0`.
+    device: String,
+
+    /// The number of VFs to enable; 0 disables SR-IOV.
+    #[arg(long)]
+    num_vfs: Option<u32>,
+
+    /// The index of the VF to configure with `--node-guid`, `--port-guid` and `--policy`.
+    #[arg(long)]
+    vf: Option<u32>,
+
+    /// The node GUID of the VF, e.g. `1122:3344:5566:7788`.
+    #[arg(long, requires = "vf")]
+    node_guid: Option<String>,
+
+    /// The port GUID of the VF.
+    #[arg(long, requires = "vf")]
+    port_guid: Option<String>,
+
+    /// The link state policy of the VF.
+    #[arg(long, value_enum, requires = "vf")]
+    policy: Option<Policy>,
+
+    /// Print the sysfs writes instead of performing them.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Policy {
+    Follow,
+    Up,
+    Down,
+}
+
+impl From<Policy> for VfLinkPolicy {
+    fn from(policy: Policy) -> Self {
+        match policy {
+            Policy::Follow => Self::Follow,
+            Policy::Up => Self::Up,
+            Policy::Down => Self::Down,
+        }
+    }
+}
+
+pub fn run(command: &SriovCommand, sysfs: &Sysfs) -> Result<(), color_eyre::Report> {
+    match command {
+        SriovCommand::Set(args) => set(args, sysfs),
+    }
+}
+
+fn set(args: &SetArgs, sysfs: &Sysfs) -> Result<(), color_eyre::Report> {
+    let mut config = SriovConfig {
+        num_vfs: args.num_vfs,
+        vfs: vec![],
+    };
+    if let Some(index) = args.vf {
+        config.vfs.push(VfConfig {
+            index,
+            node_guid: args.node_guid.clone(),
+            port_guid: args.port_guid.clone(),
+            policy: args.policy.map(VfLinkPolicy::from),
+        });
+    }
+
+    let writes = match args.dry_run {
+        true => config.plan(sysfs, &args.device)?,
+        false => config.apply(sysfs, &args.device)?,
+    };
+
+    if writes.is_empty() {
+        return Err(eyre!("nothing to configure for <{}>", args.device));
+    }
+    for write in writes {
+        println!("{}", write);
+    }
+
+    Ok(())
+}
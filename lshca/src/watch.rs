@@ -0,0 +1,208 @@
+/*
+Copyright 2023 The xflops Authors.
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use std::io;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use clap::Args;
+use color_eyre::eyre::eyre;
+use serde::Serialize;
+
+use libhca::counters::{CounterDelta, CounterSampler};
+use libhca::sysfs::Sysfs;
+use libhca::types::Discovery;
+
+use crate::counters::select_ports;
+use crate::output::{OutputFormat, SCHEMA_VERSION};
+
+/// The range of `--interval` in seconds.
+const INTERVAL_RANGE: RangeInclusive<f64> = 0.1..=3600.0;
+
+#[derive(Args)]
+pub struct WatchArgs {
+    /// All ports, or a device or port, e.g. `mlx5_0` or `mlx5_0/1`.
+    target: Option<String>,
+
+    /// The sampling interval in seconds, from 0.1 to 3600.
+    #[arg(short, long, default_value = "1", value_parser = parse_interval)]
+    interval: Duration,
+
+    /// Stop after the given number of samples; forever by default.
+    #[arg(short = 'n', long)]
+    count: Option<u32>,
+}
+
+/// One port of one sample of `lshca watch --output json|csv`.
+#[derive(Serialize)]
+struct RateRecord<'a> {
+    schema_version: u32,
+    device: &'a str,
+    port: u8,
+    interval: f64,
+    xmit_gbps: Option<f64>,
+    rcv_gbps: Option<f64>,
+    xmit_pps: Option<f64>,
+    rcv_pps: Option<f64>,
+    /// The changed error counters, e.g. `symbol_errors=3`, separated by spaces.
+    errors: String,
+    reset: bool,
+    /// The counters at their max, whose deltas are a lower bound, separated by spaces.
+    saturated: String,
+}
+
+impl<'a> RateRecord<'a> {
+    fn new(delta: &'a CounterDelta) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            device: &delta.device,
+            port: delta.port_num,
+            interval: delta.interval.as_secs_f64(),
+            xmit_gbps: delta.xmit_gbps(),
+            rcv_gbps: delta.rcv_gbps(),
+            xmit_pps: delta.rate("xmit_packets"),
+            rcv_pps: delta.rate("rcv_packets"),
+            errors: delta
+                .errors()
+                .iter()
+                .map(|(name, v)| format!("{}={}", name, v))
+                .collect::<Vec<_>>()
+                .join(" "),
+            reset: delta.reset,
+            saturated: delta.saturated.join(" "),
+        }
+    }
+}
+
+/// Sample the counters of the ports at an interval, and print their throughput and the
+/// changed error counters of every interval.
+pub async fn watch(
+    discovery: &Discovery,
+    sysfs: &Sysfs,
+    args: &WatchArgs,
+    format: OutputFormat,
+) -> Result<(), color_eyre::Report> {
+    if format == OutputFormat::Yaml {
+        return Err(eyre!("yaml output is not supported by watch"));
+    }
+    let ports = select_ports(discovery, args.target.as_deref())?;
+    let mut sampler = CounterSampler::new(sysfs.clone());
+    let mut csv_writer = csv::Writer::from_writer(io::stdout());
+
+    let mut interval = tokio::time::interval(args.interval);
+    // The first sample is the baseline of the deltas, so it is not counted.
+    for n in 0..=args.count.unwrap_or(u32::MAX) {
+        interval.tick().await;
+
+        let mut deltas = vec![];
+        for (dev, port_num) in &ports {
+            match sampler.sample(&dev.name, *port_num) {
+                Ok(Some(delta)) => deltas.push(delta),
+                Ok(None) => {}
+                Err(e) => eprintln!("{} port {}: {}", dev.name, port_num, e),
+            }
+        }
+        if n == 0 {
+            continue;
+        }
+
+        let records: Vec<RateRecord> = deltas.iter().map(RateRecord::new).collect();
+        match format {
+            OutputFormat::Table => print_rates(&records, n == 1),
+            OutputFormat::Json => {
+                for record in &records {
+                    serde_json::to_writer(io::stdout(), record)?;
+                    println!();
+                }
+            }
+            OutputFormat::Csv => {
+                for record in &records {
+                    csv_writer.serialize(record)?;
+                }
+                csv_writer.flush()?;
+            }
+            OutputFormat::Yaml => unreachable!(),
+        }
+    }
+
+    Ok(())
+}
+
+fn print_rates(records: &[RateRecord], header: bool) {
+    if header {
+        println!(
+            "{:<15}{:<6}{:<12}{:<12}{:<14}{:<14}Errors",
+            "Device", "Port", "Xmit Gb/s", "Rcv Gb/s", "Xmit pkt/s", "Rcv pkt/s"
+        );
+    }
+
+    let format_rate = |v: Option<f64>, precision: usize| {
+        v.map(|v| format!("{:.*}", precision, v))
+            .unwrap_or("-".to_string())
+    };
+
+    for record in records {
+        println!(
+            "{:<15}{:<6}{:<12}{:<12}{:<14}{:<14}{}{}{}",
+            record.device,
+            record.port,
+            format_rate(record.xmit_gbps, 2),
+            format_rate(record.rcv_gbps, 2),
+            format_rate(record.xmit_pps, 0),
+            format_rate(record.rcv_pps, 0),
+            record.errors,
+            if record.reset {
+                " (counters reset)"
+            } else {
+                ""
+            },
+            if record.saturated.is_empty() {
+                String::new()
+            } else {
+                format!(" (at max: {})", record.saturated)
+            }
+        );
+    }
+}
+
+/// Parse `--interval`, in seconds within [`INTERVAL_RANGE`].
+fn parse_interval(v: &str) -> Result<Duration, String> {
+    match v.parse::<f64>() {
+        Ok(secs) if INTERVAL_RANGE.contains(&secs) => Ok(Duration::from_secs_f64(secs)),
+        _ => Err(format!(
+            "invalid interval <{}>, expected {} to {} seconds",
+            v,
+            INTERVAL_RANGE.start(),
+            INTERVAL_RANGE.end()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_intervals() {
+        assert_eq!(parse_interval("1"), Ok(Duration::from_secs(1)));
+        assert_eq!(parse_interval("0.5"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_interval("3600"), Ok(Duration::from_secs(3600)));
+
+        for v in ["0", "-1", "0.01", "3601", "1e30", "NaN", "inf", "", "1s"] {
+            assert!(parse_interval(v).is_err(), "{}", v);
+        }
+    }
+}